@@ -0,0 +1,414 @@
+//! Persistent cache of time slices, for re-use by later runs with identical inputs.
+use crate::shake::Crop;
+use crate::slicer::SliceLength;
+use crate::streams::{Compression, DeltaEncoding};
+use crate::Result;
+use image::flat::SampleLayout;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the cache's sub-directory in the temp directory.
+const CACHE_DIR: &str = "slice-cache";
+/// Name of the manifest file of a cached slice set.
+const MANIFEST: &str = "manifest.txt";
+/// Separator between manifest entries and key description.
+const SEPARATOR: &str = "---";
+/// Version of the cache format. Part of the key.
+const CACHE_VERSION: u32 = 2;
+
+/// A cached set of time slices.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    key: String,
+    dir: PathBuf,
+    description: String,
+    layout: SampleLayout,
+    image_count: usize,
+    files: Vec<PathBuf>,
+    created: u64,
+    used: u64,
+}
+
+impl CacheEntry {
+    /// The cache key (hex hash of the key description).
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    /// The directory of the slice set.
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+    /// The layout of the images.
+    pub fn layout(&self) -> &SampleLayout {
+        &self.layout
+    }
+    /// The number of images.
+    pub fn image_count(&self) -> usize {
+        self.image_count
+    }
+    /// The time slice files.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+    /// Seconds since the last use of the slice set.
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.used)
+    }
+    /// The first input image of the slice set, if any.
+    pub fn first_input(&self) -> Option<&str> {
+        self.description
+            .lines()
+            .find(|line| line.starts_with("input="))
+            .and_then(|line| line[6..].split('|').next())
+    }
+    /// Total size of the time slice files on disk, in bytes.
+    pub fn size(&self) -> u64 {
+        self.files
+            .iter()
+            .filter_map(|file| fs::metadata(file).ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+
+    /// Writes the manifest of this entry.
+    fn write(&self) -> io::Result<()> {
+        let mut content = format!(
+            "key={}\nwidth={}\nheight={}\nchannels={}\nchannel_stride={}\nwidth_stride={}\nheight_stride={}\nimages={}\ncreated={}\nused={}\n",
+            self.key,
+            self.layout.width,
+            self.layout.height,
+            self.layout.channels,
+            self.layout.channel_stride,
+            self.layout.width_stride,
+            self.layout.height_stride,
+            self.image_count,
+            self.created,
+            self.used,
+        );
+        for file in &self.files {
+            if let Some(name) = file.file_name().and_then(|n| n.to_str()) {
+                content.push_str(&format!("slice={}\n", name));
+            }
+        }
+        content.push_str(SEPARATOR);
+        content.push('\n');
+        content.push_str(&self.description);
+        fs::write(self.dir.join(MANIFEST), content)
+    }
+
+    /// Reads the manifest of the slice set in the given directory.
+    fn read(dir: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(dir.join(MANIFEST))?;
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid cache manifest in {:?}: {}", dir, msg),
+            )
+        };
+        let (entries, description) = match content.find(&format!("\n{}\n", SEPARATOR)) {
+            Some(idx) => (&content[..idx], &content[(idx + SEPARATOR.len() + 2)..]),
+            None => return Err(invalid("missing key description")),
+        };
+
+        let mut values = std::collections::HashMap::new();
+        let mut files = vec![];
+        for line in entries.lines() {
+            let (name, value) = match line.find('=') {
+                Some(idx) => (&line[..idx], &line[(idx + 1)..]),
+                None => return Err(invalid(line)),
+            };
+            if name == "slice" {
+                files.push(dir.join(value));
+            } else {
+                values.insert(name, value);
+            }
+        }
+        let number = |name: &str| -> io::Result<u64> {
+            values
+                .get(name)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| invalid(name))
+        };
+        Ok(CacheEntry {
+            key: values.get("key").ok_or_else(|| invalid("key"))?.to_string(),
+            dir: dir.to_path_buf(),
+            description: description.to_string(),
+            layout: SampleLayout {
+                channels: number("channels")? as u8,
+                channel_stride: number("channel_stride")? as usize,
+                width: number("width")? as u32,
+                width_stride: number("width_stride")? as usize,
+                height: number("height")? as u32,
+                height_stride: number("height_stride")? as usize,
+            },
+            image_count: number("images")? as usize,
+            files,
+            created: number("created")?,
+            used: number("used")?,
+        })
+    }
+}
+
+/// Persistent cache of time slices, in a sub-directory of the temp directory.
+///
+/// Slice sets are keyed by the input files (path, size and modification time),
+/// crop (camera shake reduction), bit depth, compression, delta encoding and slice settings.
+pub struct SliceCache {
+    dir: PathBuf,
+}
+
+impl SliceCache {
+    /// Creates a cache in the given temp directory.
+    pub fn new(temp_dir: &Path) -> Self {
+        SliceCache {
+            dir: temp_dir.join(CACHE_DIR),
+        }
+    }
+
+    /// Creates the key description for a slice set. Fails if input files can't be accessed.
+    pub fn describe(
+        files: &[PathBuf],
+        crop: &Option<Vec<Crop>>,
+        is_16bit: bool,
+        compression: &Compression,
+        delta: &DeltaEncoding,
+        slices: &SliceLength,
+    ) -> Result<String> {
+        let mut description = format!(
+            "version={}\nbits={}\ncompression={:?}\ndelta={:?}\nslices={:?}\ncrop={:016x}\n",
+            CACHE_VERSION,
+            if is_16bit { 16 } else { 8 },
+            compression,
+            delta,
+            slices,
+            fnv_hash(format!("{:?}", crop).as_bytes()),
+        );
+        for file in files {
+            let meta = fs::metadata(file)?;
+            let modified = meta
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            description.push_str(&format!(
+                "input={}|{}|{}.{:09}\n",
+                fs::canonicalize(file)?.to_string_lossy(),
+                meta.len(),
+                modified.as_secs(),
+                modified.subsec_nanos()
+            ));
+        }
+        Ok(description)
+    }
+
+    /// The directory for the slice set with the given key description.
+    pub fn entry_dir(&self, description: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}", fnv_hash(description.as_bytes())))
+    }
+
+    /// Returns the cached slice set for the key description, if it exists and is complete.
+    /// Updates the entry's time of last use.
+    pub fn load(&self, description: &str) -> Option<CacheEntry> {
+        let mut entry = CacheEntry::read(&self.entry_dir(description)).ok()?;
+        if entry.description != description || !entry.files.iter().all(|f| f.is_file()) {
+            return None;
+        }
+        entry.used = now();
+        entry.write().ok()?;
+        Some(entry)
+    }
+
+    /// Prepares an empty directory for a new slice set with the given key description.
+    pub fn prepare(&self, description: &str) -> Result<PathBuf> {
+        let dir = self.entry_dir(description);
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Stores the manifest for a slice set, written to the directory created by [`prepare`](#method.prepare).
+    pub fn store(
+        &self,
+        description: &str,
+        files: &[PathBuf],
+        layout: &SampleLayout,
+        image_count: usize,
+    ) -> Result<CacheEntry> {
+        let dir = self.entry_dir(description);
+        let entry = CacheEntry {
+            key: dir
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            dir,
+            description: description.to_string(),
+            layout: *layout,
+            image_count,
+            files: files.to_vec(),
+            created: now(),
+            used: now(),
+        };
+        entry.write()?;
+        Ok(entry)
+    }
+
+    /// Lists all complete slice sets, and the directories of incomplete slice sets.
+    pub fn list(&self) -> Result<(Vec<CacheEntry>, Vec<PathBuf>)> {
+        let mut entries = vec![];
+        let mut incomplete = vec![];
+        if !self.dir.is_dir() {
+            return Ok((entries, incomplete));
+        }
+        for dir in fs::read_dir(&self.dir)? {
+            let dir = dir?.path();
+            if dir.is_dir() {
+                match CacheEntry::read(&dir) {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => incomplete.push(dir),
+                }
+            }
+        }
+        entries.sort_by_key(|e| e.used);
+        Ok((entries, incomplete))
+    }
+
+    /// Deletes all slice sets not used for at least `max_age`, as well as incomplete slice sets
+    /// of which no file was modified for at least `max_age`. Incomplete slice sets may still be written by a concurrent run.
+    /// Returns the number of deleted slice sets.
+    pub fn prune(&self, max_age: Duration) -> Result<usize> {
+        let (entries, incomplete) = self.list()?;
+        let mut count = 0;
+        for dir in incomplete {
+            if modified_age(&dir)? >= max_age.as_secs() {
+                fs::remove_dir_all(dir)?;
+                count += 1;
+            }
+        }
+        for entry in entries {
+            if entry.age() >= max_age.as_secs() {
+                fs::remove_dir_all(&entry.dir)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Seconds since the last modification of a directory or any file in it.
+/// Time slice files are appended while they are written, which does not change the modification time of the directory.
+fn modified_age(dir: &Path) -> Result<u64> {
+    let modified = |meta: fs::Metadata| -> Result<u64> {
+        Ok(meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0))
+    };
+    let mut latest = modified(fs::metadata(dir)?)?;
+    for file in fs::read_dir(dir)? {
+        // Files may be deleted by a concurrent run in the meantime
+        if let Ok(meta) = file?.metadata() {
+            latest = std::cmp::max(latest, modified(meta)?);
+        }
+    }
+    Ok(now().saturating_sub(latest))
+}
+
+/// FNV-1a hash (64 bit). Used instead of `DefaultHasher`, as it is stable across Rust versions.
+fn fnv_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cache::SliceCache;
+    use crate::slicer::SliceLength;
+    use crate::streams::{Compression, DeltaEncoding};
+    use crate::test_util::TempDir;
+    use image::flat::SampleLayout;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn cache_test() {
+        let temp_dir = TempDir::new("cache");
+        let cache = SliceCache::new(temp_dir.path());
+        let input = temp_dir.join("input.txt");
+        std::fs::write(&input, "image").unwrap();
+
+        let description = SliceCache::describe(
+            std::slice::from_ref(&input),
+            &None,
+            false,
+            &Compression::GZip(6),
+            &DeltaEncoding::None,
+            &SliceLength::Rows(4),
+        )
+        .unwrap();
+        assert!(cache.load(&description).is_none());
+
+        let dir = cache.prepare(&description).unwrap();
+        let slice = dir.join("temp-00000.bin");
+        std::fs::write(&slice, "slice").unwrap();
+        let layout = SampleLayout::row_major_packed(3, 10, 5);
+        cache.store(&description, &[slice], &layout, 7).unwrap();
+
+        let entry = cache.load(&description).unwrap();
+        assert_eq!(entry.image_count(), 7);
+        assert_eq!(entry.layout(), &layout);
+        assert_eq!(entry.files().len(), 1);
+        assert_eq!(entry.first_input(), input.canonicalize().unwrap().to_str());
+
+        // Changed settings result in a different key
+        let other = SliceCache::describe(
+            &[input],
+            &None,
+            false,
+            &Compression::GZip(6),
+            &DeltaEncoding::None,
+            &SliceLength::Rows(8),
+        )
+        .unwrap();
+        assert!(cache.load(&other).is_none());
+
+        // Incomplete slice sets are only deleted when no file was modified recently, as they may still be written.
+        // Appending to slice files does not change the directory's modification time.
+        let other_dir = cache.prepare(&other).unwrap();
+        let other_slice = other_dir.join("temp-00000.bin");
+        std::fs::write(&other_slice, "slice").unwrap();
+        let past = SystemTime::now() - Duration::from_secs(2000);
+        File::open(&other_dir).unwrap().set_modified(past).unwrap();
+        assert_eq!(cache.list().unwrap().1.len(), 1);
+
+        assert_eq!(cache.prune(Duration::from_secs(1000)).unwrap(), 0);
+        assert_eq!(cache.list().unwrap().1.len(), 1);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&other_slice)
+            .unwrap();
+        file.set_modified(past).unwrap();
+        assert_eq!(cache.prune(Duration::from_secs(1000)).unwrap(), 1);
+
+        cache.prepare(&other).unwrap();
+        assert_eq!(cache.prune(Duration::from_secs(0)).unwrap(), 2);
+        assert!(cache.load(&description).is_none());
+    }
+}
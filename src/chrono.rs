@@ -1,13 +1,44 @@
 //! Outlier chrono-photo algorithm for processing time-sliced data produced by [`TimeSlicer`](./time_slice/struct.TimeSlicer.html).
 use crate::color;
-use crate::options::{BackgroundMode, Fade, OutlierSelectionMode, Threshold};
+use crate::color::Sample;
+use crate::histogram;
+use crate::histogram::Histogram;
+use crate::mask::{Feather, MaskFilter, OutlierMask};
+use crate::options::{
+    BackgroundMode, CleanPlate, ColorSpace, Fade, OutlierSelectionMode, ScaleEstimator, Threshold,
+};
 use crate::slicer::SliceLength;
-use crate::streams::{Compression, PixelInputStream};
+use crate::streams::{open_image, TimeSlices};
+use crate::{Error, Result};
 use image::flat::SampleLayout;
 use indicatif::ProgressBar;
-use rand::{Rng, ThreadRng};
+use rand::rngs::SmallRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+use rayon::prelude::*;
+use std::borrow::Cow;
 use std::fmt;
-use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Period of the hue channel in HSV color space, see [`rgb_to_hsv`](../color/fn.rgb_to_hsv.html).
+const HUE_PERIOD: f32 = 255.0;
+
+// Consistency factors: IQR of the standard normal distribution, and the
+// factors to estimate the standard deviation from MAD, Sn and Qn
+const IQR_NORMAL: f32 = 1.349;
+const MAD_FACTOR: f32 = 1.4826;
+const SN_FACTOR: f32 = 1.1926;
+const QN_FACTOR: f32 = 2.2219;
+
+/// Minimum mean number of images per frame for sliding histograms, see [`OutlierProcessor::sliding_stats`].
+/// Below, sorting is faster.
+const SLIDING_MIN_IMAGES: usize = 32;
+
+/// Minimum number of samples per pixel for calculating medians and scales from histograms instead of sorting.
+/// Below, sorting is faster.
+const HISTOGRAM_MIN_SAMPLES: usize = 20;
+
+/// Number of pixels of a time slice processed together by a thread.
+const COLUMN_CHUNK: usize = 64;
 
 /// Error type for failed selection of background pixels.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -21,10 +52,255 @@ impl fmt::Display for PixelSelectionError {
 
 /// Per-thread data structures to avoid vector allocations.
 struct ThreadData {
+    /// Indices of the images used for medians and scales, identical in all threads.
+    sample_indices: Vec<usize>,
     outlier_indices: Vec<(usize, f32)>,
     non_outlier_indices: Vec<usize>,
-    values: Vec<u8>,
-    rng: ThreadRng,
+    values: Vec<f32>,
+    converted: Vec<f32>,
+    /// Histograms per channel, for 8 bit samples.
+    histograms: Vec<Histogram>,
+    rng: SmallRng,
+}
+
+/// Output buffers of a single frame during processing.
+struct FrameBuffers<T: Sample> {
+    channels: usize,
+    buffer: Vec<T>,
+    is_outlier: Vec<T>,
+    /// Blend values, only required for feathering.
+    blend_mask: Vec<f32>,
+    /// Background, only required for feathering or background output.
+    background: Vec<T>,
+    warnings: usize,
+}
+impl<T: Sample> FrameBuffers<T> {
+    fn new(layout: &SampleLayout, with_blend: bool, with_background: bool) -> Self {
+        let channels = layout.width_stride;
+        let len = layout.height as usize * layout.height_stride;
+        FrameBuffers {
+            channels,
+            buffer: vec![T::zero(); len],
+            is_outlier: vec![T::zero(); len],
+            blend_mask: if with_blend {
+                vec![0.0; len / channels]
+            } else {
+                vec![]
+            },
+            background: if with_blend || with_background {
+                vec![T::zero(); len]
+            } else {
+                vec![]
+            },
+            warnings: 0,
+        }
+    }
+    /// Splits the buffers into disjoint views of the time slices, with `slice_samples` samples each.
+    fn slices_mut(&mut self, slice_samples: usize) -> Vec<SliceBuffers<'_, T>> {
+        SliceBuffers {
+            channels: self.channels,
+            buffer: &mut self.buffer,
+            is_outlier: &mut self.is_outlier,
+            blend_mask: &mut self.blend_mask,
+            background: &mut self.background,
+        }
+        .split(slice_samples)
+    }
+}
+
+/// Mutable view of a part of a frame's output buffers, e.g. of a time slice.
+/// Allows threads to write to disjoint parts of a frame.
+struct SliceBuffers<'a, T: Sample> {
+    channels: usize,
+    buffer: &'a mut [T],
+    is_outlier: &'a mut [T],
+    /// Blend values, empty if not required.
+    blend_mask: &'a mut [f32],
+    /// Background, empty if not required.
+    background: &'a mut [T],
+}
+impl<'a, T: Sample> SliceBuffers<'a, T> {
+    /// Splits into consecutive views of `samples` samples each. The last view may be shorter.
+    fn split(self, samples: usize) -> Vec<Self> {
+        let mut parts = vec![];
+        let mut rest = self;
+        while !rest.buffer.is_empty() {
+            let mid = std::cmp::min(samples, rest.buffer.len());
+            let (part, tail) = rest.split_at(mid);
+            parts.push(part);
+            rest = tail;
+        }
+        parts
+    }
+    /// Splits into two views at the given sample.
+    fn split_at(self, mid: usize) -> (Self, Self) {
+        let channels = self.channels;
+        let (buffer, buffer_tail) = self.buffer.split_at_mut(mid);
+        let (is_outlier, is_outlier_tail) = self.is_outlier.split_at_mut(mid);
+        let blend_mid = std::cmp::min(mid / channels, self.blend_mask.len());
+        let (blend_mask, blend_mask_tail) = self.blend_mask.split_at_mut(blend_mid);
+        let background_mid = std::cmp::min(mid, self.background.len());
+        let (background, background_tail) = self.background.split_at_mut(background_mid);
+        (
+            SliceBuffers {
+                channels,
+                buffer,
+                is_outlier,
+                blend_mask,
+                background,
+            },
+            SliceBuffers {
+                channels,
+                buffer: buffer_tail,
+                is_outlier: is_outlier_tail,
+                blend_mask: blend_mask_tail,
+                background: background_tail,
+            },
+        )
+    }
+}
+
+/// Median and robust scale of each channel of a pixel.
+#[derive(Debug, Clone, Default)]
+struct PixelStats {
+    median: [f32; 4],
+    scale: [f32; 4],
+}
+
+/// Per-pixel statistics of all frames for a time slice, see [`OutlierProcessor::sliding_stats`].
+struct SlidingStats {
+    frames: usize,
+    /// Statistics, by pixel and frame.
+    stats: Vec<PixelStats>,
+}
+impl SlidingStats {
+    /// The statistics of the given frame and pixel.
+    fn get(&self, frame: usize, pixel: usize) -> &PixelStats {
+        &self.stats[pixel * self.frames + frame]
+    }
+}
+
+/// Image indices of frames processed in a single pass over the time slices.
+struct FrameWindows {
+    /// Image indices read from each time slice (ascending), or `None` for all images.
+    union: Option<Vec<usize>>,
+    /// Positions of each frame's images in the data read from a time slice, or `None` for all images.
+    positions: Vec<Option<Vec<usize>>>,
+    /// Index of the first image of each frame.
+    offsets: Vec<usize>,
+}
+impl FrameWindows {
+    /// Creates windows for frames with the given (ascending) image indices, or `None` for all images.
+    fn new(windows: &[Option<&[usize]>]) -> Self {
+        let offsets = windows
+            .iter()
+            .map(|window| {
+                window
+                    .and_then(|indices| indices.first().cloned())
+                    .unwrap_or(0)
+            })
+            .collect();
+        if windows.iter().any(|window| window.is_none()) {
+            return FrameWindows {
+                union: None,
+                positions: windows
+                    .iter()
+                    .map(|window| window.map(<[usize]>::to_vec))
+                    .collect(),
+                offsets,
+            };
+        }
+        let mut union: Vec<usize> = windows
+            .iter()
+            .flatten()
+            .flat_map(|w| w.iter())
+            .cloned()
+            .collect();
+        union.sort_unstable();
+        union.dedup();
+        let positions = windows
+            .iter()
+            .flatten()
+            .map(|indices| {
+                Some(
+                    indices
+                        .iter()
+                        .map(|index| union.binary_search(index).unwrap())
+                        .collect(),
+                )
+            })
+            .collect();
+        FrameWindows {
+            union: Some(union),
+            positions,
+            offsets,
+        }
+    }
+    /// The number of frames.
+    fn len(&self) -> usize {
+        self.positions.len()
+    }
+    /// Image indices to read from each time slice, or `None` for all images.
+    fn union(&self) -> Option<&[usize]> {
+        self.union.as_deref()
+    }
+    /// Index of the first image of the given frame.
+    fn frame_offset(&self, frame: usize) -> usize {
+        self.offsets[frame]
+    }
+    /// The mean number of images per frame, counting frames without image indices as empty.
+    fn mean_len(&self) -> usize {
+        let total: usize = self
+            .positions
+            .iter()
+            .map(|positions| positions.as_ref().map_or(0, Vec::len))
+            .sum();
+        total / self.len()
+    }
+    /// Positions of the given frame's images in the data read from a time slice with `num_rows` images.
+    fn frame_positions(&self, frame: usize, num_rows: usize) -> Vec<usize> {
+        match &self.positions[frame] {
+            Some(positions) => positions
+                .iter()
+                .take_while(|pos| **pos < num_rows)
+                .cloned()
+                .collect(),
+            None => (0..num_rows).collect(),
+        }
+    }
+    /// Extracts the data of a frame from the data read from a time slice.
+    /// Returns the frame's data and its number of images.
+    fn frame_data<'a, T: Sample>(
+        &self,
+        frame: usize,
+        data: &'a [T],
+        num_rows: usize,
+        num_samples: usize,
+    ) -> (Cow<'a, [T]>, usize) {
+        let positions = match &self.positions[frame] {
+            Some(positions) => positions,
+            None => return (Cow::Borrowed(data), num_rows),
+        };
+        // Images beyond the end of the time slice are missing
+        let count = positions.iter().take_while(|pos| **pos < num_rows).count();
+        if count == 0 {
+            return (Cow::Borrowed(&data[..0]), 0);
+        }
+        let first = positions[0];
+        if positions[count - 1] - first + 1 == count {
+            (
+                Cow::Borrowed(&data[(first * num_samples)..((first + count) * num_samples)]),
+                count,
+            )
+        } else {
+            let frame_data = positions[..count]
+                .iter()
+                .flat_map(|pos| &data[(pos * num_samples)..((pos + 1) * num_samples)])
+                .cloned()
+                .collect();
+            (Cow::Owned(frame_data), count)
+        }
+    }
 }
 
 /// Core processor for image analysis with outlier algorithm.
@@ -34,22 +310,31 @@ pub struct OutlierProcessor {
     background: BackgroundMode,
     outlier: OutlierSelectionMode,
     weights: [f32; 4],
-    compression: Compression,
+    color_space: ColorSpace,
+    mask_filters: Vec<MaskFilter>,
+    feather: Option<Feather>,
+    clean_plate: Option<CleanPlate>,
     sample_count: Option<usize>,
-    sample_indices: Vec<usize>,
+    /// Seed for selecting the sub-sample of images, so that all threads use the same images.
+    sample_seed: u64,
+    /// Selected frame per pixel for region-based outlier selection.
+    region_frames: Vec<Option<usize>>,
     fade: Fade,
-    data: ThreadData,
 }
 
 impl OutlierProcessor {
     /// Creates a new image processor.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         threshold: Threshold,
         bg_mode: BackgroundMode,
         outlier_mode: OutlierSelectionMode,
         weights: [f32; 4],
+        color_space: ColorSpace,
+        mask_filters: Vec<MaskFilter>,
+        feather: Option<Feather>,
+        clean_plate: Option<CleanPlate>,
         fade: Fade,
-        compression: Compression,
         sample_count: Option<usize>,
     ) -> Self {
         OutlierProcessor {
@@ -57,228 +342,959 @@ impl OutlierProcessor {
             background: bg_mode,
             outlier: outlier_mode,
             weights,
+            color_space,
+            mask_filters,
+            feather,
+            clean_plate,
             fade,
-            compression,
             sample_count,
-            sample_indices: vec![],
-            data: ThreadData {
-                outlier_indices: vec![],
-                non_outlier_indices: vec![],
-                values: vec![],
-                rng: rand::thread_rng(),
-            },
+            sample_seed: SmallRng::from_entropy().gen(),
+            region_frames: vec![],
         }
     }
-    /// Processes images based on files as created by [`TimeSlicer`](./time_slice/struct.TimeSlicer.html).
-    pub fn process(
-        mut self,
+    /// Processes images based on time slices as created by [`TimeSlicer`](./time_slice/struct.TimeSlicer.html).
+    /// Returns the output image, the blend image and the background image (if `with_background` is true),
+    /// with the sample type of the time slices.
+    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+    pub fn process<T: Sample>(
+        self,
         layout: &SampleLayout,
-        files: &[PathBuf],
+        time_slices: &TimeSlices,
         slices: &SliceLength,
         size_hint: Option<usize>,
         image_indices: Option<&[usize]>,
+        with_background: bool,
         show_progress: bool,
-    ) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    ) -> Result<(Vec<T>, Vec<T>, Option<Vec<T>>)> {
+        let mut frames = self.process_windows(
+            layout,
+            time_slices,
+            slices,
+            size_hint,
+            &[image_indices],
+            with_background,
+            show_progress,
+        )?;
+        Ok(frames.remove(0))
+    }
+
+    /// Processes multiple frames (e.g. of a video) in a single pass over the time slices.
+    /// Each time slice is read once, for the images of all frames, and shared between overlapping frames.
+    /// `frames` contains the (ascending) image indices of each frame.
+    /// Returns the output image, the blend image and the background image (if `with_background` is true)
+    /// of each frame.
+    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+    pub fn process_frames<T: Sample>(
+        self,
+        layout: &SampleLayout,
+        time_slices: &TimeSlices,
+        slices: &SliceLength,
+        size_hint: Option<usize>,
+        frames: &[&[usize]],
+        with_background: bool,
+        show_progress: bool,
+    ) -> Result<Vec<(Vec<T>, Vec<T>, Option<Vec<T>>)>> {
+        let windows: Vec<_> = frames.iter().map(|indices| Some(*indices)).collect();
+        self.process_windows(
+            layout,
+            time_slices,
+            slices,
+            size_hint,
+            &windows,
+            with_background,
+            show_progress,
+        )
+    }
+
+    /// Processes frames with the given image indices (`None` for all images), see [`process_frames`](#method.process_frames).
+    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+    fn process_windows<T: Sample>(
+        self,
+        layout: &SampleLayout,
+        time_slices: &TimeSlices,
+        slices: &SliceLength,
+        size_hint: Option<usize>,
+        windows: &[Option<&[usize]>],
+        with_background: bool,
+        show_progress: bool,
+    ) -> Result<Vec<(Vec<T>, Vec<T>, Option<Vec<T>>)>> {
         let channels = layout.width_stride;
-        let mut buffer = vec![0; layout.height as usize * layout.height_stride];
-        let mut is_outlier = vec![0; layout.height as usize * layout.height_stride];
+        let slice_samples = slices.samples(layout);
+        let windows = FrameWindows::new(windows);
+        let sliding = self.use_sliding_stats::<T>(&windows, channels);
+
+        // The clean plate is shared by all frames
+        let plate = match &self.clean_plate {
+            Some(clean_plate) => Some(self.create_clean_plate::<T>(
+                clean_plate,
+                layout,
+                time_slices,
+                slice_samples,
+                show_progress,
+            )?),
+            None => None,
+        };
+        let plate = plate.as_deref();
 
-        let mut pixel = vec![0; channels];
+        let mut processors: Vec<_> = (0..windows.len()).map(|_| self.frame_processor()).collect();
 
-        let mut warnings = 0;
-        let slice_bytes = slices.bytes(&layout);
+        let masks: Vec<Option<OutlierMask>> =
+            if self.mask_filters.is_empty() && self.outlier != OutlierSelectionMode::Largest {
+                (0..windows.len()).map(|_| None).collect()
+            } else {
+                Self::create_masks::<T>(
+                    &mut processors,
+                    &windows,
+                    sliding,
+                    layout,
+                    time_slices,
+                    slice_samples,
+                    size_hint,
+                    plate,
+                    show_progress,
+                )?
+                .into_iter()
+                .map(Some)
+                .collect()
+            };
+
+        let mut outputs: Vec<_> = (0..windows.len())
+            .map(|_| FrameBuffers::new(layout, self.feather.is_some(), with_background))
+            .collect();
 
         if show_progress {
-            println!("Processing {} time slices", files.len());
+            println!("Processing {} time slices", time_slices.len());
         }
-        let bar = ProgressBar::new(files.len() as u64);
-        bar.set_draw_delta((files.len() / 200) as u64);
-        for (out_row, file) in files.iter().enumerate() {
-            if show_progress {
-                bar.inc(1);
+        // Views of the frames' output buffers, by time slice and frame
+        let mut slice_outputs: Vec<Vec<SliceBuffers<T>>> =
+            (0..time_slices.len()).map(|_| vec![]).collect();
+        for output in outputs.iter_mut() {
+            for (views, view) in slice_outputs
+                .iter_mut()
+                .zip(output.slices_mut(slice_samples))
+            {
+                views.push(view);
             }
+        }
+        let bar = ProgressBar::new(time_slices.len() as u64);
+        bar.set_draw_delta((time_slices.len() / 200) as u64);
+        let warnings = slice_outputs
+            .into_par_iter()
+            .enumerate()
+            .map(|(out_row, views)| {
+                let buff_row_start = out_row * slice_samples;
+                let (data, num_rows, num_samples) = Self::read_slice::<T>(
+                    time_slices,
+                    out_row,
+                    slice_samples,
+                    size_hint,
+                    windows.union(),
+                )?;
+                let stats = if sliding {
+                    Some(self.sliding_stats(&data, &windows, num_rows, num_samples, channels))
+                } else {
+                    None
+                };
+                let warnings: Vec<usize> = views
+                    .into_par_iter()
+                    .enumerate()
+                    .map(|(frame, view)| {
+                        let (data, num_rows) =
+                            windows.frame_data(frame, &data, num_rows, num_samples);
+                        processors[frame].process_slice(
+                            &data,
+                            num_rows,
+                            num_samples,
+                            buff_row_start,
+                            windows.frame_offset(frame),
+                            masks[frame].as_ref(),
+                            plate,
+                            stats.as_ref().map(|stats| (stats, frame)),
+                            view,
+                        )
+                    })
+                    .collect();
+                if show_progress {
+                    bar.inc(1);
+                }
+                Ok(warnings)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if show_progress {
+            bar.finish_and_clear();
+        }
+        for slice_warnings in warnings {
+            for (output, count) in outputs.iter_mut().zip(slice_warnings) {
+                output.warnings += count;
+            }
+        }
 
-            let buff_row_start = out_row * slice_bytes; //layout.height_stride;
-            let (mut data, frame_offset) = match image_indices {
-                Some(indices) => (Vec::with_capacity(indices.len() * slice_bytes), indices[0]),
-                None => match size_hint {
-                    Some(hint) => (Vec::with_capacity(hint * slice_bytes), 0),
-                    None => (Vec::new(), 0),
-                },
-            };
-            let mut num_rows: usize = 0;
-            let mut num_bytes = 0;
-            {
-                let mut stream = PixelInputStream::new(file, self.compression.clone())?;
-                if let Some(indices) = image_indices {
-                    let mut curr_row = 0;
-                    let mut curr_idx = 0;
-                    loop {
-                        if curr_idx >= indices.len() {
-                            break;
-                        }
-                        if curr_row == indices[curr_idx] {
-                            if let Some(n_bytes) = stream.read_chunk(&mut data) {
-                                num_rows += 1;
-                                if num_bytes == 0 {
-                                    num_bytes = n_bytes;
-                                } else if num_bytes != n_bytes {
-                                    panic!("Unexpected data alignment in slice file {:?}", file);
-                                }
+        Ok(outputs
+            .into_par_iter()
+            .map(|output| self.finish_frame(layout, output, with_background, show_progress))
+            .collect())
+    }
+
+    /// Checks if medians and scales are calculated from sliding histograms, see [`sliding_stats`](#method.sliding_stats).
+    /// Requires 8 bit samples in RGB color space without sub-sampling, and an estimator available from histograms.
+    /// Only used for multiple frames with enough images per frame to be faster than sorting.
+    fn use_sliding_stats<T: Sample>(&self, windows: &FrameWindows, channels: usize) -> bool {
+        self.supports_histograms::<T>(channels)
+            && self.sample_count.is_none()
+            && windows.len() > 1
+            && windows.mean_len() >= SLIDING_MIN_IMAGES
+    }
+
+    /// Checks if medians and scales for outlier detection can be calculated from histograms.
+    /// Requires 8 bit samples in RGB color space, and an estimator available from histograms.
+    fn supports_histograms<T: Sample>(&self, channels: usize) -> bool {
+        T::BYTES == 1
+            && (self.color_space == ColorSpace::Rgb || channels < 3)
+            && (self.threshold.absolute()
+                || *self.threshold.estimator() == ScaleEstimator::Iqr
+                || *self.threshold.estimator() == ScaleEstimator::Mad)
+    }
+
+    /// Calculates the median and the robust scale (normalized like [`median_scale`](#method.median_scale))
+    /// of the values in a histogram. The scale is zero for absolute thresholds.
+    fn histogram_stats(threshold: &Threshold, histogram: &Histogram) -> (f32, f32) {
+        if threshold.absolute() {
+            (histogram.median(), 0.0)
+        } else {
+            match threshold.estimator() {
+                ScaleEstimator::Iqr => {
+                    let (q1, med, q3) = histogram.quartiles();
+                    (med, q3 - q1)
+                }
+                _ => {
+                    let med = histogram.median();
+                    (med, IQR_NORMAL * MAD_FACTOR * histogram.mad(med))
+                }
+            }
+        }
+    }
+
+    /// Calculates the medians and scales of all pixels of a time slice, for all frames.
+    /// Uses a histogram per channel that slides over the frames, by adding the images entering a frame's window
+    /// and removing the images leaving it. Results are identical to sorting.
+    fn sliding_stats<T: Sample>(
+        &self,
+        data: &[T],
+        windows: &FrameWindows,
+        num_rows: usize,
+        num_samples: usize,
+        channels: usize,
+    ) -> SlidingStats {
+        let positions: Vec<Vec<usize>> = (0..windows.len())
+            .map(|frame| windows.frame_positions(frame, num_rows))
+            .collect();
+        let frames = positions.len();
+        let mut stats = vec![PixelStats::default(); frames * (num_samples / channels)];
+        stats
+            .par_chunks_mut(frames)
+            .enumerate()
+            .for_each(|(col, pixel_stats)| {
+                let mut histograms = vec![Histogram::new(); channels];
+                let mut previous: &[usize] = &[];
+                for (frame, current) in positions.iter().enumerate() {
+                    Self::window_changes(previous, current, |row, add| {
+                        let offset = row * num_samples + col * channels;
+                        for (ch, histogram) in histograms.iter_mut().enumerate() {
+                            let value = data[offset + ch].as_f32() as u8;
+                            if add {
+                                histogram.add(value);
                             } else {
-                                break;
-                            }
-                            curr_idx += 1;
-                        } else {
-                            let result = stream.skip_chunk();
-                            if result.is_none() {
-                                break;
+                                histogram.remove(value);
                             }
                         }
-                        curr_row += 1;
-                    }
-                } else {
-                    while let Some(n_bytes) = stream.read_chunk(&mut data) {
-                        num_rows += 1;
-                        if num_bytes == 0 {
-                            num_bytes = n_bytes;
-                        } else if num_bytes != n_bytes {
-                            panic!("Unexpected data alignment in slice file {:?}", file);
+                    });
+                    previous = current;
+                    for (ch, histogram) in histograms.iter().enumerate() {
+                        if histogram.is_empty() {
+                            continue;
                         }
+                        let (median, scale) = Self::histogram_stats(&self.threshold, histogram);
+                        pixel_stats[frame].median[ch] = median;
+                        pixel_stats[frame].scale[ch] = scale;
                     }
                 }
+            });
+        SlidingStats { frames, stats }
+    }
+
+    /// Calls `change` for each row leaving (`false`) and entering (`true`) the window,
+    /// from the `previous` to the `current` window. Windows are sorted.
+    fn window_changes<F: FnMut(usize, bool)>(previous: &[usize], current: &[usize], mut change: F) {
+        let (mut i, mut j) = (0, 0);
+        while i < previous.len() || j < current.len() {
+            if j == current.len() || (i < previous.len() && previous[i] < current[j]) {
+                change(previous[i], false);
+                i += 1;
+            } else if i == previous.len() || current[j] < previous[i] {
+                change(current[j], true);
+                j += 1;
+            } else {
+                i += 1;
+                j += 1;
             }
-            if self.sample_indices.is_empty() {
-                if let Some(cnt) = self.sample_count {
-                    if cnt >= num_rows {
-                        self.sample_indices = (0..num_rows).collect();
-                    } else {
-                        self.sample_indices =
-                            rand::seq::sample_indices(&mut self.data.rng, num_rows, cnt);
-                        self.sample_indices.sort_unstable();
+        }
+    }
+
+    /// Creates a processor with the same settings, for processing another frame.
+    fn frame_processor(&self) -> Self {
+        OutlierProcessor::new(
+            self.threshold.clone(),
+            self.background.clone(),
+            self.outlier.clone(),
+            self.weights,
+            self.color_space.clone(),
+            self.mask_filters.clone(),
+            self.feather.clone(),
+            self.clean_plate.clone(),
+            self.fade.clone(),
+            self.sample_count,
+        )
+    }
+
+    /// Processes the pixels of a time slice for a single frame, and writes them to the time slice's view
+    /// of the frame's output buffers. Chunks of pixels are processed in parallel.
+    /// Returns the number of pixels that seem to consist of only outliers.
+    #[allow(clippy::too_many_arguments)]
+    fn process_slice<T: Sample>(
+        &self,
+        data: &[T],
+        num_rows: usize,
+        num_samples: usize,
+        buff_row_start: usize,
+        frame_offset: usize,
+        mask: Option<&OutlierMask>,
+        plate: Option<&[T]>,
+        stats: Option<(&SlidingStats, usize)>,
+        output: SliceBuffers<T>,
+    ) -> usize {
+        let channels = output.channels;
+        let (output, _) = output.split_at(num_samples);
+        output
+            .split(COLUMN_CHUNK * channels)
+            .into_par_iter()
+            .enumerate()
+            .map_init(
+                || self.thread_data(),
+                |scratch, (chunk, output)| {
+                    self.prepare_data(scratch, num_rows, channels);
+                    let mut pixel = [T::zero(); 4];
+                    let mut bg_pixel = [T::zero(); 4];
+                    let mut warnings = 0;
+                    for i in 0..(output.buffer.len() / channels) {
+                        let col = chunk * COLUMN_CHUNK + i;
+                        let col_offset = col * channels;
+                        let pix_offset = buff_row_start + col_offset;
+                        let out_offset = i * channels;
+
+                        let pixel_mask = mask.map(|mask| (mask, buff_row_start / channels + col));
+                        let (blend, warning) = self.calc_pixel(
+                            scratch,
+                            data,
+                            col_offset,
+                            num_samples,
+                            &mut pixel[..channels],
+                            &mut bg_pixel[..channels],
+                            frame_offset as i32,
+                            pixel_mask,
+                            plate.map(|plate| &plate[pix_offset..(pix_offset + channels)]),
+                            stats.map(|(stats, frame)| stats.get(frame, col)),
+                        );
+                        if warning {
+                            warnings += 1;
+                        }
+                        if !output.blend_mask.is_empty() {
+                            output.blend_mask[i] = blend;
+                        }
+                        if !output.background.is_empty() {
+                            output.background[out_offset..(out_offset + channels)]
+                                .copy_from_slice(&bg_pixel[..channels]);
+                        }
+                        let blend = T::from_f32(blend * T::MAX_VALUE);
+                        for (ch, value) in pixel[..channels].iter().enumerate() {
+                            let idx = out_offset + ch;
+                            output.buffer[idx] = *value;
+                            if ch < 3 {
+                                output.is_outlier[idx] = blend;
+                            } else {
+                                output.is_outlier[idx] = T::max_value();
+                            }
+                        }
                     }
-                } else {
-                    self.sample_indices = (0..num_rows).collect();
-                }
+                    warnings
+                },
+            )
+            .sum()
+    }
+
+    /// Finishes a frame after processing all time slices, by applying feathering.
+    /// Returns the output image, the blend image and the background image (if `with_background` is true).
+    #[allow(clippy::type_complexity)]
+    fn finish_frame<T: Sample>(
+        &self,
+        layout: &SampleLayout,
+        mut output: FrameBuffers<T>,
+        with_background: bool,
+        show_progress: bool,
+    ) -> (Vec<T>, Vec<T>, Option<Vec<T>>) {
+        if show_progress && output.warnings > 0 {
+            println!(
+                "Warning: {:?} pixels seem to consist of only outliers",
+                output.warnings
+            );
+        }
+
+        if let Some(feather) = &self.feather {
+            if show_progress {
+                println!("Feathering blend mask");
             }
-            if self.data.outlier_indices.len() != num_rows {
-                self.data.outlier_indices = vec![(0, 0.0); num_rows];
-                self.data.non_outlier_indices = vec![0; num_rows];
-                self.data.values = vec![0; self.sample_indices.len() * channels];
+            Self::feather(
+                feather,
+                layout,
+                &mut output.buffer,
+                &mut output.is_outlier,
+                &output.background,
+                &output.blend_mask,
+            );
+        }
+
+        (
+            output.buffer,
+            output.is_outlier,
+            if with_background {
+                Some(output.background)
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Feathers the blend mask, and re-blends the output image and the blend image.
+    fn feather<T: Sample>(
+        feather: &Feather,
+        layout: &SampleLayout,
+        buffer: &mut [T],
+        is_outlier: &mut [T],
+        background: &[T],
+        blend_mask: &[f32],
+    ) {
+        let channels = layout.width_stride;
+        let color_channels = std::cmp::min(channels, 3);
+
+        // Outlier colors multiplied by blend, from output = background + (outlier - background) * blend
+        let colors: Vec<f32> = buffer
+            .iter()
+            .zip(background)
+            .enumerate()
+            .map(|(i, (out, bg))| out.as_f32() - bg.as_f32() * (1.0 - blend_mask[i / channels]))
+            .collect();
+        let guide: Vec<f32> = buffer
+            .chunks(channels)
+            .map(|pix| {
+                pix[..color_channels]
+                    .iter()
+                    .map(|v| v.as_f32())
+                    .sum::<f32>()
+                    / (color_channels as f32 * T::MAX_VALUE)
+            })
+            .collect();
+
+        let (feathered, outlier_colors) = feather.apply(
+            blend_mask,
+            &colors,
+            &guide,
+            layout.width as usize,
+            layout.height as usize,
+            channels,
+        );
+        for (i, (out, bg)) in buffer.iter_mut().zip(background).enumerate() {
+            let blend = feathered[i / channels];
+            *out = T::from_f32(bg.as_f32() * (1.0 - blend) + outlier_colors[i] * blend);
+            if i % channels < 3 {
+                is_outlier[i] = T::from_f32(blend * T::MAX_VALUE);
             }
-            (0..(num_bytes / channels)).into_iter().for_each(|col| {
-                let col_offset = col as usize * channels;
-                let pix_offset = buff_row_start + col as usize * channels;
-
-                let (blend, warning) = self.calc_pixel(
-                    &data,
-                    col_offset,
-                    num_bytes,
-                    &mut pixel,
-                    frame_offset as i32,
-                );
-                if warning {
-                    warnings += 1;
+        }
+    }
+
+    /// Loads or calculates the clean plate, with the same layout as the output image.
+    fn create_clean_plate<T: Sample>(
+        &self,
+        clean_plate: &CleanPlate,
+        layout: &SampleLayout,
+        time_slices: &TimeSlices,
+        slice_samples: usize,
+        show_progress: bool,
+    ) -> Result<Vec<T>> {
+        match clean_plate {
+            CleanPlate::Image(path) => {
+                let image = open_image(path)?;
+                let samples = T::flat_samples(&image).ok_or_else(|| {
+                    Error::Layout(format!(
+                        "Clean plate {:?} must have the same bit depth as the processed images",
+                        path
+                    ))
+                })?;
+                let plate_layout = samples.layout;
+                if plate_layout.width != layout.width
+                    || plate_layout.height != layout.height
+                    || plate_layout.width_stride != layout.width_stride
+                {
+                    return Err(Error::Layout(format!(
+                        "Clean plate {:?} must have the same size and channels as the processed (and cropped) images: {}x{}x{}, got {}x{}x{}",
+                        path,
+                        layout.width,
+                        layout.height,
+                        layout.width_stride,
+                        plate_layout.width,
+                        plate_layout.height,
+                        plate_layout.width_stride
+                    )));
                 }
-                for ch in 0..channels {
-                    let idx = pix_offset + ch;
-                    buffer[idx] = pixel[ch];
-                    if ch < 3 {
-                        is_outlier[idx] = blend;
-                    } else {
-                        is_outlier[idx] = 255;
-                    }
+                Ok(samples.samples.to_vec())
+            }
+            CleanPlate::Frames(start, end) => {
+                if show_progress {
+                    println!("Creating clean plate from frames {} to {}", start, end);
                 }
-            });
+                let indices: Vec<usize> = (*start..*end).collect();
+                let mut plate = vec![T::zero(); layout.height as usize * layout.height_stride];
+                plate
+                    .par_chunks_mut(slice_samples)
+                    .enumerate()
+                    .try_for_each(|(out_row, plate)| {
+                        let (data, num_rows, num_samples) = Self::read_slice::<T>(
+                            time_slices,
+                            out_row,
+                            slice_samples,
+                            None,
+                            Some(&indices),
+                        )?;
+                        if num_rows == 0 {
+                            return Err(Error::Config(format!(
+                                "No frames in range {} to {} for clean plate",
+                                start, end
+                            )));
+                        }
+                        let mut values = vec![0.0; num_rows];
+                        let mut histogram = Histogram::new();
+                        for sample in 0..num_samples {
+                            if T::BYTES == 1 && num_rows >= HISTOGRAM_MIN_SAMPLES {
+                                histogram.clear();
+                                for row in 0..num_rows {
+                                    histogram.add(data[row * num_samples + sample].as_f32() as u8);
+                                }
+                                plate[sample] = T::from_f32(histogram.median());
+                                continue;
+                            }
+                            for (row, value) in values.iter_mut().enumerate() {
+                                *value = data[row * num_samples + sample].as_f32();
+                            }
+                            values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+                            plate[sample] = T::from_f32(Self::median(&values));
+                        }
+                        Ok(())
+                    })?;
+                Ok(plate)
+            }
         }
+    }
+
+    /// Determines per-frame outliers for all pixels and applies the spatial mask filters,
+    /// for each frame (and its processor) in a single pass over the time slices.
+    ///
+    /// This pass reads and decompresses all time slices in addition to the pass that blends the frames,
+    /// as filtering and region selection require the complete masks of a frame.
+    /// Masks take one bit per pixel and image of each frame's window.
+    #[allow(clippy::too_many_arguments)]
+    fn create_masks<T: Sample>(
+        processors: &mut [OutlierProcessor],
+        windows: &FrameWindows,
+        sliding: bool,
+        layout: &SampleLayout,
+        time_slices: &TimeSlices,
+        slice_samples: usize,
+        size_hint: Option<usize>,
+        plate: Option<&[T]>,
+        show_progress: bool,
+    ) -> Result<Vec<OutlierMask>> {
+        let channels = layout.width_stride;
+        let masks: Vec<Option<OutlierMask>> = processors.iter().map(|_| None).collect();
+        let masks = Mutex::new(masks);
+
+        if show_progress {
+            println!("Detecting outliers in {} time slices", time_slices.len());
+        }
+        let bar = ProgressBar::new(time_slices.len() as u64);
+        bar.set_draw_delta((time_slices.len() / 200) as u64);
+        let frame_processors: &[OutlierProcessor] = processors;
+        (0..time_slices.len())
+            .into_par_iter()
+            .try_for_each(|out_row| -> Result<()> {
+                let (data, num_rows, num_samples) = Self::read_slice::<T>(
+                    time_slices,
+                    out_row,
+                    slice_samples,
+                    size_hint,
+                    windows.union(),
+                )?;
+                let stats = if sliding {
+                    Some(frame_processors[0].sliding_stats(
+                        &data,
+                        windows,
+                        num_rows,
+                        num_samples,
+                        channels,
+                    ))
+                } else {
+                    None
+                };
+                let outliers: Vec<(usize, Vec<(usize, usize)>)> = frame_processors
+                    .par_iter()
+                    .enumerate()
+                    .map(|(frame, processor)| {
+                        let (data, num_rows) =
+                            windows.frame_data(frame, &data, num_rows, num_samples);
+                        let outliers = processor.slice_outliers(
+                            &data,
+                            num_rows,
+                            num_samples,
+                            channels,
+                            out_row * slice_samples,
+                            plate,
+                            stats.as_ref().map(|stats| (stats, frame)),
+                        );
+                        (num_rows, outliers)
+                    })
+                    .collect();
+
+                let mut masks = masks.lock().unwrap();
+                for (mask, (num_rows, outliers)) in masks.iter_mut().zip(outliers) {
+                    let mask = mask.get_or_insert_with(|| {
+                        OutlierMask::new(num_rows, layout.width as usize, layout.height as usize)
+                    });
+                    for (sample_idx, pixel) in outliers {
+                        mask.set(sample_idx, pixel, true);
+                    }
+                }
+                if show_progress {
+                    bar.inc(1);
+                }
+                Ok(())
+            })?;
         if show_progress {
             bar.finish_and_clear();
+            println!("Filtering outlier masks");
         }
+        let masks = masks.into_inner().unwrap();
+        Ok(processors
+            .par_iter_mut()
+            .zip(masks.into_par_iter())
+            .map(|(processor, mask)| {
+                let mut mask = mask.unwrap_or_else(|| OutlierMask::new(0, 0, 0));
+                mask.apply(&processor.mask_filters);
+                if processor.outlier == OutlierSelectionMode::Largest {
+                    if show_progress {
+                        println!("Selecting outlier regions");
+                    }
+                    processor.region_frames = mask.largest_regions();
+                }
+                mask
+            })
+            .collect())
+    }
 
-        if warnings > 0 {
-            println!(
-                "Warning: {:?} pixels seem to consist of only outliers",
-                warnings
-            );
+    /// Determines the outliers of all pixels of a time slice for a single frame.
+    /// Chunks of pixels are processed in parallel.
+    /// Returns the outliers as pairs of image index and (flat) pixel index.
+    #[allow(clippy::too_many_arguments)]
+    fn slice_outliers<T: Sample>(
+        &self,
+        data: &[T],
+        num_rows: usize,
+        num_samples: usize,
+        channels: usize,
+        pix_start: usize,
+        plate: Option<&[T]>,
+        stats: Option<(&SlidingStats, usize)>,
+    ) -> Vec<(usize, usize)> {
+        let columns = num_samples / channels;
+        let pixel_start = pix_start / channels;
+        let chunks: Vec<Vec<(usize, usize)>> = (0..columns.div_ceil(COLUMN_CHUNK))
+            .into_par_iter()
+            .map_init(
+                || self.thread_data(),
+                |scratch, chunk| {
+                    self.prepare_data(scratch, num_rows, channels);
+                    let mut outliers = vec![];
+                    let end = std::cmp::min((chunk + 1) * COLUMN_CHUNK, columns);
+                    for col in (chunk * COLUMN_CHUNK)..end {
+                        let (num_outliers, _, _) = self.find_outliers(
+                            scratch,
+                            data,
+                            col * channels,
+                            num_samples,
+                            channels,
+                            None,
+                            plate.map(|plate| &plate[(pix_start + col * channels)..][..channels]),
+                            stats.map(|(stats, frame)| stats.get(frame, col)),
+                        );
+                        for (sample_idx, _) in scratch.outlier_indices.iter().take(num_outliers) {
+                            outliers.push((*sample_idx, pixel_start + col));
+                        }
+                    }
+                    outliers
+                },
+            )
+            .collect();
+        chunks.concat()
+    }
+
+    /// Reads the data of a time slice, restricted to the given image indices.
+    /// Returns the data, the number of images read and the number of samples per image.
+    fn read_slice<T: Sample>(
+        time_slices: &TimeSlices,
+        index: usize,
+        slice_samples: usize,
+        size_hint: Option<usize>,
+        image_indices: Option<&[usize]>,
+    ) -> Result<(Vec<T>, usize, usize)> {
+        let mut data = match image_indices {
+            Some(indices) => Vec::with_capacity(indices.len() * slice_samples),
+            None => match size_hint {
+                Some(hint) => Vec::with_capacity(hint * slice_samples),
+                None => Vec::new(),
+            },
+        };
+        let mut num_rows: usize = 0;
+        let mut num_samples = 0;
+        let mut stream = time_slices.open(index)?;
+        if let Some(indices) = image_indices {
+            for image in indices {
+                stream.seek_chunk(*image)?;
+                if let Some(n_samples) = stream.read_chunk(&mut data)? {
+                    num_rows += 1;
+                    if num_samples == 0 {
+                        num_samples = n_samples;
+                    } else if num_samples != n_samples {
+                        return Err(Error::Layout(format!(
+                            "Unexpected data alignment in time slice {}",
+                            index
+                        )));
+                    }
+                } else {
+                    break;
+                }
+            }
+        } else {
+            while let Some(n_samples) = stream.read_chunk(&mut data)? {
+                num_rows += 1;
+                if num_samples == 0 {
+                    num_samples = n_samples;
+                } else if num_samples != n_samples {
+                    return Err(Error::Layout(format!(
+                        "Unexpected data alignment in time slice {}",
+                        index
+                    )));
+                }
+            }
         }
+        Ok((data, num_rows, num_samples))
+    }
+
+    /// Creates per-thread data structures, to be prepared by [`prepare_data`](#method.prepare_data).
+    fn thread_data(&self) -> ThreadData {
+        ThreadData {
+            sample_indices: vec![],
+            outlier_indices: vec![],
+            non_outlier_indices: vec![],
+            values: vec![],
+            converted: vec![],
+            histograms: vec![],
+            rng: SmallRng::from_entropy(),
+        }
+    }
 
-        Ok((buffer, is_outlier))
+    /// Prepares sample indices and per-thread data structures for the given number of images.
+    fn prepare_data(&self, scratch: &mut ThreadData, num_rows: usize, channels: usize) {
+        if scratch.outlier_indices.len() == num_rows && scratch.histograms.len() == channels {
+            return;
+        }
+        scratch.sample_indices = match self.sample_count {
+            Some(cnt) if cnt < num_rows => {
+                let mut rng = SmallRng::seed_from_u64(self.sample_seed);
+                let mut indices = rand::seq::sample_indices(&mut rng, num_rows, cnt);
+                indices.sort_unstable();
+                indices
+            }
+            _ => (0..num_rows).collect(),
+        };
+        scratch.outlier_indices = vec![(0, 0.0); num_rows];
+        scratch.non_outlier_indices = vec![0; num_rows];
+        scratch.values = vec![0.0; scratch.sample_indices.len() * channels];
+        scratch.converted = vec![0.0; num_rows * channels];
+        scratch.histograms = vec![Histogram::new(); channels];
     }
 
-    fn calc_pixel(
-        &mut self,
-        data: &[u8],
+    /// Determines the outliers of a single pixel, and stores them in `outlier_indices`, in frame order.
+    /// Uses the outlier mask for the given (flat) pixel index instead of the threshold, if provided.
+    /// Uses the clean plate's pixel (`reference`) instead of the median, if provided.
+    /// Uses pre-calculated medians and scales (`stats`) instead of sorting, if provided.
+    /// Otherwise, uses histograms instead of sorting for large samples of 8 bit data.
+    /// Returns the number of outliers, and the squared distance and index of the most extreme outlier.
+    #[allow(clippy::too_many_arguments)]
+    fn find_outliers<T: Sample>(
+        &self,
+        scratch: &mut ThreadData,
+        data: &[T],
         offset: usize,
         stride: usize,
-        mut pixel: &mut [u8],
-        frame_offset: i32,
-    ) -> (u8, bool) {
-        let channels = pixel.len();
+        channels: usize,
+        mask: Option<(&OutlierMask, usize)>,
+        reference: Option<&[T]>,
+        stats: Option<&PixelStats>,
+    ) -> (usize, f32, usize) {
         let samples = data.len() / stride;
-        let sub_samples = self.sample_indices.len();
+        let sub_samples = scratch.sample_indices.len();
 
         let threshold_sq = self.threshold.min() * self.threshold.min();
+        let max_sq = self.threshold.max() * self.threshold.max();
+        // Distances are calculated in 8 bit color range, as absolute thresholds are given in it
+        let value_scale = 255.0 / T::MAX_VALUE;
 
         let mut median = [0.0; 4];
-        let mut iqr_inv = [0.0; 4];
+        let mut scale_inv = [0.0; 4];
 
-        // Prepare medians
-        // TODO: allow restriction to a sample
-        for (sample_idx, data_idx) in self.sample_indices.iter().enumerate() {
-            let idx = data_idx * stride + offset;
+        // Convert to the color space for distance calculation
+        let mut scaled = [0.0; 4];
+        for sample_idx in 0..samples {
+            let idx = sample_idx * stride + offset;
             for ch in 0..channels {
-                if self.weights[ch] != 0.0 {
-                    let p = data[idx + ch];
-                    self.data.values[ch * sub_samples + sample_idx] = p;
-                }
+                scaled[ch] = data[idx + ch].as_f32() * value_scale;
             }
+            let conv_idx = sample_idx * channels;
+            self.color_space.convert(
+                &scaled[..channels],
+                &mut scratch.converted[conv_idx..(conv_idx + channels)],
+            );
         }
 
-        // Calculate medians and inverse inter-quartile range
-        for i in 0..channels {
-            if self.weights[i] != 0.0 {
-                let slice =
-                    &mut self.data.values[(i * sub_samples)..(i * sub_samples + sub_samples)];
-                slice.sort_unstable();
-                if self.threshold.absolute() {
-                    median[i] = Self::median(slice);
-                } else {
-                    let (q1, med, q3) = Self::quartiles(slice);
+        // With a clean plate and absolute thresholds, medians and scales are not required
+        let reference_only = reference.is_some() && self.threshold.absolute();
+        if let (false, Some(stats)) = (reference_only, stats) {
+            for i in 0..channels {
+                if self.weights[i] != 0.0 {
+                    median[i] = stats.median[i];
+                    if !self.threshold.absolute() {
+                        let scale = stats.scale[i];
+                        scale_inv[i] = if scale == 0.0 { 1.0 } else { 1.0 / scale };
+                    }
+                }
+            }
+        } else if !reference_only
+            && sub_samples >= HISTOGRAM_MIN_SAMPLES
+            && self.supports_histograms::<T>(channels)
+        {
+            // Calculate medians and scales from histograms, with identical results to sorting
+            for i in 0..channels {
+                if self.weights[i] != 0.0 {
+                    let histogram = &mut scratch.histograms[i];
+                    histogram.clear();
+                    for data_idx in &scratch.sample_indices {
+                        histogram.add(scratch.converted[data_idx * channels + i] as u8);
+                    }
+                    let (med, scale) = Self::histogram_stats(&self.threshold, histogram);
                     median[i] = med;
-                    iqr_inv[i] = q3 - q1;
-                    if iqr_inv[i] == 0.0 {
-                        iqr_inv[i] = 1.0;
+                    if !self.threshold.absolute() {
+                        scale_inv[i] = if scale == 0.0 { 1.0 } else { 1.0 / scale };
+                    }
+                }
+            }
+        } else if !reference_only {
+            // Prepare medians
+            for (sample_idx, data_idx) in scratch.sample_indices.iter().enumerate() {
+                let idx = data_idx * channels;
+                for ch in 0..channels {
+                    if self.weights[ch] != 0.0 {
+                        scratch.values[ch * sub_samples + sample_idx] = scratch.converted[idx + ch];
+                    }
+                }
+            }
+
+            // Calculate medians and inverse robust scale (e.g. inter-quartile range)
+            for i in 0..channels {
+                if self.weights[i] != 0.0 {
+                    let slice =
+                        &mut scratch.values[(i * sub_samples)..(i * sub_samples + sub_samples)];
+                    slice.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+                    let circular = self.color_space.is_circular(i, channels);
+                    let shift = if circular {
+                        Self::circular_shift(slice, HUE_PERIOD)
+                    } else {
+                        0.0
+                    };
+                    if self.threshold.absolute() {
+                        median[i] = Self::median(slice);
+                    } else {
+                        let (med, scale) = Self::median_scale(slice, self.threshold.estimator());
+                        median[i] = med;
+                        scale_inv[i] = if scale == 0.0 { 1.0 } else { 1.0 / scale };
+                    }
+                    if circular {
+                        median[i] = (median[i] - shift).rem_euclid(HUE_PERIOD);
                     }
-                    iqr_inv[i] = 1.0 / iqr_inv[i];
                 }
             }
         }
+        if let Some(reference) = reference {
+            for ch in 0..channels {
+                scaled[ch] = reference[ch].as_f32() * value_scale;
+            }
+            self.color_space
+                .convert(&scaled[..channels], &mut median[..channels]);
+        }
 
         let mut num_outliers = 0;
         let mut max_dist_sq = 0.0;
         let mut max_index = 0;
 
         for sample_idx in 0..samples {
-            let pix_offset = sample_idx * stride + offset;
-            let pix = &data[pix_offset..(pix_offset + channels)];
+            let conv_offset = sample_idx * channels;
+            let pix = &scratch.converted[conv_offset..(conv_offset + channels)];
             let mut dist_sq = 0.0;
             for (i, p) in pix.iter().enumerate() {
                 let w = self.weights[i];
                 if w != 0.0 {
-                    let diff = median[i] - *p as f32;
+                    let mut diff = median[i] - p;
+                    if self.color_space.is_circular(i, channels) {
+                        diff -= HUE_PERIOD * (diff / HUE_PERIOD).round();
+                    }
                     dist_sq += if diff == 0.0 {
                         0.0
                     } else {
                         if self.threshold.absolute() {
                             w.signum() * (w * diff).powi(2)
                         } else {
-                            w.signum() * (w * iqr_inv[i] * diff).powi(2)
+                            w.signum() * (w * scale_inv[i] * diff).powi(2)
                         }
                     };
                 }
             }
-            if dist_sq >= threshold_sq {
-                self.data.outlier_indices[num_outliers] = (sample_idx, dist_sq);
+            let is_outlier = match mask {
+                Some((mask, pixel)) => {
+                    // Outliers added by mask filtering are blended fully
+                    if dist_sq < threshold_sq {
+                        dist_sq = max_sq;
+                    }
+                    mask.get(sample_idx, pixel)
+                }
+                None => dist_sq >= threshold_sq,
+            };
+            if is_outlier {
+                scratch.outlier_indices[num_outliers] = (sample_idx, dist_sq);
                 num_outliers += 1;
                 if dist_sq > max_dist_sq {
                     max_dist_sq = dist_sq;
@@ -287,105 +1303,165 @@ impl OutlierProcessor {
             }
         }
 
+        (num_outliers, max_dist_sq, max_index)
+    }
+
+    /// Calculates the output color of a single pixel, and writes the pixel's background color to `background`.
+    /// Uses the outlier mask for the given (flat) pixel index to determine outliers, if provided.
+    /// Uses the clean plate's pixel (`reference`) as background and for outlier detection, if provided.
+    /// Uses pre-calculated medians and scales (`stats`) instead of sorting, if provided.
+    /// Returns the blend value [0, 1] and a warning flag if only outliers were found.
+    #[allow(clippy::too_many_arguments)]
+    fn calc_pixel<T: Sample>(
+        &self,
+        scratch: &mut ThreadData,
+        data: &[T],
+        offset: usize,
+        stride: usize,
+        pixel: &mut [T],
+        background: &mut [T],
+        frame_offset: i32,
+        mask: Option<(&OutlierMask, usize)>,
+        reference: Option<&[T]>,
+        stats: Option<&PixelStats>,
+    ) -> (f32, bool) {
+        let channels = pixel.len();
+        let samples = data.len() / stride;
+
+        let (num_outliers, max_dist_sq, max_index) = self.find_outliers(
+            scratch, data, offset, stride, channels, mask, reference, stats,
+        );
+
         let has_outliers = num_outliers > 0;
         let mut has_warning = false;
 
         // Fill pixel with background
-        match self.background {
-            BackgroundMode::Average => {
-                let mut mean = [0.0; 4];
-                for sample_idx in 0..samples {
-                    let pix_offset = sample_idx * stride + offset;
-                    let pix = &data[pix_offset..(pix_offset + channels)];
-                    for (i, p) in pix.iter().enumerate() {
-                        mean[i] += *p as f32;
-                    }
-                }
-                for m in mean.iter_mut() {
-                    *m /= samples as f32;
-                }
-                if has_outliers {
-                    if num_outliers == 1 {
-                        let off = self.data.outlier_indices[0].0 * stride + offset;
-                        let sample = &data[off..(off + channels)];
-                        for ch in 0..channels {
-                            pixel[ch] = (mean[ch] * (samples as f32 / (samples - 1) as f32)
-                                - sample[ch] as f32 / samples as f32)
-                                .round() as u8;
+        if let Some(reference) = reference {
+            pixel.copy_from_slice(reference);
+        } else {
+            match self.background {
+                BackgroundMode::Average => {
+                    let mut mean = [0.0; 4];
+                    for sample_idx in 0..samples {
+                        let pix_offset = sample_idx * stride + offset;
+                        let pix = &data[pix_offset..(pix_offset + channels)];
+                        for (i, p) in pix.iter().enumerate() {
+                            mean[i] += p.as_f32();
                         }
-                    } else {
-                        let mut outlier_sum = [0.0; 4];
-                        for (sample_idx, _dist_sq) in
-                            self.data.outlier_indices.iter().take(num_outliers)
-                        {
-                            let off = sample_idx * stride + offset;
+                    }
+                    for m in mean.iter_mut() {
+                        *m /= samples as f32;
+                    }
+                    if has_outliers {
+                        if num_outliers == 1 {
+                            let off = scratch.outlier_indices[0].0 * stride + offset;
+                            let sample = &data[off..(off + channels)];
+                            for ch in 0..channels {
+                                pixel[ch] = T::from_f32(
+                                    mean[ch] * (samples as f32 / (samples - 1) as f32)
+                                        - sample[ch].as_f32() / samples as f32,
+                                );
+                            }
+                        } else {
+                            let mut outlier_sum = [0.0; 4];
+                            for (sample_idx, _dist_sq) in
+                                scratch.outlier_indices.iter().take(num_outliers)
+                            {
+                                let off = sample_idx * stride + offset;
+                                for ch in 0..channels {
+                                    outlier_sum[ch] += data[off + ch].as_f32();
+                                }
+                            }
+                            // TODO: check the equation again!
+                            let num_non_outliers = samples - num_outliers;
                             for ch in 0..channels {
-                                outlier_sum[ch] += data[off + ch] as f32;
+                                pixel[ch] = T::from_f32(
+                                    mean[ch] * (samples as f32 / num_non_outliers as f32)
+                                        - outlier_sum[ch] / samples as f32,
+                                );
                             }
                         }
-                        // TODO: check the equation again!
-                        let num_non_outliers = samples - num_outliers;
+                    } else {
                         for ch in 0..channels {
-                            pixel[ch] = (mean[ch] * (samples as f32 / num_non_outliers as f32)
-                                - outlier_sum[ch] / samples as f32)
-                                .round() as u8;
+                            pixel[ch] = T::from_f32(mean[ch]);
                         }
                     }
-                } else {
-                    for ch in 0..channels {
-                        pixel[ch] = mean[ch].round() as u8;
-                    }
                 }
-            }
-            BackgroundMode::Median => {
-                // In case of median, we don't remove the outliers!
-                for ch in 0..channels {
-                    pixel[ch] = median[ch].round() as u8;
+                BackgroundMode::Median if stats.is_some() => {
+                    let stats = stats.unwrap();
+                    for (p, median) in pixel.iter_mut().zip(&stats.median) {
+                        *p = T::from_f32(*median);
+                    }
                 }
-            }
-            BackgroundMode::First | BackgroundMode::Random => {
-                let (sample_idx, warning) = match self.background {
-                    BackgroundMode::First => {
-                        if !has_outliers {
-                            (0, false)
-                        } else {
-                            self.first_excluded(samples, num_outliers).unwrap()
+                BackgroundMode::Median
+                    if T::BYTES == 1 && scratch.sample_indices.len() >= HISTOGRAM_MIN_SAMPLES =>
+                {
+                    for ch in 0..channels {
+                        let histogram = &mut scratch.histograms[ch];
+                        histogram.clear();
+                        for data_idx in &scratch.sample_indices {
+                            histogram.add(data[data_idx * stride + offset + ch].as_f32() as u8);
                         }
+                        pixel[ch] = T::from_f32(histogram.median());
                     }
-                    BackgroundMode::Random => {
-                        if !has_outliers {
-                            (self.data.rng.gen_range(0, samples), false)
-                        } else {
-                            self.sample_excluded(samples, num_outliers).unwrap()
+                }
+                BackgroundMode::Median => {
+                    // In case of median, we don't remove the outliers!
+                    let sub_samples = scratch.sample_indices.len();
+                    for ch in 0..channels {
+                        let values =
+                            &mut scratch.values[(ch * sub_samples)..((ch + 1) * sub_samples)];
+                        for (value, data_idx) in values.iter_mut().zip(&scratch.sample_indices) {
+                            *value = data[data_idx * stride + offset + ch].as_f32();
                         }
+                        values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+                        pixel[ch] = T::from_f32(Self::median(values));
                     }
-                    _ => (0, false),
-                };
-                let off = sample_idx * stride + offset;
-                let sample = &data[off..(off + channels)];
-
-                for ch in 0..channels {
-                    pixel[ch] = sample[ch];
                 }
+                BackgroundMode::First | BackgroundMode::Random => {
+                    let (sample_idx, warning) = match self.background {
+                        BackgroundMode::First => {
+                            if !has_outliers {
+                                (0, false)
+                            } else {
+                                Self::first_excluded(scratch, samples, num_outliers).unwrap()
+                            }
+                        }
+                        BackgroundMode::Random => {
+                            if !has_outliers {
+                                (scratch.rng.gen_range(0, samples), false)
+                            } else {
+                                Self::sample_excluded(scratch, samples, num_outliers).unwrap()
+                            }
+                        }
+                        _ => (0, false),
+                    };
+                    let off = sample_idx * stride + offset;
+                    let sample = &data[off..(off + channels)];
 
-                if warning {
-                    has_warning = true;
+                    pixel.copy_from_slice(sample);
+
+                    if warning {
+                        has_warning = true;
+                    }
                 }
             }
         }
 
+        background.copy_from_slice(pixel);
+
         if has_outliers {
             // Get outlier
             if num_outliers == 1 {
                 // Only one outlier
-                let (sample_idx, dist_sq) = self.data.outlier_indices[0];
+                let (sample_idx, dist_sq) = scratch.outlier_indices[0];
                 let off = sample_idx * stride + offset;
                 let sample = &data[off..(off + channels)];
 
                 let fade = self.fade(sample_idx as i32, samples as i32, frame_offset);
                 let blend = fade * self.threshold.blend_value(dist_sq.sqrt());
-                color::blend_into_u8(&mut pixel, &sample, blend);
-                ((blend * 255.0).round() as u8, has_warning)
+                color::blend_into(pixel, sample, blend);
+                (blend, has_warning)
             } else {
                 // More outliers
                 if self.outlier == OutlierSelectionMode::AllForward
@@ -394,44 +1470,44 @@ impl OutlierProcessor {
                     let mut pix_new = [0.0; 4];
                     let mut blend_inv = 1.0;
                     for ch in 0..channels {
-                        pix_new[ch] = pixel[ch] as f32;
+                        pix_new[ch] = pixel[ch].as_f32();
                     }
                     if self.outlier == OutlierSelectionMode::AllForward {
                         for (sample_idx, dist_sq) in
-                            self.data.outlier_indices.iter().take(num_outliers)
+                            scratch.outlier_indices.iter().take(num_outliers)
                         {
                             let off = sample_idx * stride + offset;
                             let sample = &data[off..(off + channels)];
                             // Blend outlier into background
                             let fade = self.fade(*sample_idx as i32, samples as i32, frame_offset);
                             let blend = fade * self.threshold.blend_value(dist_sq.sqrt());
-                            color::blend_into_f32_u8(&mut pix_new, &sample, blend);
+                            color::blend_into_f32_sample(&mut pix_new, sample, blend);
                             blend_inv *= 1.0 - blend;
                         }
                     } else {
                         for (sample_idx, dist_sq) in
-                            self.data.outlier_indices.iter().take(num_outliers).rev()
+                            scratch.outlier_indices.iter().take(num_outliers).rev()
                         {
                             let off = sample_idx * stride + offset;
                             let sample = &data[off..(off + channels)];
                             // Blend outlier into background
                             let fade = self.fade(*sample_idx as i32, samples as i32, frame_offset);
                             let blend = fade * self.threshold.blend_value(dist_sq.sqrt());
-                            color::blend_into_f32_u8(&mut pix_new, &sample, blend);
+                            color::blend_into_f32_sample(&mut pix_new, sample, blend);
                             blend_inv *= 1.0 - blend;
                         }
                     }
                     for ch in 0..channels {
-                        pixel[ch] = pix_new[ch].round() as u8;
+                        pixel[ch] = T::from_f32(pix_new[ch]);
                     }
-                    (((1.0 - blend_inv) * 255.0).round() as u8, has_warning)
+                    (1.0 - blend_inv, has_warning)
                 } else {
-                    let mut temp_sample = [0; 4];
+                    let mut temp_sample = [T::zero(); 4];
                     let (sample_idx, sample, dist) = if self.outlier
                         == OutlierSelectionMode::Average
                     {
                         if num_outliers == 1 {
-                            let (sample_idx, dist_sq) = self.data.outlier_indices[0];
+                            let (sample_idx, dist_sq) = scratch.outlier_indices[0];
                             let off = sample_idx * stride + offset;
                             let sample = &data[off..(off + channels)];
                             (sample_idx, sample, dist_sq.sqrt())
@@ -441,38 +1517,45 @@ impl OutlierProcessor {
                                 let pix_offset = sample_idx * stride + offset;
                                 let pix = &data[pix_offset..(pix_offset + channels)];
                                 for (i, p) in pix.iter().enumerate() {
-                                    mean[i] += *p as f32;
+                                    mean[i] += p.as_f32();
                                 }
                             }
                             for m in mean.iter_mut() {
                                 *m /= samples as f32;
                             }
 
-                            for ch in 0..channels {
-                                mean[ch] = 0.0;
+                            for m in mean.iter_mut() {
+                                *m = 0.0;
                             }
                             let mut mean_dist = 0.0;
                             for (sample_idx, dist_sq) in
-                                self.data.outlier_indices.iter().take(num_outliers)
+                                scratch.outlier_indices.iter().take(num_outliers)
                             {
                                 let off = sample_idx * stride + offset;
                                 for ch in 0..channels {
-                                    mean[ch] += data[off + ch] as f32;
+                                    mean[ch] += data[off + ch].as_f32();
                                 }
                                 mean_dist += dist_sq.sqrt();
                             }
                             for ch in 0..channels {
-                                temp_sample[ch] = (mean[ch] / num_outliers as f32).round() as u8;
+                                temp_sample[ch] = T::from_f32(mean[ch] / num_outliers as f32);
                             }
                             (0, &temp_sample[..], mean_dist / num_outliers as f32)
                         }
                     } else {
                         let (sample_idx, dist_sq) = match self.outlier {
-                            OutlierSelectionMode::First => self.data.outlier_indices[0],
-                            OutlierSelectionMode::Last => {
-                                self.data.outlier_indices[num_outliers - 1]
-                            }
+                            OutlierSelectionMode::First => scratch.outlier_indices[0],
+                            OutlierSelectionMode::Last => scratch.outlier_indices[num_outliers - 1],
                             OutlierSelectionMode::Extreme => (max_index, max_dist_sq),
+                            OutlierSelectionMode::Largest => {
+                                let frame = mask.and_then(|(_, pixel)| self.region_frames[pixel]);
+                                *scratch
+                                    .outlier_indices
+                                    .iter()
+                                    .take(num_outliers)
+                                    .find(|(sample_idx, _)| Some(*sample_idx) == frame)
+                                    .unwrap_or(&(max_index, max_dist_sq))
+                            }
                             OutlierSelectionMode::Average
                             | OutlierSelectionMode::AllForward
                             | OutlierSelectionMode::AllBackward => (0, 0.0),
@@ -484,12 +1567,12 @@ impl OutlierProcessor {
                     // Blend outlier into background
                     let fade = self.fade(sample_idx as i32, samples as i32, frame_offset);
                     let blend = fade * self.threshold.blend_value(dist);
-                    color::blend_into_u8(&mut pixel, &sample, blend);
-                    ((blend * 255.0).round() as u8, has_warning)
+                    color::blend_into(pixel, sample, blend);
+                    (blend, has_warning)
                 }
             }
         } else {
-            (0, has_warning)
+            (0.0, has_warning)
         }
     }
 
@@ -503,7 +1586,7 @@ impl OutlierProcessor {
 
     /// Returns the first index in 0..samples that does not appear in the outliers
     fn first_excluded(
-        &self,
+        scratch: &ThreadData,
         samples: usize,
         num_outliers: usize,
     ) -> Result<(usize, bool), PixelSelectionError> {
@@ -514,7 +1597,7 @@ impl OutlierProcessor {
             ));*/
             return Ok((0, true));
         }
-        let excluded = &self.data.outlier_indices[..num_outliers];
+        let excluded = &scratch.outlier_indices[..num_outliers];
         let len = excluded.len();
         let mut excl_index = 0;
         for i in 0..samples {
@@ -530,7 +1613,7 @@ impl OutlierProcessor {
     }
     /// Returns a random index in 0..samples that does not appear in the outliers
     fn sample_excluded(
-        &mut self,
+        scratch: &mut ThreadData,
         samples: usize,
         num_outliers: usize,
     ) -> Result<(usize, bool), PixelSelectionError> {
@@ -539,24 +1622,150 @@ impl OutlierProcessor {
                 "Unable to select random background pixel. All pixels seem to be outliers."
                     .to_string(),
             ));*/
-            return Ok((self.data.rng.gen_range(0_usize, samples), true));
+            return Ok((scratch.rng.gen_range(0_usize, samples), true));
         }
-        let excluded = &self.data.outlier_indices[..num_outliers];
-        for (i, idx) in self.data.non_outlier_indices.iter_mut().enumerate() {
+        let excluded = &scratch.outlier_indices[..num_outliers];
+        for (i, idx) in scratch.non_outlier_indices.iter_mut().enumerate() {
             *idx = i;
         }
         let mut candidates = samples;
         for idx in excluded {
-            self.data.non_outlier_indices.swap(idx.0, candidates - 1);
+            scratch.non_outlier_indices.swap(idx.0, candidates - 1);
             candidates -= 1;
         }
-        let idx = self.data.rng.gen_range(0_usize, candidates);
-        Ok((self.data.non_outlier_indices[idx], false))
+        let idx = scratch.rng.gen_range(0_usize, candidates);
+        Ok((scratch.non_outlier_indices[idx], false))
+    }
+
+    /// Shifts a sorted sample of circular values by half a period, if this reduces the inter-quartile range,
+    /// i.e. if the values are concentrated around the wrap-around point.
+    /// The sample is sorted afterwards. Returns the applied shift.
+    fn circular_shift(data: &mut [f32], period: f32) -> f32 {
+        let (q1, _, q3) = Self::quartiles(data);
+        let half = 0.5 * period;
+        for v in data.iter_mut() {
+            *v = (*v + half).rem_euclid(period);
+        }
+        data.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        let (q1_sh, _, q3_sh) = Self::quartiles(data);
+        if q3_sh - q1_sh < q3 - q1 {
+            half
+        } else {
+            for v in data.iter_mut() {
+                *v = (*v - half).rem_euclid(period);
+            }
+            data.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+            0.0
+        }
+    }
+
+    /// Calculates the median and a robust scale estimate of a sorted sample.
+    /// Scale estimates are normalized to the inter-quartile range of a normal distribution.
+    /// Values in `data` may be modified.
+    fn median_scale(data: &mut [f32], estimator: &ScaleEstimator) -> (f32, f32) {
+        match estimator {
+            ScaleEstimator::Iqr => {
+                let (q1, med, q3) = Self::quartiles(data);
+                (med, q3 - q1)
+            }
+            ScaleEstimator::Mad => {
+                let med = Self::median(data);
+                for v in data.iter_mut() {
+                    *v = (*v - med).abs();
+                }
+                data.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+                (med, IQR_NORMAL * MAD_FACTOR * Self::median(data))
+            }
+            ScaleEstimator::Sn => (Self::median(data), IQR_NORMAL * SN_FACTOR * Self::sn(data)),
+            ScaleEstimator::Qn => (Self::median(data), IQR_NORMAL * QN_FACTOR * Self::qn(data)),
+        }
+    }
+
+    /// Calculates the (unnormalized) Sn scale estimator of a sorted sample:
+    /// the low median over i of the high median over j of |x_i - x_j|.
+    fn sn(data: &[f32]) -> f32 {
+        let n = data.len();
+        let mut inner = Vec::with_capacity(n);
+        for i in 0..n {
+            // Distances to the left and to the right are sorted sequences.
+            // The distance to x_i itself (0) is always the smallest.
+            let k = n / 2;
+            let dist = if k == 0 {
+                0.0
+            } else {
+                Self::kth_of_two(
+                    |j| data[i] - data[i - 1 - j],
+                    i,
+                    |j| data[i + 1 + j] - data[i],
+                    n - i - 1,
+                    k - 1,
+                )
+            };
+            inner.push(dist);
+        }
+        inner.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        inner[n.div_ceil(2) - 1]
+    }
+
+    /// Calculates the (unnormalized) Qn scale estimator of a sorted sample:
+    /// the k-th smallest of all pairwise distances |x_i - x_j| with i < j, where k = h(h-1)/2 and h = n/2 + 1.
+    fn qn(data: &[f32]) -> f32 {
+        let n = data.len();
+        if n < 2 {
+            return 0.0;
+        }
+        let h = n / 2 + 1;
+        let k = h * (h - 1) / 2;
+        // Binary search on the distance, counting pairs within the distance with two pointers
+        let mut lower = 0.0;
+        let mut upper = data[n - 1] - data[0];
+        for _ in 0..32 {
+            let mid = 0.5 * (lower + upper);
+            let mut count = 0;
+            let mut j = 0;
+            for i in 0..n {
+                while j < n && data[j] - data[i] <= mid {
+                    j += 1;
+                }
+                count += j - i - 1;
+            }
+            if count >= k {
+                upper = mid;
+            } else {
+                lower = mid;
+            }
+        }
+        upper
+    }
+
+    /// Returns the k-th (zero-based) smallest value of the union of two sorted sequences a and b,
+    /// given by accessor functions and lengths.
+    fn kth_of_two<A, B>(a: A, len_a: usize, b: B, len_b: usize, k: usize) -> f32
+    where
+        A: Fn(usize) -> f32,
+        B: Fn(usize) -> f32,
+    {
+        // Binary search for the number of elements taken from a
+        let mut lo = (k + 1).saturating_sub(len_b);
+        let mut hi = std::cmp::min(k + 1, len_a);
+        while lo < hi {
+            let i = (lo + hi) / 2;
+            if a(i) < b(k - i) {
+                lo = i + 1;
+            } else {
+                hi = i;
+            }
+        }
+        let i = lo;
+        let j = k + 1 - i;
+        let from_a = if i > 0 { a(i - 1) } else { f32::NEG_INFINITY };
+        let from_b = if j > 0 { b(j - 1) } else { f32::NEG_INFINITY };
+        from_a.max(from_b)
     }
 
     /// Calculates quartiles from a sample.
     /// Return (Q1, Median, Q3)
-    fn quartiles(data: &[u8]) -> (f32, f32, f32) {
+    fn quartiles(data: &[f32]) -> (f32, f32, f32) {
         (
             Self::quantile(data, 0.25),
             Self::median(data),
@@ -565,41 +1774,126 @@ impl OutlierProcessor {
     }
 
     /// Calculates a quantile a sample.
-    fn quantile(data: &[u8], q: f32) -> f32 {
-        let pos = (data.len() + 1) as f32 * q;
-        let p1 = pos as usize - 1;
-        let frac = pos.fract();
-        if frac < 0.001 {
-            data[p1] as f32
-        } else if frac > 0.999 {
-            data[p1 + 1] as f32
-        } else {
-            (1.0 - frac) * data[p1] as f32 + frac * data[p1 + 1] as f32
-        }
+    fn quantile(data: &[f32], q: f32) -> f32 {
+        histogram::quantile_by(data.len(), q, |i| data[i])
     }
 
     /// Calculates the median of a sample.
-    fn median(data: &[u8]) -> f32 {
-        let len = data.len();
-
-        if (len + 1) % 2 == 0 {
-            data[(len + 1) / 2 - 1] as f32
-        } else {
-            let idx = (len + 1) / 2;
-            0.5 * (data[idx - 1] as f32 + data[idx] as f32)
-        }
+    fn median(data: &[f32]) -> f32 {
+        histogram::median_by(data.len(), |i| data[i])
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::chrono::OutlierProcessor;
+    use crate::chrono::{FrameBuffers, FrameWindows, OutlierProcessor};
+    use crate::options::{
+        BackgroundMode, ColorSpace, Fade, OutlierSelectionMode, ScaleEstimator, Threshold,
+    };
+    use image::flat::SampleLayout;
+
+    #[test]
+    fn slice_buffers_test() {
+        // 5 x 3 pixels, 3 channels, slices of 4 pixels
+        let layout = SampleLayout::row_major_packed(3, 5, 3);
+        let mut frame = FrameBuffers::<u8>::new(&layout, true, false);
+        let mut slices = frame.slices_mut(12);
+        assert_eq!(slices.len(), 4);
+        assert_eq!(slices[3].buffer.len(), 9);
+        assert_eq!(slices[3].blend_mask.len(), 3);
+        assert_eq!(slices[3].background.len(), 9);
+
+        for (i, slice) in slices.iter_mut().enumerate() {
+            slice.buffer.iter_mut().for_each(|v| *v = i as u8);
+            slice.blend_mask.iter_mut().for_each(|v| *v = i as f32);
+        }
+        assert_eq!(frame.buffer[11], 0);
+        assert_eq!(frame.buffer[12], 1);
+        assert_eq!(frame.blend_mask[14], 3.0);
+
+        // Buffers that are not required remain empty
+        let mut frame = FrameBuffers::<u8>::new(&layout, false, false);
+        let slices = frame.slices_mut(12);
+        assert_eq!(slices.len(), 4);
+        assert!(slices[0].blend_mask.is_empty() && slices[0].background.is_empty());
+    }
+
+    #[test]
+    fn sliding_stats_test() {
+        // 40 images of a single pixel with two channels
+        let data: Vec<u8> = (0..80_u32).map(|i| ((i * 37) % 251) as u8).collect();
+        let indices: Vec<Vec<usize>> = (0..6).map(|f| (f * 3..(f * 3 + 20)).collect()).collect();
+        let frames: Vec<Option<&[usize]>> = indices.iter().map(|i| Some(&i[..])).collect();
+        let windows = FrameWindows::new(&frames);
+
+        for threshold in &["rel/2/4", "mad/2/4"] {
+            let processor = OutlierProcessor::new(
+                threshold.parse::<Threshold>().unwrap(),
+                BackgroundMode::First,
+                OutlierSelectionMode::Extreme,
+                [1.0; 4],
+                ColorSpace::Rgb,
+                vec![],
+                None,
+                None,
+                Fade::none(),
+                None,
+            );
+            let stats = processor.sliding_stats(&data, &windows, 40, 2, 2);
+            for (frame, indices) in indices.iter().enumerate() {
+                for ch in 0..2 {
+                    let mut values: Vec<f32> =
+                        indices.iter().map(|i| data[i * 2 + ch] as f32).collect();
+                    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    let (median, scale) = OutlierProcessor::median_scale(
+                        &mut values,
+                        processor.threshold.estimator(),
+                    );
+                    assert_eq!(stats.get(frame, 0).median[ch], median);
+                    assert_eq!(stats.get(frame, 0).scale[ch], scale);
+                }
+            }
+        }
+    }
 
     #[test]
     fn quartiles_test() {
-        let values = [0, 1, 2, 3, 4, 5, 6];
+        let values = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
         println!("{:?}", OutlierProcessor::quartiles(&values));
 
         assert_eq!(OutlierProcessor::quartiles(&values), (1.0, 3.0, 5.0))
     }
+
+    #[test]
+    fn robust_scale_test() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 100.0];
+
+        let mut data = values;
+        let (med, scale) = OutlierProcessor::median_scale(&mut data, &ScaleEstimator::Mad);
+        assert_eq!(med, 4.0);
+        assert!((scale - 2.0 * 1.349 * 1.4826).abs() < 1e-4);
+
+        // Brute-force Sn and Qn
+        let n = values.len();
+        let mut inner: Vec<f32> = (0..n)
+            .map(|i| {
+                let mut d: Vec<f32> = values.iter().map(|v| (v - values[i]).abs()).collect();
+                d.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                d[n / 2]
+            })
+            .collect();
+        inner.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(OutlierProcessor::sn(&values), inner[n.div_ceil(2) - 1]);
+
+        let mut pairs = vec![];
+        for i in 0..n {
+            for j in (i + 1)..n {
+                pairs.push((values[j] - values[i]).abs());
+            }
+        }
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let h = n / 2 + 1;
+        let qn = OutlierProcessor::qn(&values);
+        assert!((qn - pairs[h * (h - 1) / 2 - 1]).abs() < 1e-4);
+    }
 }
@@ -1,10 +1,17 @@
 //! Command-line interface for chrono-photo.
 use crate::flist::FrameRange;
-use crate::options::{BackgroundMode, Fade, OutlierSelectionMode, SelectionMode, Threshold};
+use crate::mask::{Feather, MaskFilter};
+use crate::options::{
+    parse_default, BackgroundMode, CleanPlate, ColorSpace, Fade, OutlierSelectionMode,
+    ParameterSweep, SelectionMode, Sweep, Threshold, DEFAULT_BACKGROUND, DEFAULT_COLOR_SPACE,
+    DEFAULT_COMPRESSION, DEFAULT_DELTA, DEFAULT_IN_MEMORY, DEFAULT_MODE, DEFAULT_OUTLIER,
+    DEFAULT_QUALITY, DEFAULT_SLICE, DEFAULT_THRESHOLD, DEFAULT_WEIGHTS,
+};
+use crate::pipeline::Pipeline;
 use crate::shake::{ShakeAnchor, ShakeParams, ShakeReduction};
-use crate::slicer::SliceLength;
-use crate::streams::Compression;
-use core::fmt;
+use crate::slicer::{MemoryMode, SliceLength};
+use crate::streams::{Compression, DeltaEncoding};
+use crate::{Error, Result};
 use std::path::PathBuf;
 use std::str::FromStr;
 use structopt::StructOpt;
@@ -21,12 +28,9 @@ use structopt::StructOpt;
 //#[structopt(name = "chrono-photo command line application")]
 pub struct Cli {
     /// File search pattern. ** MUST be quoted on Unix systems! **
-    #[structopt(short, long)]
-    pattern: String,
+    #[structopt(short, long, required_unless_one = &["cache-list", "cache-prune", "inspect"])]
+    pattern: Option<String>,
 
-    // /// Sets to 16 bit per color channel mode. Use for 16 bit TIFF files.
-    // #[structopt(long, name = "16-bit")]
-    // is_16bit: bool,
     /// Frames to be used from those matching pattern: `start/end/step`. Optional.
     /// For default values, use `.`, e.g. `././2`.
     #[structopt(short, long, value_name = "frames")]
@@ -41,8 +45,13 @@ pub struct Cli {
     video_out: Option<FrameRange>,
 
     /// Path to output file
-    #[structopt(short, long, value_name = "path")]
-    output: String,
+    #[structopt(
+        short,
+        long,
+        value_name = "path",
+        required_unless_one = &["cache-list", "cache-prune", "inspect"]
+    )]
+    output: Option<String>,
 
     /// Temp directory. Used with `--mode outlier` only. Optional, default system temp directory.
     #[structopt(long, name = "temp-dir", value_name = "path")]
@@ -53,32 +62,45 @@ pub struct Cli {
     #[structopt(long, name = "output-blend", value_name = "path")]
     output_blend: Option<String>,
 
+    /// Path of output image showing only the background, i.e. with outliers removed.
+    /// Used with `--mode outlier` only.
+    #[structopt(long, name = "output-background", value_name = "path")]
+    output_background: Option<String>,
+
     /// Pixel selection mode (lighter|darker|outlier). Optional, default 'outlier'.
     #[structopt(short, long)]
     mode: Option<SelectionMode>,
 
-    /// Outlier threshold mode (abs|rel)/<lower>[/<upper>]. Optional, default 'abs/0.05/0.2'.
+    /// Outlier threshold mode (abs|rel|iqr|mad|sn|qn)/<lower>[/<upper>]. Optional, default 'abs/0.05/0.2'.
+    /// Multiple values, and ranges `<start>..<end>:<step>` for numbers, for parameter sweeps.
     /// Used with `--mode outlier` only.
     #[structopt(short, long, value_name = "thresh")]
-    threshold: Option<Threshold>,
+    threshold: Option<Vec<Sweep<Threshold>>>,
 
     /// Background pixel selection mode (first|random|average|median). Optional, default 'random'.
-    /// Used with `--mode outlier` only.
+    /// Multiple values for parameter sweeps. Used with `--mode outlier` only.
     #[structopt(short, long, value_name = "bg")]
-    background: Option<BackgroundMode>,
+    background: Option<Vec<Sweep<BackgroundMode>>>,
 
     /// Outlier selection mode in case more than one outlier is found
-    /// (first|last|extreme|average|forward|backward). Optional, default 'extreme'.
-    /// Used with `--mode outlier` only.
+    /// (first|last|extreme|average|forward|backward|largest). Optional, default 'extreme'.
+    /// Multiple values for parameter sweeps. Used with `--mode outlier` only.
     #[structopt(short = "l", long, value_name = "mode")]
-    outlier: Option<OutlierSelectionMode>,
+    outlier: Option<Vec<Sweep<OutlierSelectionMode>>>,
 
-    /// Compression mode and level (0 to 9) for time slices (gzip|zlib|deflate)[/<level>].
+    /// Compression mode and level for time slices (none|lz4|gzip|zlib|deflate|zstd)[/<level>].
+    /// Levels 0 to 9 for gzip, zlib and deflate, 1 to 22 for zstd.
     /// Used with `--mode outlier` only.
     /// Optional, default 'gzip/6'.
     #[structopt(short, long, value_name = "comp/lev")]
     compression: Option<Compression>,
 
+    /// Temporal delta encoding of time slices before compression (none|previous|keyframe/<interval>).
+    /// Reduces temp file size for static scenes. Used with `--mode outlier` only.
+    /// Optional, default 'none'.
+    #[structopt(long, value_name = "mode")]
+    delta: Option<DeltaEncoding>,
+
     /// Output image quality for JPG files, in percent. Optional, default '95'.
     #[structopt(short, long)]
     quality: Option<u8>,
@@ -89,6 +111,13 @@ pub struct Cli {
     #[structopt(short, long)]
     slice: Option<SliceLength>,
 
+    /// Holds time slices in memory instead of temp files (auto[/<mb>]|always|never).
+    /// With 'auto', time slices are held in memory if their estimated size fits the budget (in MB).
+    /// Used with `--mode outlier` only.
+    /// Optional, default 'auto/1024'.
+    #[structopt(long = "in-memory", value_name = "mode")]
+    in_memory: Option<MemoryMode>,
+
     /// Restricts calculation of median and inter-quartile range to a sub-sample of input images.
     /// Use for large amounts of images to speed up calculations. Optional.
     /// Used with `--mode outlier` only.
@@ -96,9 +125,31 @@ pub struct Cli {
     sample: Option<usize>,
 
     /// Color channel weights (4 values: RGBA) for distance calculation. Optional, default '1 1 1 1'.
+    /// With `--color-space`, weights refer to the channels of the selected color space (e.g. LabA).
     #[structopt(long, number_of_values = 4, value_name = "w")]
     weights: Option<Vec<f32>>,
 
+    /// Color space for outlier detection (rgb|lab|ycbcr|hsv). Optional, default 'rgb'.
+    /// Used with `--mode outlier` only.
+    #[structopt(long, name = "color-space", value_name = "space")]
+    color_space: Option<ColorSpace>,
+
+    /// Spatial filters applied to per-frame outlier masks, in the given order. Optional, default none.
+    /// Format: `(open/<r>|close/<r>|min-area/<px>|fill-holes[/<px>]) [...]`.
+    /// Used with `--mode outlier` only.
+    #[structopt(long = "mask-filter", name = "mask-filter", value_name = "filter")]
+    mask_filters: Option<Vec<MaskFilter>>,
+
+    /// Feathering of the blend mask for soft subject edges (gauss/<sigma>|guided/<radius>[/<eps>]).
+    /// Optional, default none. Used with `--mode outlier` only.
+    #[structopt(long, value_name = "feather")]
+    feather: Option<Feather>,
+
+    /// Clean plate, i.e. an image of the empty scene (<path>|frames/<start>/<end>). Optional, default none.
+    /// Used as background and as reference for outlier detection. Used with `--mode outlier` only.
+    #[structopt(long = "clean-plate", name = "clean-plate", value_name = "plate")]
+    clean_plate: Option<CleanPlate>,
+
     /// Frame fading. Optional, default None. Format: (clamp|repeat)/(abs|rel)/(f1,v1)/(f2,v2)[/(f,v)...]
     #[structopt(long)]
     fade: Option<Fade>,
@@ -112,6 +163,17 @@ pub struct Cli {
     #[structopt(long, name = "video-threads", value_name = "num")]
     video_threads: Option<usize>,
 
+    /// Number of video frames rendered in a single pass over the time slices. Optional, default as many frames as
+    /// fit into 1 GB of output buffers. Used with `--mode outlier` only.
+    #[structopt(long, name = "video-batch", value_name = "frames")]
+    video_batch: Option<usize>,
+
+    /// Memory budget in MB. Chooses slicing, in-memory time slices, threads and video batch size to fit the budget.
+    /// Given `--threads`, `--video-threads` and `--video-batch` are used as upper limits.
+    /// Optional, default none. Used with `--mode outlier` only.
+    #[structopt(long = "max-memory", name = "max-memory", value_name = "mb")]
+    max_memory: Option<usize>,
+
     /// Number of threads for camera shake reduction. Optional, default equal to number of processors.
     /// Limiting this may be required if memory usage is too high.
     #[structopt(long, name = "shake-threads", value_name = "num")]
@@ -126,6 +188,28 @@ pub struct Cli {
     #[structopt(long, name = "shake-anchors", value_name = "x/y")]
     shake_anchors: Option<Vec<ShakeAnchor>>,
 
+    /// Keeps time slices in a persistent cache in the temp directory, and re-uses them for identical inputs.
+    /// Used with `--mode outlier` only.
+    #[structopt(long)]
+    cache: bool,
+
+    /// Lists cached time slice sets and exits.
+    #[structopt(long = "cache-list", name = "cache-list")]
+    cache_list: bool,
+
+    /// Deletes cached time slice sets not used for the given number of days (0 for all) and exits.
+    #[structopt(long = "cache-prune", name = "cache-prune", value_name = "days")]
+    cache_prune: Option<u64>,
+
+    /// Prints the headers of the given time slice files, verifies their chunks and exits.
+    #[structopt(long, value_name = "path")]
+    inspect: Option<Vec<PathBuf>>,
+
+    /// Reports input images, time slices, estimated memory and output files, without processing, and exits.
+    /// Reads only image headers and writes no files.
+    #[structopt(long = "dry-run", name = "dry-run")]
+    dry_run: bool,
+
     /// Prints debug information (i.e. parsed cmd parameters) before processing.
     #[structopt(long, short)]
     debug: bool,
@@ -137,12 +221,15 @@ pub struct Cli {
 
 impl Cli {
     /// Parses this Cli into a [CliParsed](struct.CliParsed.html).
-    pub fn parse(self) -> Result<CliParsed, ParseCliError> {
+    pub fn parse(self) -> Result<CliParsed> {
         let mut warings = Vec::new();
         if self.mode.is_some() && self.mode.as_ref().unwrap() != &SelectionMode::Outlier {
             if self.output_blend.is_some() {
                 warings.push("--output-blend".to_string());
             }
+            if self.output_background.is_some() {
+                warings.push("--output-background".to_string());
+            }
             if self.threshold.is_some() {
                 warings.push("--threshold".to_string());
             }
@@ -164,68 +251,104 @@ impl Cli {
             if self.compression.is_some() {
                 warings.push("--compression".to_string());
             }
+            if self.delta.is_some() {
+                warings.push("--delta".to_string());
+            }
+            if self.in_memory.is_some() {
+                warings.push("--in-memory".to_string());
+            }
+            if self.video_batch.is_some() {
+                warings.push("--video-batch".to_string());
+            }
+            if self.max_memory.is_some() {
+                warings.push("--max-memory".to_string());
+            }
+            if self.cache {
+                warings.push("--cache".to_string());
+            }
+            if self.color_space.is_some() {
+                warings.push("--color-space".to_string());
+            }
+            if self.mask_filters.is_some() {
+                warings.push("--mask-filter".to_string());
+            }
+            if self.feather.is_some() {
+                warings.push("--feather".to_string());
+            }
+            if self.clean_plate.is_some() {
+                warings.push("--clean-plate".to_string());
+            }
         }
+        let mut problems = vec![];
         if self.shake.is_some() != self.shake_anchors.is_some() {
-            return Err(ParseCliError(
-                "Provide both options or none: `--shake` and `--shake-anchors`".to_string(),
-            ));
+            problems.push("`--shake`, `--shake-anchors`: provide both options or none".to_string());
         }
 
-        let mut weights = [1.0; 4];
+        let mut weights = DEFAULT_WEIGHTS;
         if let Some(w) = &self.weights {
             for (i, v) in w.iter().enumerate() {
                 weights[i] = *v;
             }
         }
 
+        let thresholds = sweep_values(self.threshold, DEFAULT_THRESHOLD);
+        let outliers = sweep_values(self.outlier, DEFAULT_OUTLIER);
+        let backgrounds = sweep_values(self.background, DEFAULT_BACKGROUND);
+        let sweep = ParameterSweep::new(thresholds.clone(), outliers.clone(), backgrounds.clone());
+
         let shake_params = self.shake;
         let shake_anchors = self.shake_anchors;
         let out = CliParsed {
-            pattern: self.pattern,
-            // is_16bit: self.is_16bit,
-            temp_dir: self.temp_dir.map(|d| PathBuf::from(d)),
-            output: PathBuf::from(&self.output),
-            output_blend: match self.output_blend {
-                Some(out) => Some(PathBuf::from(out)),
-                None => None,
-            },
-            mode: self.mode.unwrap_or(SelectionMode::Outlier),
-            threshold: self.threshold.unwrap_or(Threshold::abs(0.05, 0.2)),
-            background: self.background.unwrap_or(BackgroundMode::Random),
-            outlier: self.outlier.unwrap_or(OutlierSelectionMode::Extreme),
-            compression: self.compression.unwrap_or(Compression::GZip(6)),
-            quality: match self.quality {
-                Some(q) => {
-                    if q <= 100 && q > 0 {
-                        q
-                    } else {
-                        return Err(ParseCliError(format!(
-                            "Expected 0 < qualtiy <= 100. Got value {}",
-                            q
-                        )));
-                    }
-                }
-                None => 95,
-            },
+            pattern: self.pattern.unwrap_or_default(),
+            temp_dir: self.temp_dir.map(PathBuf::from),
+            output: PathBuf::from(self.output.unwrap_or_default()),
+            output_blend: self.output_blend.map(PathBuf::from),
+            output_background: self.output_background.map(PathBuf::from),
+            mode: self.mode.unwrap_or_else(|| parse_default(DEFAULT_MODE)),
+            threshold: thresholds[0].1.clone(),
+            background: backgrounds[0].1.clone(),
+            outlier: outliers[0].1.clone(),
+            sweep: if sweep.len() > 1 { Some(sweep) } else { None },
+            compression: self
+                .compression
+                .unwrap_or_else(|| parse_default(DEFAULT_COMPRESSION)),
+            delta: self.delta.unwrap_or_else(|| parse_default(DEFAULT_DELTA)),
+            quality: self.quality.unwrap_or(DEFAULT_QUALITY),
             frames: self.frames,
             video_in: self.video_in,
             video_out: self.video_out,
-            slice: self.slice.unwrap_or(SliceLength::Rows(4)),
+            slice: self.slice.unwrap_or_else(|| parse_default(DEFAULT_SLICE)),
+            in_memory: self
+                .in_memory
+                .unwrap_or_else(|| parse_default(DEFAULT_IN_MEMORY)),
             sample: self.sample,
             weights,
-            fade: self.fade.unwrap_or(Fade::none()),
+            color_space: self
+                .color_space
+                .unwrap_or_else(|| parse_default(DEFAULT_COLOR_SPACE)),
+            mask_filters: self.mask_filters.unwrap_or_default(),
+            feather: self.feather,
+            clean_plate: self.clean_plate,
+            fade: self.fade.unwrap_or_else(Fade::none),
             threads: self.threads,
             video_threads: self.video_threads,
+            video_batch: self.video_batch,
+            max_memory: self.max_memory,
             shake_threads: self.shake_threads,
             shake_reduction: shake_params.and_then(|shake| {
-                shake_anchors.and_then(|anchors| {
-                    Some(ShakeReduction::new(
+                shake_anchors.map(|anchors| {
+                    ShakeReduction::new(
                         anchors.iter().map(|a| a.anchor()).collect(),
                         shake.anchor_radius(),
                         shake.search_radius(),
-                    ))
+                    )
                 })
             }),
+            cache: self.cache,
+            cache_list: self.cache_list,
+            cache_prune: self.cache_prune,
+            inspect: self.inspect.unwrap_or_default(),
+            dry_run: self.dry_run,
             debug: self.debug,
             wait: self.wait,
         };
@@ -238,15 +361,17 @@ impl Cli {
             println!();
         }
 
-        out.validate()
+        problems.extend(out.problems());
+        invalid_options(problems)?;
+        Ok(out)
     }
 }
 
 impl FromStr for Cli {
-    type Err = ParseCliError;
+    type Err = Error;
 
     /// Parses a string into a Cli.
-    fn from_str(str: &str) -> Result<Self, Self::Err> {
+    fn from_str(str: &str) -> Result<Self> {
         let quote_parts: Vec<_> = str.split('"').collect();
         let mut args: Vec<String> = vec![];
         for (i, part) in quote_parts.iter().enumerate() {
@@ -261,7 +386,8 @@ impl FromStr for Cli {
                 args.push(part.to_string());
             }
         }
-        Ok(Cli::from_iter(args.iter()))
+        Cli::from_iter_safe(args.iter())
+            .map_err(|err| Error::Parse(err.message.trim_start_matches("error: ").to_string()))
     }
 }
 
@@ -271,8 +397,6 @@ impl FromStr for Cli {
 pub struct CliParsed {
     /// File search pattern
     pub pattern: String,
-    // /// Sets to 16 bit per color channel mode. Use for 16 bit TIFF files.
-    //pub is_16bit: bool,
     /// Frames to be used from those matching pattern: `start/end/step`. Optional.
     /// For default values, use `.`, e.g. `././step`.
     pub frames: Option<FrameRange>,
@@ -286,6 +410,8 @@ pub struct CliParsed {
     pub output: PathBuf,
     /// Path of output image showing which pixels are outliers (blend value).
     pub output_blend: Option<PathBuf>,
+    /// Path of output image showing only the background.
+    pub output_background: Option<PathBuf>,
     /// Pixel selection mode.
     pub mode: SelectionMode,
     /// Outlier threshold mode.
@@ -294,26 +420,54 @@ pub struct CliParsed {
     pub outlier: OutlierSelectionMode,
     /// Background pixel selection mode.
     pub background: BackgroundMode,
+    /// Parameter sweep over threshold, outlier and background modes. `None` for a single combination.
+    pub sweep: Option<ParameterSweep>,
     /// Compression mode for time slices.
     pub compression: Compression,
+    /// Temporal delta encoding of time slices.
+    pub delta: DeltaEncoding,
     /// Output image quality for JPG files, in percent.
     pub quality: u8,
-    /// Controls slicing to temp files (rows|pixels|count)/<number>. Optional, default 'rows/1'
+    /// Controls slicing to temp files (rows|pixels|count)/<number>. Optional, default 'rows/4'
     pub slice: SliceLength,
+    /// Holds time slices in memory instead of temp files.
+    pub in_memory: MemoryMode,
     /// Restricts calculation of median and inter-quartile range to a sub-sample of input images. Use for large amounts of images to speed up calculations. Optional.
     pub sample: Option<usize>,
     /// Color channel weights for distance calculation
     pub weights: [f32; 4],
+    /// Color space for outlier detection.
+    pub color_space: ColorSpace,
+    /// Spatial filters for per-frame outlier masks.
+    pub mask_filters: Vec<MaskFilter>,
+    /// Feathering of the blend mask.
+    pub feather: Option<Feather>,
+    /// Clean plate for background and outlier detection.
+    pub clean_plate: Option<CleanPlate>,
     /// Frame fading. Optional, default None.
     pub fade: Fade,
     /// Number of threads. Optional, default equal to number of processors.
     pub threads: Option<usize>,
     /// Number of threads for parallel video frame output. Optional, default equal to number of processors.
     pub video_threads: Option<usize>,
+    /// Number of video frames rendered in a single pass. Optional, default from output buffer memory.
+    pub video_batch: Option<usize>,
+    /// Memory budget in MB for planning slicing, threads and video batch size. Optional.
+    pub max_memory: Option<usize>,
     /// Number of threads for camera shake reduction. Optional, default equal to number of processors.
     pub shake_threads: Option<usize>,
     /// Shake reduction
     pub shake_reduction: Option<ShakeReduction>,
+    /// Keep time slices in a persistent cache.
+    pub cache: bool,
+    /// List cached time slice sets and exit.
+    pub cache_list: bool,
+    /// Delete cached time slice sets not used for the given number of days and exit.
+    pub cache_prune: Option<u64>,
+    /// Time slice files to inspect.
+    pub inspect: Vec<PathBuf>,
+    /// Report what would be done and exit, without processing.
+    pub dry_run: bool,
     /// Print debug information (i.e. parsed cmd parameters).
     pub debug: bool,
 
@@ -321,26 +475,137 @@ pub struct CliParsed {
     pub wait: bool,
 }
 
+/// Flattens the values of an option for parameter sweeps, or parses the default value given in command line format.
+fn sweep_values<T: FromStr>(values: Option<Vec<Sweep<T>>>, default: &str) -> Vec<(String, T)> {
+    match values {
+        Some(values) => values.into_iter().flat_map(|v| v.into_values()).collect(),
+        None => vec![(default.to_string(), parse_default(default))],
+    }
+}
+
 impl CliParsed {
-    /// Check for validity
-    pub fn validate(self) -> Result<Self, ParseCliError> {
-        Ok(self)
+    /// Is this a command (`--inspect`, `--cache-list` or `--cache-prune`) rather than image processing?
+    pub fn is_command(&self) -> bool {
+        !self.inspect.is_empty() || self.cache_list || self.cache_prune.is_some()
+    }
+
+    /// Lists all problems with the options, independent of the input images.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.quality == 0 || self.quality > 100 {
+            problems.push(format!(
+                "`--quality`: expects 0 < quality <= 100, got {}",
+                self.quality
+            ));
+        }
+        let ranges = [
+            ("--frames", &self.frames),
+            ("--video-in", &self.video_in),
+            ("--video-out", &self.video_out),
+        ];
+        for (option, range) in ranges.iter() {
+            if let Some(range) = range {
+                if range.step() == 0 {
+                    problems.push(format!("`{}`: step must be at least 1", option));
+                }
+            }
+        }
+        if self.weights.iter().all(|w| *w == 0.0) {
+            problems.push("`--weights`: at least one weight must be non-zero".to_string());
+        }
+
+        if self.mode == SelectionMode::Outlier {
+            let thresholds = match &self.sweep {
+                Some(sweep) => sweep.thresholds().iter().map(|(_, t)| t).collect(),
+                None => vec![&self.threshold],
+            };
+            for threshold in thresholds {
+                if threshold.min() > threshold.max() {
+                    let scale = if threshold.absolute() { 255.0 } else { 1.0 };
+                    problems.push(format!(
+                        "`--threshold`: lower threshold {} is greater than upper threshold {}",
+                        threshold.min() / scale,
+                        threshold.max() / scale
+                    ));
+                }
+            }
+            let slice_len = match self.slice {
+                SliceLength::Rows(n) | SliceLength::Pixels(n) | SliceLength::Count(n) => n,
+            };
+            if slice_len == 0 {
+                problems.push(format!(
+                    "`--slice`: expects a number > 0, got {}",
+                    self.slice
+                ));
+            }
+            let counts = [
+                ("--sample", self.sample),
+                ("--video-batch", self.video_batch),
+                ("--max-memory", self.max_memory),
+            ];
+            for (option, count) in counts.iter() {
+                if *count == Some(0) {
+                    problems.push(format!("`{}`: expects a number > 0, got 0", option));
+                }
+            }
+        }
+        problems
     }
 }
 
-/// Error type for failed parsing command line argument.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ParseCliError(String);
+/// Creates a pipeline for image processing from the parsed arguments.
+impl From<&CliParsed> for Pipeline {
+    fn from(args: &CliParsed) -> Self {
+        let mut pipeline = Pipeline::new(&args.pattern, &args.output)
+            .video(args.video_in.clone(), args.video_out.clone());
+        pipeline.frames = args.frames.clone();
+        pipeline.mode = args.mode.clone();
+        pipeline.threshold = args.threshold.clone();
+        pipeline.outlier = args.outlier.clone();
+        pipeline.background = args.background.clone();
+        pipeline.sweep = args.sweep.clone();
+        pipeline.weights = args.weights;
+        pipeline.color_space = args.color_space.clone();
+        pipeline.mask_filters = args.mask_filters.clone();
+        pipeline.feather = args.feather.clone();
+        pipeline.clean_plate = args.clean_plate.clone();
+        pipeline.fade = args.fade.clone();
+        pipeline.sample = args.sample;
+        pipeline.slice = args.slice.clone();
+        pipeline.compression = args.compression.clone();
+        pipeline.delta = args.delta.clone();
+        pipeline.in_memory = args.in_memory.clone();
+        pipeline.cache = args.cache;
+        pipeline.temp_dir = args.temp_dir.clone();
+        pipeline.video_threads = args.video_threads;
+        pipeline.video_batch = args.video_batch;
+        pipeline.shake_reduction = args.shake_reduction.clone();
+        pipeline.shake_threads = args.shake_threads;
+        pipeline.output_blend = args.output_blend.clone();
+        pipeline.output_background = args.output_background.clone();
+        pipeline.quality = args.quality;
+        pipeline.verbose = true;
+        pipeline
+    }
+}
 
-impl fmt::Display for ParseCliError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+/// Reports all problems with the options in a single configuration error.
+fn invalid_options(problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "Invalid options:\n  {}",
+            problems.join("\n  ")
+        )))
     }
 }
 
 #[cfg(test)]
 mod test {
     use crate::cli::Cli;
+    use crate::pipeline::Pipeline;
+    use std::str::FromStr;
 
     #[test]
     fn args_from_string() {
@@ -350,4 +615,20 @@ mod test {
 
         //println!("{:#?}", parsed);
     }
+
+    #[test]
+    fn validate_test() {
+        let str = "chrono-photo --pattern \"test_data/generated/*.jpg\" --output test_data/temp --threshold abs/0.3/0.1 --weights 0 0 0 0 --slice rows/0";
+        let cli: Cli = str.parse().unwrap();
+        let message = cli.parse().unwrap_err().to_string();
+        for option in &["--threshold", "--weights", "--slice"] {
+            assert!(message.contains(&format!("`{}`", option)), "{}", message);
+        }
+
+        let str = "chrono-photo --pattern \"*.jpg\" --output out.png --shake 5/5 --shake-anchors 10/10 50/50";
+        let parsed = Cli::from_str(str).unwrap().parse().unwrap();
+        let pipeline = Pipeline::from(&parsed);
+        assert!(pipeline.validate_image(100, 100, 3, 10).is_ok());
+        assert!(pipeline.validate_image(100, 59, 3, 10).is_err());
+    }
 }
@@ -1,16 +1,150 @@
 //! Helper functions for colors
+use byteorder::{BigEndian, ByteOrder};
+use image::flat::FlatSamples;
+use image::{ColorType, DynamicImage};
+use num_traits::PrimInt;
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::io::Read;
+
+/// Color channel sample type, i.e. `u8` for 8 bit and `u16` for 16 bit per channel images.
+pub trait Sample: PrimInt + Default + Debug + Send + Sync + 'static {
+    /// Maximum value of a sample, as float.
+    const MAX_VALUE: f32;
+    /// Number of bytes per sample.
+    const BYTES: usize;
+
+    /// Converts the sample to float.
+    fn as_f32(self) -> f32;
+    /// Converts a float [0, MAX_VALUE] to a sample, with rounding and clamping.
+    fn from_f32(value: f32) -> Self;
+    /// Converts the sample to 16 bits.
+    fn as_u16(self) -> u16;
+    /// Converts the sample to 8 bits, with rounding.
+    fn as_u8(self) -> u8;
+    /// Returns a view on the image's samples, if the image has the sample type.
+    fn flat_samples(image: &DynamicImage) -> Option<FlatSamples<&[Self]>>;
+    /// Image color type for the given number of channels.
+    fn color_type(channels: usize) -> ColorType;
+    /// Converts samples to (big endian) bytes, for writing to time slices.
+    fn to_bytes(samples: &[Self]) -> Cow<'_, [u8]>;
+    /// Converts samples to native endian bytes, as expected by image encoders.
+    fn to_native_bytes(samples: &[Self]) -> Cow<'_, [u8]>;
+    /// Converts (big endian) bytes to samples, and appends them. Returns the number of samples converted.
+    fn from_bytes(bytes: &[u8], out: &mut Vec<Self>) -> usize;
+    /// Reads all (big endian) bytes from the reader, and appends them as samples.
+    /// `buffer` holds the bytes if a conversion is required, and is re-used to avoid allocations.
+    /// Returns the number of samples read.
+    fn read_samples<R: Read>(
+        reader: &mut R,
+        out: &mut Vec<Self>,
+        buffer: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        buffer.clear();
+        reader.read_to_end(buffer)?;
+        Ok(Self::from_bytes(buffer, out))
+    }
+}
+
+impl Sample for u8 {
+    const MAX_VALUE: f32 = 255.0;
+    const BYTES: usize = 1;
+
+    fn as_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0.0, Self::MAX_VALUE) as u8
+    }
+    fn as_u16(self) -> u16 {
+        self as u16
+    }
+    fn as_u8(self) -> u8 {
+        self
+    }
+    fn flat_samples(image: &DynamicImage) -> Option<FlatSamples<&[Self]>> {
+        image.as_flat_samples_u8()
+    }
+    fn color_type(channels: usize) -> ColorType {
+        match channels {
+            1 => ColorType::L8,
+            2 => ColorType::La8,
+            4 => ColorType::Rgba8,
+            _ => ColorType::Rgb8,
+        }
+    }
+    fn to_bytes(samples: &[Self]) -> Cow<'_, [u8]> {
+        Cow::Borrowed(samples)
+    }
+    fn to_native_bytes(samples: &[Self]) -> Cow<'_, [u8]> {
+        Cow::Borrowed(samples)
+    }
+    fn from_bytes(bytes: &[u8], out: &mut Vec<Self>) -> usize {
+        out.extend_from_slice(bytes);
+        bytes.len()
+    }
+    fn read_samples<R: Read>(
+        reader: &mut R,
+        out: &mut Vec<Self>,
+        _buffer: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        reader.read_to_end(out)
+    }
+}
+
+impl Sample for u16 {
+    const MAX_VALUE: f32 = 65535.0;
+    const BYTES: usize = 2;
+
+    fn as_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0.0, Self::MAX_VALUE) as u16
+    }
+    fn as_u16(self) -> u16 {
+        self
+    }
+    fn as_u8(self) -> u8 {
+        ((self as u32 * 255 + 32767) / 65535) as u8
+    }
+    fn flat_samples(image: &DynamicImage) -> Option<FlatSamples<&[Self]>> {
+        image.as_flat_samples_u16()
+    }
+    fn color_type(channels: usize) -> ColorType {
+        match channels {
+            1 => ColorType::L16,
+            2 => ColorType::La16,
+            4 => ColorType::Rgba16,
+            _ => ColorType::Rgb16,
+        }
+    }
+    fn to_bytes(samples: &[Self]) -> Cow<'_, [u8]> {
+        let mut bytes = vec![0; samples.len() * Self::BYTES];
+        BigEndian::write_u16_into(samples, &mut bytes);
+        Cow::Owned(bytes)
+    }
+    fn to_native_bytes(samples: &[Self]) -> Cow<'_, [u8]> {
+        Cow::Owned(samples.iter().flat_map(|s| s.to_ne_bytes()).collect())
+    }
+    fn from_bytes(bytes: &[u8], out: &mut Vec<Self>) -> usize {
+        let len = bytes.len() / Self::BYTES;
+        let start = out.len();
+        out.resize(start + len, 0);
+        BigEndian::read_u16_into(&bytes[..(len * Self::BYTES)], &mut out[start..]);
+        len
+    }
+}
 
 /// Blends color b into color a.
-pub fn blend_into_u8(a: &mut [u8], b: &[u8], blend: f32) {
+pub fn blend_into<T: Sample>(a: &mut [T], b: &[T], blend: f32) {
     if blend <= 0.0 {
     } else if blend >= 1.0 {
-        for (i, b) in b.iter().enumerate() {
-            a[i] = *b;
-        }
+        a[..b.len()].copy_from_slice(b);
     } else {
         for (a, b) in a.iter_mut().zip(b) {
-            let aa = *a;
-            *a = (aa as f32 + (*b as f32 - aa as f32) * blend).round() as u8;
+            let aa = a.as_f32();
+            *a = T::from_f32(aa + (b.as_f32() - aa) * blend);
         }
     }
 }
@@ -28,39 +162,114 @@ pub fn blend_into_f32(a: &mut [f32], b: &[f32], blend: f32) {
         }
     }
 }
-/// Blends float color [0, 255](!) b into color a.
-pub fn blend_into_f32_u8(a: &mut [f32], b: &[u8], blend: f32) {
+/// Blends color b into float color a (same value range as b).
+pub fn blend_into_f32_sample<T: Sample>(a: &mut [f32], b: &[T], blend: f32) {
     if blend <= 0.0 {
     } else if blend >= 1.0 {
-        for (i, b) in b.iter().enumerate() {
-            a[i] = *b as f32;
+        for (a, b) in a.iter_mut().zip(b) {
+            *a = b.as_f32();
         }
     } else {
         for (a, b) in a.iter_mut().zip(b) {
             let aa = *a;
-            *a = aa + (*b as f32 - aa) * blend;
+            *a = aa + (b.as_f32() - aa) * blend;
         }
     }
 }
 
+/// Converts sRGB [0, 255] to CIELAB (D65). L is in [0, 100], a and b are approx. in [-128, 127].
+pub fn rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let linear = |v: f32| {
+        let v = v / 255.0;
+        if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let lin = [linear(rgb[0]), linear(rgb[1]), linear(rgb[2])];
+    let x = (0.412_456_4 * lin[0] + 0.357_576_1 * lin[1] + 0.180_437_5 * lin[2]) / 0.950_47;
+    let y = 0.212_672_9 * lin[0] + 0.715_152_2 * lin[1] + 0.072_175 * lin[2];
+    let z = (0.019_333_9 * lin[0] + 0.119_192 * lin[1] + 0.950_304_1 * lin[2]) / 1.088_83;
+
+    let f = |t: f32| {
+        if t > 0.008_856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+/// Converts RGB [0, 255] to full-range YCbCr (ITU-R BT.601), all channels in [0, 255].
+pub fn rgb_to_ycbcr(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb;
+    [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        128.0 - 0.168_736 * r - 0.331_264 * g + 0.5 * b,
+        128.0 + 0.5 * r - 0.418_688 * g - 0.081_312 * b,
+    ]
+}
+/// Converts RGB [0, 255] to HSV, all channels in [0, 255]. Hue is circular.
+pub fn rgb_to_hsv(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb;
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    let sat = if max == 0.0 { 0.0 } else { 255.0 * delta / max };
+    [hue * 255.0 / 6.0, sat, max]
+}
+
 #[cfg(test)]
 mod test {
-    /*
-    use crate::color::{blend_into_u8, blend_u8};
+    use crate::color::{blend_into, rgb_to_hsv, rgb_to_lab, Sample};
+
     #[test]
     fn blend_test() {
-        let a = [0; 4];
-        let b = [255; 4];
-        let mut c = [0; 4];
+        let mut a = [0_u16; 4];
+        let b = [65535_u16; 4];
+
+        blend_into(&mut a, &b, 0.5);
+        assert_eq!(a, [32768; 4]);
 
-        blend_u8(&a, &b, 0.0, &mut c);
-        assert_eq!(c, [0; 4]);
+        blend_into(&mut a, &b, 1.0);
+        assert_eq!(a, [65535; 4]);
+    }
+
+    #[test]
+    fn sample_bytes_test() {
+        let samples = [0_u16, 1, 256, 65535];
+        let bytes = u16::to_bytes(&samples);
+        let mut out = Vec::new();
+        let mut buffer = Vec::new();
+        let len = u16::read_samples(&mut &bytes[..], &mut out, &mut buffer).unwrap();
+        assert_eq!(len, 4);
+        assert_eq!(&out[..], &samples[..]);
+        assert_eq!(u16::from_bytes(&bytes, &mut out), 4);
+        assert_eq!(&out[4..], &samples[..]);
+        assert_eq!(65535_u16.as_u8(), 255);
+    }
+
+    #[test]
+    fn color_space_test() {
+        let lab = rgb_to_lab([255.0, 255.0, 255.0]);
+        assert!((lab[0] - 100.0).abs() < 0.01);
+        assert!(lab[1].abs() < 0.01 && lab[2].abs() < 0.01);
 
-        blend_u8(&a, &b, 0.5, &mut c);
-        assert_eq!(c, [128; 4]);
+        let lab = rgb_to_lab([255.0, 0.0, 0.0]);
+        assert!((lab[0] - 53.24).abs() < 0.1);
+        assert!((lab[1] - 80.09).abs() < 0.1);
 
-        blend_u8(&a, &b, 1.0, &mut c);
-        assert_eq!(c, [255; 4]);
+        assert_eq!(rgb_to_hsv([0.0, 255.0, 0.0]), [85.0, 255.0, 255.0]);
     }
-    */
 }
@@ -1,7 +1,7 @@
 //! Lists files by pattern
 extern crate glob;
 
-use crate::ParseOptionError;
+use crate::{ParseOptionError, Result};
 use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -40,7 +40,7 @@ impl FrameRange {
     }
     /// The total number of frames, irrespective of step
     pub fn range(&self) -> Option<i32> {
-        self.start.and_then(|s| self.end.and_then(|e| Some(e - s)))
+        self.start.and_then(|s| self.end.map(|e| e - s))
     }
 }
 impl FromStr for FrameRange {
@@ -54,7 +54,7 @@ impl FromStr for FrameRange {
                 parts.len()
             )));
         }
-        let mut values = vec![None; 3];
+        let mut values = [None; 3];
         for (i, s) in parts.iter().enumerate() {
             if *s == "." {
                 values[i] = None;
@@ -94,7 +94,7 @@ impl FileLister {
         }
     }
     /// Lists all files that match this lister's pattern.
-    pub fn files_vecdeque<'a>(&self) -> Result<VecDeque<PathBuf>, glob::PatternError> {
+    pub fn files_vecdeque(&self) -> Result<VecDeque<PathBuf>> {
         // TODO Return an iterator instead of a vector. Having problems with "size not known at compile time".
         let paths: glob::Paths = glob::glob(&self.pattern)?;
         let vec = paths
@@ -102,7 +102,7 @@ impl FileLister {
             .map(|p| p.unwrap());
         match &self.frames {
             Some(fr) => Ok(vec
-                .take(fr.end.unwrap_or(std::i32::MAX) as usize)
+                .take(fr.end.unwrap_or(i32::MAX) as usize)
                 .skip(fr.start.unwrap_or(0) as usize)
                 .enumerate()
                 .filter_map(|(i, p)| {
@@ -118,7 +118,7 @@ impl FileLister {
     }
 
     /// Lists all files that match this lister's pattern.
-    pub fn files_vec<'a>(&self) -> Result<Vec<PathBuf>, glob::PatternError> {
+    pub fn files_vec(&self) -> Result<Vec<PathBuf>> {
         // TODO Return an iterator instead of a vector. Having problems with "size not known at compile time".
         let paths: glob::Paths = glob::glob(&self.pattern)?;
         let vec = paths
@@ -126,7 +126,7 @@ impl FileLister {
             .map(|p| p.unwrap());
         match &self.frames {
             Some(fr) => Ok(vec
-                .take(fr.end.unwrap_or(std::i32::MAX) as usize)
+                .take(fr.end.unwrap_or(i32::MAX) as usize)
                 .skip(fr.start.unwrap_or(0) as usize)
                 .enumerate()
                 .filter_map(|(i, p)| {
@@ -149,7 +149,7 @@ mod test {
     #[test]
     fn parse_pattern() {
         let pattern = "test_data/*.txt";
-        let lister = FileLister::new(&pattern, &None);
+        let lister = FileLister::new(pattern, &None);
 
         let _list = lister.files_vecdeque().expect("Error processing pattern");
     }
@@ -0,0 +1,186 @@
+//! Counting histograms of 8 bit values, for exact order statistics without sorting.
+
+/// Number of bins per block of a [`Histogram`](struct.Histogram.html).
+const BLOCK_SIZE: usize = 16;
+/// Number of blocks of a [`Histogram`](struct.Histogram.html).
+const BLOCKS: usize = 256 / BLOCK_SIZE;
+
+/// Counting histogram of 8 bit values, for exact order statistics (median, quantiles, MAD).
+/// Values can be added and removed, e.g. for sliding windows.
+///
+/// Bins are grouped into blocks with summed counts, so that the k-th smallest value
+/// is found in at most 32 steps, independent of the number of values.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: [u32; 256],
+    blocks: [u32; BLOCKS],
+    len: usize,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    /// Creates an empty histogram.
+    pub fn new() -> Self {
+        Histogram {
+            counts: [0; 256],
+            blocks: [0; BLOCKS],
+            len: 0,
+        }
+    }
+    /// Removes all values.
+    pub fn clear(&mut self) {
+        self.counts = [0; 256];
+        self.blocks = [0; BLOCKS];
+        self.len = 0;
+    }
+    /// The number of values.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// Checks if there are no values.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Adds a value.
+    pub fn add(&mut self, value: u8) {
+        self.counts[value as usize] += 1;
+        self.blocks[value as usize / BLOCK_SIZE] += 1;
+        self.len += 1;
+    }
+    /// Removes a value. The value must have been added before.
+    pub fn remove(&mut self, value: u8) {
+        self.counts[value as usize] -= 1;
+        self.blocks[value as usize / BLOCK_SIZE] -= 1;
+        self.len -= 1;
+    }
+
+    /// The k-th (zero-based) smallest value.
+    pub fn kth(&self, k: usize) -> f32 {
+        let mut remaining = k as u32;
+        let mut block = 0;
+        while remaining >= self.blocks[block] {
+            remaining -= self.blocks[block];
+            block += 1;
+        }
+        let mut bin = block * BLOCK_SIZE;
+        while remaining >= self.counts[bin] {
+            remaining -= self.counts[bin];
+            bin += 1;
+        }
+        bin as f32
+    }
+    /// The k-th (zero-based) smallest absolute deviation of the values from `center`.
+    fn kth_deviation(&self, center: f32, k: usize) -> f32 {
+        let mut remaining = k as u32;
+        // Walk outwards from the center, always taking the closer bin
+        let mut lower = center.floor() as i32;
+        let mut upper = lower + 1;
+        loop {
+            let dist_lower = if lower >= 0 {
+                center - lower as f32
+            } else {
+                f32::INFINITY
+            };
+            let dist_upper = if upper < 256 {
+                upper as f32 - center
+            } else {
+                f32::INFINITY
+            };
+            let (bin, dist) = if dist_lower <= dist_upper {
+                lower -= 1;
+                (lower + 1, dist_lower)
+            } else {
+                upper += 1;
+                (upper - 1, dist_upper)
+            };
+            let count = self.counts[bin as usize];
+            if remaining < count {
+                return dist;
+            }
+            remaining -= count;
+        }
+    }
+
+    /// The median of the values. Identical to the median of the sorted values.
+    pub fn median(&self) -> f32 {
+        median_by(self.len, |k| self.kth(k))
+    }
+    /// A quantile of the values. Identical to the quantile of the sorted values.
+    pub fn quantile(&self, q: f32) -> f32 {
+        quantile_by(self.len, q, |k| self.kth(k))
+    }
+    /// Quartiles of the values.
+    /// Return (Q1, Median, Q3)
+    pub fn quartiles(&self) -> (f32, f32, f32) {
+        (self.quantile(0.25), self.median(), self.quantile(0.75))
+    }
+    /// The (unnormalized) median absolute deviation of the values from the given median.
+    pub fn mad(&self, median: f32) -> f32 {
+        median_by(self.len, |k| self.kth_deviation(median, k))
+    }
+}
+
+/// Calculates a quantile of a sorted sample of length `len`, given by an accessor function.
+pub fn quantile_by<F: Fn(usize) -> f32>(len: usize, q: f32, value: F) -> f32 {
+    let pos = (len + 1) as f32 * q;
+    let p1 = pos as usize - 1;
+    let frac = pos.fract();
+    if frac < 0.001 {
+        value(p1)
+    } else if frac > 0.999 {
+        value(p1 + 1)
+    } else {
+        (1.0 - frac) * value(p1) + frac * value(p1 + 1)
+    }
+}
+
+/// Calculates the median of a sorted sample of length `len`, given by an accessor function.
+pub fn median_by<F: Fn(usize) -> f32>(len: usize, value: F) -> f32 {
+    if len % 2 == 1 {
+        value(len / 2)
+    } else {
+        let idx = len / 2;
+        0.5 * (value(idx - 1) + value(idx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::histogram::{median_by, quantile_by, Histogram};
+
+    #[test]
+    fn histogram_test() {
+        let values: Vec<u8> = vec![7, 200, 3, 3, 255, 0, 17, 99, 99, 100, 42, 3, 180];
+        let mut histogram = Histogram::new();
+        for v in &values {
+            histogram.add(*v);
+        }
+        histogram.add(77);
+        histogram.remove(77);
+
+        let mut sorted: Vec<f32> = values.iter().map(|v| *v as f32).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (k, v) in sorted.iter().enumerate() {
+            assert_eq!(histogram.kth(k), *v);
+        }
+        let median = median_by(sorted.len(), |k| sorted[k]);
+        assert_eq!(histogram.median(), median);
+        for q in &[0.25, 0.4, 0.75] {
+            assert_eq!(
+                histogram.quantile(*q),
+                quantile_by(sorted.len(), *q, |k| sorted[k])
+            );
+        }
+
+        for center in &[median, 99.5] {
+            let mut dev: Vec<f32> = sorted.iter().map(|v| (v - center).abs()).collect();
+            dev.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(histogram.mad(*center), median_by(dev.len(), |k| dev[k]));
+        }
+    }
+}
@@ -1,14 +1,105 @@
+pub mod cache;
 pub mod chrono;
 pub mod cli;
 pub mod color;
 pub mod flist;
+pub mod histogram;
+pub mod mask;
+pub mod memory;
 pub mod options;
+pub mod pipeline;
 pub mod shake;
 pub mod simple;
 pub mod slicer;
 pub mod streams;
+#[cfg(test)]
+mod test_util;
 
 use std::fmt;
+use std::path::PathBuf;
+
+/// Crate-wide error type, returned by all fallible public functions.
+#[derive(Debug)]
+pub enum Error {
+    /// File system errors, and invalid or corrupt time slice files.
+    Io(std::io::Error),
+    /// Errors decoding or encoding an image file, with the path of the file.
+    Image(PathBuf, image::ImageError),
+    /// Images or data with an unexpected layout, e.g. images of different size or bit depth.
+    Layout(String),
+    /// Invalid values of options.
+    Parse(String),
+    /// Invalid combinations of options, or an invalid processing setup.
+    Config(String),
+}
+
+/// Result type with the crate-wide [`Error`](enum.Error.html) type as default.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Creates an image error for the given file.
+    pub fn image(path: &std::path::Path, err: image::ImageError) -> Self {
+        Error::Image(path.to_path_buf(), err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Image(path, err) => write!(f, "Image error in {:?}: {}", path, err),
+            Error::Layout(msg) => write!(f, "Layout error: {}", msg),
+            Error::Parse(msg) => write!(f, "Parse error: {}", msg),
+            Error::Config(msg) => write!(f, "Configuration error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Image(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(err: glob::PatternError) -> Self {
+        Error::Parse(format!("Invalid file pattern: {}", err))
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for Error {
+    fn from(err: rayon::ThreadPoolBuildError) -> Self {
+        Error::Config(format!("Unable to build thread pool: {}", err))
+    }
+}
+
+impl From<ParseEnumError> for Error {
+    fn from(err: ParseEnumError) -> Self {
+        Error::Parse(err.0)
+    }
+}
+
+impl From<ParseOptionError> for Error {
+    fn from(err: ParseOptionError) -> Self {
+        Error::Parse(err.0)
+    }
+}
+
+impl From<ParseArrayError> for Error {
+    fn from(err: ParseArrayError) -> Self {
+        Error::Parse(err.0)
+    }
+}
 
 /// Error type for failed parsing of `String`s to `enum`s.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -39,3 +130,22 @@ impl fmt::Display for ParseArrayError {
         self.0.fmt(f)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::options::Threshold;
+    use crate::{Error, Result};
+
+    #[test]
+    fn error_test() {
+        let parse = || -> Result<Threshold> { Ok("abs/x".parse()?) };
+        match parse() {
+            Err(Error::Parse(msg)) => assert!(msg.contains("abs/x")),
+            other => panic!("Expected parse error, got {:?}", other),
+        }
+
+        let err: Error = std::io::Error::from(std::io::ErrorKind::NotFound).into();
+        assert!(err.to_string().starts_with("I/O error: "));
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
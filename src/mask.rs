@@ -0,0 +1,629 @@
+//! Spatial post-processing of per-frame outlier masks and blend masks.
+use crate::ParseOptionError;
+use rayon::prelude::*;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Spatial filter operation on per-frame outlier masks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaskFilter {
+    /// Erosion followed by dilation, with the given radius. Removes small speckles.
+    Open(u32),
+    /// Dilation followed by erosion, with the given radius. Closes small gaps.
+    Close(u32),
+    /// Removes connected outlier regions smaller than the given area (in pixels).
+    MinArea(usize),
+    /// Fills holes inside outlier regions, up to the given maximum area (in pixels). Fills all holes if `None`.
+    FillHoles(Option<usize>),
+}
+impl FromStr for MaskFilter {
+    type Err = ParseOptionError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = str.split('/').collect();
+        let number = |idx: usize| -> Result<Option<usize>, ParseOptionError> {
+            match parts.get(idx) {
+                Some(num) => num.parse().map(Some).map_err(|_| {
+                    ParseOptionError(format!("Unable to parse number in mask filter: {}", str))
+                }),
+                None => Ok(None),
+            }
+        };
+        let required = |value: Option<usize>| {
+            value.ok_or_else(|| ParseOptionError(format!("Missing number in mask filter: {}", str)))
+        };
+
+        match parts[0] {
+            "open" => Ok(MaskFilter::Open(required(number(1)?)? as u32)),
+            "close" => Ok(MaskFilter::Close(required(number(1)?)? as u32)),
+            "min-area" => Ok(MaskFilter::MinArea(required(number(1)?)?)),
+            "fill-holes" => Ok(MaskFilter::FillHoles(number(1)?)),
+            _ => Err(ParseOptionError(format!(
+                "Not a mask filter: {}. Must be one of (open/<r>|close/<r>|min-area/<px>|fill-holes[/<px>])",
+                str
+            ))),
+        }
+    }
+}
+
+/// Spatial feathering of the blend mask, for soft subject edges.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Feather {
+    /// Gaussian blur with the given standard deviation (in pixels).
+    Gauss(f32),
+    /// Edge-aware guided filter with the given radius (in pixels) and regularization.
+    /// Uses the output image as guide.
+    Guided(usize, f32),
+}
+impl FromStr for Feather {
+    type Err = ParseOptionError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = str.split('/').collect();
+        let error = |message: &str| ParseOptionError(format!("{} in feather: {}", message, str));
+        let positive = |idx: usize| -> Result<Option<f32>, ParseOptionError> {
+            match parts.get(idx).map(|num| num.parse::<f32>()) {
+                Some(Ok(value)) if value.is_finite() && value > 0.0 => Ok(Some(value)),
+                Some(Ok(_)) => Err(error("Expected a number > 0")),
+                Some(Err(_)) => Err(error("Unable to parse number")),
+                None => Ok(None),
+            }
+        };
+        let radius = || -> Result<usize, ParseOptionError> {
+            match parts.get(1).map(|num| num.parse::<usize>()) {
+                Some(Ok(radius)) if radius > 0 => Ok(radius),
+                Some(Ok(_)) => Err(error("Expected a radius > 0")),
+                Some(Err(_)) => Err(error("Unable to parse integer radius")),
+                None => Err(error("Missing radius")),
+            }
+        };
+
+        match parts[0] {
+            "gauss" => Ok(Feather::Gauss(
+                positive(1)?.ok_or_else(|| error("Missing number"))?,
+            )),
+            "guided" => Ok(Feather::Guided(radius()?, positive(2)?.unwrap_or(0.01))),
+            _ => Err(ParseOptionError(format!(
+                "Not a feather mode: {}. Must be one of (gauss/<sigma>|guided/<radius>[/<eps>])",
+                str
+            ))),
+        }
+    }
+}
+
+impl Feather {
+    /// Feathers the blend mask (one value per pixel, in [0, 1]).
+    ///
+    /// `colors` are the outlier colors, multiplied by the blend value (`channels` values per pixel),
+    /// `guide` is the output image's brightness in [0, 1] (one value per pixel).
+    ///
+    /// Returns the feathered blend mask and the outlier colors (not multiplied by blend).
+    /// Where the original blend value is below 1, outlier colors are extrapolated from the neighbourhood.
+    pub fn apply(
+        &self,
+        blend: &[f32],
+        colors: &[f32],
+        guide: &[f32],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> (Vec<f32>, Vec<f32>) {
+        let smooth = |data: &[f32], channels: usize| match self {
+            Feather::Gauss(sigma) => gauss_blur(data, width, height, channels, *sigma),
+            Feather::Guided(radius, _) => box_mean(data, width, height, channels, *radius),
+        };
+        let smooth_blend = smooth(blend, 1);
+        let smooth_colors = smooth(colors, channels);
+
+        let mut feathered = match self {
+            Feather::Gauss(_) => smooth_blend.clone(),
+            Feather::Guided(radius, eps) => {
+                guided_filter(blend, guide, width, height, *radius, *eps)
+            }
+        };
+        let mut outlier_colors = vec![0.0; colors.len()];
+        feathered
+            .par_iter_mut()
+            .zip(outlier_colors.par_chunks_mut(channels))
+            .enumerate()
+            .for_each(|(i, (feathered, out))| {
+                let (b, sb) = (blend[i], smooth_blend[i]);
+                if sb <= 0.0 {
+                    // No outliers in the neighbourhood
+                    *feathered = 0.0;
+                    return;
+                }
+                *feathered = feathered.clamp(0.0, 1.0);
+                let offset = i * channels;
+                for ch in 0..channels {
+                    let extrapolated = smooth_colors[offset + ch] / sb;
+                    out[ch] = colors[offset + ch] + (1.0 - b) * extrapolated;
+                }
+            });
+        (feathered, outlier_colors)
+    }
+}
+
+/// Per-frame binary outlier masks of an image sequence, stored as bits.
+pub struct OutlierMask {
+    frames: usize,
+    width: usize,
+    height: usize,
+    words_per_frame: usize,
+    bits: Vec<u64>,
+}
+
+impl OutlierMask {
+    /// Creates an empty mask (no outliers).
+    pub fn new(frames: usize, width: usize, height: usize) -> Self {
+        let words_per_frame = (width * height).div_ceil(64);
+        OutlierMask {
+            frames,
+            width,
+            height,
+            words_per_frame,
+            bits: vec![0; frames * words_per_frame],
+        }
+    }
+    /// The number of frames.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+    /// Is the pixel (flat index) of the frame an outlier?
+    pub fn get(&self, frame: usize, pixel: usize) -> bool {
+        let word = frame * self.words_per_frame + pixel / 64;
+        self.bits[word] & (1 << (pixel % 64)) != 0
+    }
+    /// Sets the outlier state of the pixel (flat index) of the frame.
+    pub fn set(&mut self, frame: usize, pixel: usize, value: bool) {
+        let word = frame * self.words_per_frame + pixel / 64;
+        if value {
+            self.bits[word] |= 1 << (pixel % 64);
+        } else {
+            self.bits[word] &= !(1 << (pixel % 64));
+        }
+    }
+    /// Selects the frame with the largest connected outlier region (8-connected) for each pixel.
+    /// Ties are resolved in favour of later frames.
+    /// Returns the selected frame per pixel (flat index), or `None` for pixels that are no outliers in any frame.
+    ///
+    /// Frames are processed in parallel, with a single buffer of the best (region size, frame) per pixel for each thread.
+    pub fn largest_regions(&self) -> Vec<Option<usize>> {
+        let (width, height) = (self.width, self.height);
+        let pixels = width * height;
+        let buffers: Vec<Mutex<Vec<(usize, usize)>>> = (0..rayon::current_num_threads())
+            .map(|_| Mutex::new(vec![]))
+            .collect();
+        self.bits
+            .par_chunks(self.words_per_frame)
+            .enumerate()
+            .for_each(|(frame, words)| {
+                let thread = rayon::current_thread_index().unwrap_or(0);
+                let mut best = buffers[thread].lock().unwrap();
+                if best.is_empty() {
+                    best.resize(pixels, (0, 0));
+                }
+                let mask = unpack(words, pixels);
+                let mut visited = vec![false; pixels];
+                for start in 0..pixels {
+                    if mask[start] && !visited[start] {
+                        let (region, _) = flood(&mask, &mut visited, width, height, start, true);
+                        let score = (region.len(), frame + 1);
+                        for idx in region {
+                            if score > best[idx] {
+                                best[idx] = score;
+                            }
+                        }
+                    }
+                }
+            });
+
+        let mut best = vec![(0, 0); pixels];
+        for buffer in buffers {
+            for (a, b) in best.iter_mut().zip(buffer.into_inner().unwrap()) {
+                if b > *a {
+                    *a = b;
+                }
+            }
+        }
+        best.iter().map(|(_, frame)| frame.checked_sub(1)).collect()
+    }
+    /// Applies the filters to the mask of each frame, in the given order.
+    pub fn apply(&mut self, filters: &[MaskFilter]) {
+        if filters.is_empty() {
+            return;
+        }
+        let (width, height) = (self.width, self.height);
+        let pixels = width * height;
+        self.bits
+            .par_chunks_mut(self.words_per_frame)
+            .for_each(|words| {
+                let mut mask = unpack(words, pixels);
+                for filter in filters {
+                    match filter {
+                        MaskFilter::Open(r) => {
+                            mask = morph(&mask, width, height, *r as usize, false);
+                            mask = morph(&mask, width, height, *r as usize, true);
+                        }
+                        MaskFilter::Close(r) => {
+                            mask = morph(&mask, width, height, *r as usize, true);
+                            mask = morph(&mask, width, height, *r as usize, false);
+                        }
+                        MaskFilter::MinArea(area) => remove_small(&mut mask, width, height, *area),
+                        MaskFilter::FillHoles(area) => fill_holes(&mut mask, width, height, *area),
+                    }
+                }
+                for w in words.iter_mut() {
+                    *w = 0;
+                }
+                for (i, _) in mask.iter().enumerate().filter(|(_, v)| **v) {
+                    words[i / 64] |= 1 << (i % 64);
+                }
+            });
+    }
+}
+
+/// Unpacks the bits of a single frame's mask.
+fn unpack(words: &[u64], pixels: usize) -> Vec<bool> {
+    (0..pixels)
+        .map(|i| words[i / 64] & (1 << (i % 64)) != 0)
+        .collect()
+}
+
+/// Dilation (`dilate == true`) or erosion with a square structuring element of size `2 * radius + 1`.
+/// Pixels outside the image are ignored.
+fn morph(mask: &[bool], width: usize, height: usize, radius: usize, dilate: bool) -> Vec<bool> {
+    if radius == 0 {
+        return mask.to_vec();
+    }
+    // Pixels that "hit": true for dilation, false for erosion
+    let hit = |v: bool| v == dilate;
+    let mut temp = vec![false; mask.len()];
+    let mut counts = vec![0_usize; std::cmp::max(width, height) + 1];
+
+    // Horizontal pass, using prefix sums of hits
+    for y in 0..height {
+        let row = &mask[(y * width)..((y + 1) * width)];
+        for x in 0..width {
+            counts[x + 1] = counts[x] + hit(row[x]) as usize;
+        }
+        for x in 0..width {
+            let lo = x.saturating_sub(radius);
+            let hi = std::cmp::min(x + radius + 1, width);
+            let any_hit = counts[hi] - counts[lo] > 0;
+            temp[y * width + x] = any_hit == dilate;
+        }
+    }
+    // Vertical pass
+    let mut out = vec![false; mask.len()];
+    for x in 0..width {
+        for y in 0..height {
+            counts[y + 1] = counts[y] + hit(temp[y * width + x]) as usize;
+        }
+        for y in 0..height {
+            let lo = y.saturating_sub(radius);
+            let hi = std::cmp::min(y + radius + 1, height);
+            let any_hit = counts[hi] - counts[lo] > 0;
+            out[y * width + x] = any_hit == dilate;
+        }
+    }
+    out
+}
+
+/// Finds the connected region of pixels with the given value, starting at `start`.
+/// Marks pixels as visited and returns the region's pixels and whether it touches the image border.
+fn flood(
+    mask: &[bool],
+    visited: &mut [bool],
+    width: usize,
+    height: usize,
+    start: usize,
+    eight_connected: bool,
+) -> (Vec<usize>, bool) {
+    let value = mask[start];
+    let mut region = vec![];
+    let mut stack = vec![start];
+    let mut border = false;
+    visited[start] = true;
+    while let Some(idx) = stack.pop() {
+        region.push(idx);
+        let (x, y) = ((idx % width) as i64, (idx / width) as i64);
+        if x == 0 || y == 0 || x == width as i64 - 1 || y == height as i64 - 1 {
+            border = true;
+        }
+        for dy in -1..=1_i64 {
+            for dx in -1..=1_i64 {
+                if (dx == 0 && dy == 0) || (!eight_connected && dx != 0 && dy != 0) {
+                    continue;
+                }
+                let (xx, yy) = (x + dx, y + dy);
+                if xx < 0 || yy < 0 || xx >= width as i64 || yy >= height as i64 {
+                    continue;
+                }
+                let next = yy as usize * width + xx as usize;
+                if !visited[next] && mask[next] == value {
+                    visited[next] = true;
+                    stack.push(next);
+                }
+            }
+        }
+    }
+    (region, border)
+}
+
+/// Removes 8-connected outlier regions smaller than `min_area`.
+fn remove_small(mask: &mut [bool], width: usize, height: usize, min_area: usize) {
+    let mut visited = vec![false; mask.len()];
+    for start in 0..mask.len() {
+        if mask[start] && !visited[start] {
+            let (region, _) = flood(mask, &mut visited, width, height, start, true);
+            if region.len() < min_area {
+                for idx in region {
+                    mask[idx] = false;
+                }
+            }
+        }
+    }
+}
+
+/// Fills 4-connected non-outlier regions that do not touch the image border, up to `max_area`.
+fn fill_holes(mask: &mut [bool], width: usize, height: usize, max_area: Option<usize>) {
+    let mut visited = vec![false; mask.len()];
+    for start in 0..mask.len() {
+        if !mask[start] && !visited[start] {
+            let (region, border) = flood(mask, &mut visited, width, height, start, false);
+            if !border && max_area.map_or(true, |max| region.len() <= max) {
+                for idx in region {
+                    mask[idx] = true;
+                }
+            }
+        }
+    }
+}
+
+/// Separable Gaussian blur of interleaved data, with `channels` values per pixel.
+/// Weights are re-normalized at the image borders.
+fn gauss_blur(data: &[f32], width: usize, height: usize, channels: usize, sigma: f32) -> Vec<f32> {
+    if sigma <= 0.0 {
+        return data.to_vec();
+    }
+    let radius = (3.0 * sigma).ceil() as usize;
+    let kernel: Vec<f32> = (0..=(2 * radius))
+        .map(|i| {
+            let d = i as f32 - radius as f32;
+            (-d * d / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let row_len = width * channels;
+
+    // Horizontal pass
+    let mut temp = vec![0.0; data.len()];
+    temp.par_chunks_mut(row_len)
+        .zip(data.par_chunks(row_len))
+        .for_each(|(out, row)| {
+            for x in 0..width {
+                let lo = x.saturating_sub(radius);
+                let hi = std::cmp::min(x + radius + 1, width);
+                let mut norm = 0.0;
+                for xx in lo..hi {
+                    let w = kernel[xx + radius - x];
+                    norm += w;
+                    for ch in 0..channels {
+                        out[x * channels + ch] += w * row[xx * channels + ch];
+                    }
+                }
+                for ch in 0..channels {
+                    out[x * channels + ch] /= norm;
+                }
+            }
+        });
+    // Vertical pass
+    let mut out = vec![0.0; data.len()];
+    out.par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(y, out)| {
+            let lo = y.saturating_sub(radius);
+            let hi = std::cmp::min(y + radius + 1, height);
+            let mut norm = 0.0;
+            for yy in lo..hi {
+                let w = kernel[yy + radius - y];
+                norm += w;
+                let row = &temp[(yy * row_len)..((yy + 1) * row_len)];
+                for (o, v) in out.iter_mut().zip(row) {
+                    *o += w * v;
+                }
+            }
+            for o in out.iter_mut() {
+                *o /= norm;
+            }
+        });
+    out
+}
+
+/// Mean over a square box of size `2 * radius + 1` of interleaved data, with `channels` values per pixel.
+/// Pixels outside the image are ignored.
+fn box_mean(data: &[f32], width: usize, height: usize, channels: usize, radius: usize) -> Vec<f32> {
+    let row_len = width * channels;
+
+    // Horizontal pass, using prefix sums
+    let mut temp = vec![0.0; data.len()];
+    temp.par_chunks_mut(row_len)
+        .zip(data.par_chunks(row_len))
+        .for_each(|(out, row)| {
+            let mut sums = vec![0.0_f64; (width + 1) * channels];
+            for x in 0..width {
+                for ch in 0..channels {
+                    sums[(x + 1) * channels + ch] =
+                        sums[x * channels + ch] + row[x * channels + ch] as f64;
+                }
+            }
+            for x in 0..width {
+                let lo = x.saturating_sub(radius);
+                let hi = std::cmp::min(x + radius + 1, width);
+                for ch in 0..channels {
+                    out[x * channels + ch] = ((sums[hi * channels + ch] - sums[lo * channels + ch])
+                        / (hi - lo) as f64) as f32;
+                }
+            }
+        });
+    // Vertical pass, using running sums over rows
+    let mut out = vec![0.0; data.len()];
+    let mut sums = vec![0.0_f64; row_len];
+    let row = |y: usize| &temp[(y * row_len)..((y + 1) * row_len)];
+    for y in 0..std::cmp::min(radius, height) {
+        for (s, v) in sums.iter_mut().zip(row(y)) {
+            *s += *v as f64;
+        }
+    }
+    for y in 0..height {
+        if y + radius < height {
+            for (s, v) in sums.iter_mut().zip(row(y + radius)) {
+                *s += *v as f64;
+            }
+        }
+        if y > radius {
+            for (s, v) in sums.iter_mut().zip(row(y - radius - 1)) {
+                *s -= *v as f64;
+            }
+        }
+        let count = (std::cmp::min(y + radius + 1, height) - y.saturating_sub(radius)) as f64;
+        for (o, s) in out[(y * row_len)..((y + 1) * row_len)]
+            .iter_mut()
+            .zip(&sums)
+        {
+            *o = (s / count) as f32;
+        }
+    }
+    out
+}
+
+/// Guided filter (He et al.) of `input`, using the single-channel `guide`.
+fn guided_filter(
+    input: &[f32],
+    guide: &[f32],
+    width: usize,
+    height: usize,
+    radius: usize,
+    eps: f32,
+) -> Vec<f32> {
+    let mean = |data: &[f32]| box_mean(data, width, height, 1, radius);
+    let product =
+        |a: &[f32], b: &[f32]| -> Vec<f32> { a.iter().zip(b).map(|(a, b)| a * b).collect() };
+
+    let mean_guide = mean(guide);
+    let mean_input = mean(input);
+    let corr_guide = mean(&product(guide, guide));
+    let corr_input = mean(&product(guide, input));
+
+    let mut a = vec![0.0; input.len()];
+    let mut b = vec![0.0; input.len()];
+    for i in 0..input.len() {
+        let var = corr_guide[i] - mean_guide[i] * mean_guide[i];
+        let cov = corr_input[i] - mean_guide[i] * mean_input[i];
+        a[i] = cov / (var + eps);
+        b[i] = mean_input[i] - a[i] * mean_guide[i];
+    }
+    let mean_a = mean(&a);
+    let mean_b = mean(&b);
+    guide
+        .iter()
+        .enumerate()
+        .map(|(i, g)| mean_a[i] * g + mean_b[i])
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mask::{Feather, MaskFilter, OutlierMask};
+
+    #[test]
+    fn mask_filter_test() {
+        let (w, h) = (9, 7);
+        let mut mask = OutlierMask::new(1, w, h);
+        // A 5x5 square with a hole in the middle
+        for y in 1..6 {
+            for x in 1..6 {
+                if (x, y) != (3, 3) {
+                    mask.set(0, y * w + x, true);
+                }
+            }
+        }
+        // A single speckle
+        mask.set(0, 3 * w + 8, true);
+
+        mask.apply(&[MaskFilter::MinArea(2), MaskFilter::FillHoles(None)]);
+        assert!(mask.get(0, 3 * w + 3));
+        assert!(!mask.get(0, 3 * w + 8));
+
+        mask.apply(&[MaskFilter::Open(1)]);
+        assert!(mask.get(0, 3 * w + 3));
+        assert!(mask.get(0, w + 1));
+
+        mask.apply(&[MaskFilter::Open(3)]);
+        assert!(!mask.get(0, 3 * w + 3));
+
+        // Largest region wins, ties resolved in favour of later frames
+        let mut mask = OutlierMask::new(3, 4, 1);
+        mask.set(0, 0, true);
+        mask.set(0, 1, true);
+        mask.set(0, 2, true);
+        mask.set(1, 2, true);
+        mask.set(1, 3, true);
+        mask.set(2, 0, true);
+        assert_eq!(
+            mask.largest_regions(),
+            vec![Some(0), Some(0), Some(0), Some(1)]
+        );
+        let mut mask = OutlierMask::new(2, 2, 1);
+        mask.set(0, 0, true);
+        mask.set(1, 0, true);
+        assert_eq!(mask.largest_regions(), vec![Some(1), None]);
+
+        assert_eq!(
+            "fill-holes/10".parse::<MaskFilter>(),
+            Ok(MaskFilter::FillHoles(Some(10)))
+        );
+        assert!("open".parse::<MaskFilter>().is_err());
+    }
+
+    #[test]
+    fn feather_test() {
+        let (w, h) = (8, 3);
+        // Left half is a red outlier, right half is background
+        let blend: Vec<f32> = (0..(w * h))
+            .map(|i| if i % w < 4 { 1.0 } else { 0.0 })
+            .collect();
+        let colors: Vec<f32> = blend.iter().flat_map(|b| vec![255.0 * b, 0.0]).collect();
+
+        for feather in &[Feather::Gauss(1.0), Feather::Guided(2, 0.01)] {
+            let (feathered, outlier_colors) = feather.apply(&blend, &colors, &blend, w, h, 2);
+            // Blend is soft at the edge, and zero far from it
+            assert!(feathered[w + 3] < 1.0 && feathered[w + 4] > 0.0);
+            assert!(feathered[w + 7] < 0.01);
+            // Outlier colors are extrapolated beyond the edge
+            assert!((outlier_colors[(w + 4) * 2] - 255.0).abs() < 0.01);
+            assert!((outlier_colors[w * 2] - 255.0).abs() < 0.01);
+        }
+
+        assert_eq!("gauss/2.5".parse::<Feather>(), Ok(Feather::Gauss(2.5)));
+        assert_eq!("guided/4".parse::<Feather>(), Ok(Feather::Guided(4, 0.01)));
+        assert_eq!(
+            "guided/4/0.1".parse::<Feather>(),
+            Ok(Feather::Guided(4, 0.1))
+        );
+        for invalid in &[
+            "gauss",
+            "gauss/0",
+            "gauss/-1",
+            "gauss/NaN",
+            "guided/2.5",
+            "guided/-3",
+            "guided/0",
+            "guided/NaN",
+            "guided/2/0",
+            "guided/2/-0.1",
+        ] {
+            assert!(invalid.parse::<Feather>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Memory planning. Estimates the peak memory of a job, and chooses slicing and parallelism to fit a budget.
+use crate::slicer::SliceLength;
+
+/// Number of image rows per time slice preferred by the planner, i.e. the default of option `--slice`.
+const DEFAULT_ROWS: usize = 4;
+/// Minimum number of pixels per time slice. Smaller slices result in too many temporary files.
+const MIN_SLICE_PIXELS: usize = 64;
+/// Number of decoded images per thread held in memory during time-slicing,
+/// see [`ImageStream::prefetch`](../streams/struct.ImageStream.html#method.prefetch).
+const PREFETCH_IMAGES: usize = 2;
+/// Per-pixel statistics of sliding histograms per frame, in bytes.
+const PIXEL_STATS_BYTES: usize = 32;
+/// Per-thread data per image and channel (converted and sorted values), in bytes.
+const SCRATCH_BYTES: usize = 8;
+
+/// Properties of a job that determine its memory usage.
+#[derive(Debug, Clone)]
+pub struct MemoryJob {
+    /// Image width, in pixels.
+    pub width: usize,
+    /// Image height, in pixels.
+    pub height: usize,
+    /// Channels per pixel.
+    pub channels: usize,
+    /// Bytes per sample, 1 for 8 bit and 2 for 16 bit images.
+    pub sample_bytes: usize,
+    /// Number of input images.
+    pub images: usize,
+    /// Number of output frames, 1 for a single image.
+    pub frames: usize,
+    /// Range of images per output frame (`--video-in`), equal to `images` for a single image.
+    pub window: usize,
+    /// Images between consecutive output frames (`--video-out` step).
+    pub window_shift: usize,
+    /// Are per-frame background buffers required (feathering or background output)?
+    pub with_background: bool,
+    /// Is a per-frame blend mask required (feathering)?
+    pub with_blend: bool,
+    /// Are per-frame outlier masks required (mask filters or outlier mode `largest`)?
+    pub with_masks: bool,
+    /// Is a clean plate used?
+    pub with_plate: bool,
+}
+
+/// Slicing and parallelism planned for a memory budget, see [`MemoryJob::plan`](struct.MemoryJob.html#method.plan).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryPlan {
+    /// Slicing to temp files.
+    pub slice: SliceLength,
+    /// Number of threads for time-slicing and processing.
+    pub threads: usize,
+    /// Number of video frames rendered in a single pass over the time slices.
+    pub video_batch: usize,
+    /// Are time slices held in memory?
+    pub in_memory: bool,
+    /// Estimated peak memory, in bytes.
+    pub peak: usize,
+    /// Does the estimated peak memory fit the budget?
+    pub fits: bool,
+}
+
+impl MemoryJob {
+    /// Size of a single image, in bytes.
+    pub fn image_bytes(&self) -> usize {
+        self.width * self.height * self.channels * self.sample_bytes
+    }
+    /// Size of all time slices held in memory, in bytes.
+    pub fn slices_bytes(&self) -> usize {
+        self.images * self.image_bytes()
+    }
+    /// Size of the outlier masks of a single frame (one bit per pixel and image), in bytes. Zero if no masks are required.
+    pub fn mask_bytes(&self) -> usize {
+        if self.with_masks {
+            self.window_rows(1) * (self.width * self.height).div_ceil(8)
+        } else {
+            0
+        }
+    }
+    /// Peak memory during time-slicing with the given number of threads, excluding time slices held in memory.
+    pub fn slicing_bytes(&self, threads: usize) -> usize {
+        PREFETCH_IMAGES * threads * self.image_bytes()
+    }
+    /// Peak memory during processing, with the given samples per time slice, threads and video batch size.
+    /// Excludes time slices held in memory.
+    pub fn processing_bytes(&self, slice_samples: usize, threads: usize, batch: usize) -> usize {
+        self.fixed_bytes(batch) + threads * slice_samples * self.per_sample_bytes(batch)
+    }
+    /// Estimated peak memory of the entire job, in bytes.
+    pub fn peak_bytes(
+        &self,
+        slice_samples: usize,
+        threads: usize,
+        batch: usize,
+        in_memory: bool,
+    ) -> usize {
+        let peak = std::cmp::max(
+            self.slicing_bytes(threads),
+            self.processing_bytes(slice_samples, threads, batch),
+        );
+        if in_memory {
+            peak + self.slices_bytes()
+        } else {
+            peak
+        }
+    }
+
+    /// Plans slicing, the number of threads and the video batch size for the given budget in bytes.
+    /// Prefers time slices of entire rows over many threads, and many threads over large video batches.
+    /// Time slices of less than a row are only used if nothing else fits.
+    /// `in_memory` forces time slices to be held in memory (or not), `None` to decide by the budget.
+    pub fn plan(
+        &self,
+        budget: usize,
+        max_threads: usize,
+        max_batch: usize,
+        in_memory: Option<bool>,
+    ) -> MemoryPlan {
+        let max_batch = std::cmp::max(1, std::cmp::min(max_batch, self.frames));
+        let forced_slices = if in_memory == Some(true) {
+            self.slices_bytes()
+        } else {
+            0
+        };
+        let available = budget.saturating_sub(forced_slices);
+
+        for min_pixels in &[self.width, MIN_SLICE_PIXELS] {
+            for threads in (1..=std::cmp::max(max_threads, 1)).rev() {
+                if self.slicing_bytes(threads) > available {
+                    continue;
+                }
+                let mut batch = max_batch;
+                loop {
+                    if let Some(slice) = self.fit_slice(available, threads, batch, *min_pixels) {
+                        return self.create_plan(slice, threads, batch, budget, in_memory);
+                    }
+                    if batch == 1 {
+                        break;
+                    }
+                    batch = batch.div_ceil(2);
+                }
+            }
+        }
+        let slice = SliceLength::Pixels(MIN_SLICE_PIXELS);
+        self.create_plan(slice, 1, 1, budget, in_memory)
+    }
+
+    /// Output buffers and outlier masks of a single frame, in bytes.
+    pub fn frame_bytes(&self) -> usize {
+        let pixels = self.width * self.height;
+        let samples = pixels * self.channels;
+        let mut frame = 2 * samples * self.sample_bytes;
+        if self.with_background {
+            frame += samples * self.sample_bytes;
+        }
+        if self.with_blend {
+            frame += pixels * 4;
+        }
+        frame + self.mask_bytes()
+    }
+    /// Output buffers, masks and clean plate, in bytes.
+    fn fixed_bytes(&self, batch: usize) -> usize {
+        let plate = if self.with_plate {
+            self.image_bytes()
+        } else {
+            0
+        };
+        batch * self.frame_bytes() + plate
+    }
+    /// Memory per thread and sample of a time slice (of a single image), in bytes.
+    fn per_sample_bytes(&self, batch: usize) -> usize {
+        let sliding = if self.frames > 1 {
+            batch * PIXEL_STATS_BYTES / self.channels
+        } else {
+            0
+        };
+        self.window_rows(batch) * self.sample_bytes + sliding
+    }
+    /// Number of images read per time slice for a batch of frames.
+    fn window_rows(&self, batch: usize) -> usize {
+        let rows = self.window + (batch - 1) * self.window_shift;
+        std::cmp::min(rows, self.images)
+    }
+    /// The largest slicing up to the default that fits the budget, with at least `min_pixels` per time slice.
+    fn fit_slice(
+        &self,
+        budget: usize,
+        threads: usize,
+        batch: usize,
+        min_pixels: usize,
+    ) -> Option<SliceLength> {
+        let fixed = self.fixed_bytes(batch);
+        let scratch = self.window_rows(batch) * self.channels * SCRATCH_BYTES;
+        let per_thread = budget.checked_sub(fixed)? / threads;
+        let samples = per_thread.checked_sub(scratch)? / self.per_sample_bytes(batch);
+        let pixels = samples / self.channels;
+        if pixels >= DEFAULT_ROWS * self.width {
+            Some(SliceLength::Rows(DEFAULT_ROWS))
+        } else if pixels >= self.width {
+            Some(SliceLength::Rows(pixels / self.width))
+        } else if pixels >= min_pixels {
+            Some(SliceLength::Pixels(pixels))
+        } else {
+            None
+        }
+    }
+    /// Creates the plan for the given choices, and decides whether time slices are held in memory.
+    fn create_plan(
+        &self,
+        slice: SliceLength,
+        threads: usize,
+        batch: usize,
+        budget: usize,
+        in_memory: Option<bool>,
+    ) -> MemoryPlan {
+        let slice_samples = match slice {
+            SliceLength::Rows(rows) => rows * self.width * self.channels,
+            SliceLength::Pixels(pixels) => pixels * self.channels,
+            SliceLength::Count(count) => (self.width * self.height * self.channels).div_ceil(count),
+        };
+        let in_memory = in_memory
+            .unwrap_or_else(|| self.peak_bytes(slice_samples, threads, batch, true) <= budget);
+        let peak = self.peak_bytes(slice_samples, threads, batch, in_memory);
+        MemoryPlan {
+            slice,
+            threads,
+            video_batch: batch,
+            in_memory,
+            peak,
+            fits: peak <= budget,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory::MemoryJob;
+    use crate::slicer::SliceLength;
+
+    #[test]
+    fn memory_plan_test() {
+        const MB: usize = 1024 * 1024;
+        // 1000 Full HD images, 8 bit RGB
+        let job = MemoryJob {
+            width: 1920,
+            height: 1080,
+            channels: 3,
+            sample_bytes: 1,
+            images: 1000,
+            frames: 1,
+            window: 1000,
+            window_shift: 1,
+            with_background: false,
+            with_blend: false,
+            with_masks: false,
+            with_plate: false,
+        };
+
+        // Enough memory for everything
+        let plan = job.plan(16 * 1024 * MB, 8, 1, None);
+        assert_eq!(plan.slice, SliceLength::Rows(4));
+        assert_eq!(plan.threads, 8);
+        assert!(plan.in_memory && plan.fits);
+
+        // Smaller slices, with all threads
+        let plan = job.plan(256 * MB, 8, 1, None);
+        assert_eq!(plan.threads, 8);
+        assert_eq!(plan.slice, SliceLength::Rows(4));
+        assert!(!plan.in_memory && plan.fits);
+        let plan = job.plan(100 * MB, 8, 1, None);
+        assert_eq!(plan.threads, 8);
+        assert!(match plan.slice {
+            SliceLength::Rows(rows) => rows < 4,
+            SliceLength::Pixels(pixels) => pixels < 1920,
+            SliceLength::Count(_) => false,
+        });
+        assert!(plan.peak <= 100 * MB);
+
+        // Fewer threads, as decoded images don't fit
+        let plan = job.plan(40 * MB, 8, 1, None);
+        assert!(plan.threads < 8 && plan.fits);
+
+        // Video: smaller batches
+        let video = MemoryJob {
+            frames: 500,
+            window: 20,
+            with_background: true,
+            ..job
+        };
+        let plan = video.plan(1024 * MB, 4, 500, Some(false));
+        assert_eq!(plan.threads, 4);
+        assert!(plan.video_batch < 500 && plan.video_batch > 1 && plan.fits);
+        assert!(plan.peak <= 1024 * MB);
+
+        // Outlier masks take one bit per pixel and image of a frame's window
+        let masks = MemoryJob {
+            window: 500,
+            with_masks: true,
+            ..video
+        };
+        assert_eq!(masks.mask_bytes(), 500 * 1920 * 1080 / 8);
+        assert_eq!(
+            masks.frame_bytes(),
+            video.frame_bytes() + masks.mask_bytes()
+        );
+
+        // Impossible
+        assert!(!job.plan(MB, 8, 1, None).fits);
+    }
+}
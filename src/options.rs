@@ -1,8 +1,40 @@
 //! Data structures for options of the tool
 
+use crate::color;
 use crate::{ParseEnumError, ParseOptionError};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+/// Default pixel selection mode, in command line format.
+pub const DEFAULT_MODE: &str = "outlier";
+/// Default outlier threshold, in command line format.
+pub const DEFAULT_THRESHOLD: &str = "abs/0.05/0.2";
+/// Default outlier selection mode, in command line format.
+pub const DEFAULT_OUTLIER: &str = "extreme";
+/// Default background pixel selection mode, in command line format.
+pub const DEFAULT_BACKGROUND: &str = "random";
+/// Default color space for outlier detection, in command line format.
+pub const DEFAULT_COLOR_SPACE: &str = "rgb";
+/// Default slicing of time slices, in command line format.
+pub const DEFAULT_SLICE: &str = "rows/4";
+/// Default compression of time slices, in command line format.
+pub const DEFAULT_COMPRESSION: &str = "gzip/6";
+/// Default delta encoding of time slices, in command line format.
+pub const DEFAULT_DELTA: &str = "none";
+/// Default in-memory mode of time slices, in command line format.
+pub const DEFAULT_IN_MEMORY: &str = "auto/1024";
+/// Default color channel weights.
+pub const DEFAULT_WEIGHTS: [f32; 4] = [1.0; 4];
+/// Default output image quality for JPG files, in percent.
+pub const DEFAULT_QUALITY: u8 = 95;
+
+/// Parses one of the default options. Panics on invalid defaults, which are covered by tests.
+pub(crate) fn parse_default<T: FromStr>(str: &str) -> T {
+    str.parse()
+        .unwrap_or_else(|_| panic!("Invalid default option {}", str))
+}
+
 /// Pixel selection mode.
 #[derive(Debug, Clone, PartialEq)]
 pub enum SelectionMode {
@@ -66,6 +98,7 @@ pub struct Fade {
 }
 impl Fade {
     /// Expects vector of (frame, value) pairs, ordered by frame.
+    /// Panics if less than two frames are given.
     pub fn new(mode: FadeMode, absolute: bool, frames: Vec<(i32, f32)>) -> Self {
         let offset = frames[0].0;
         let len = (frames
@@ -75,11 +108,11 @@ impl Fade {
             - offset) as usize;
         let mut values = vec![1.0; len + 1];
         let mut idx = 0;
-        for i in 0..(len + 1) {
+        for (i, value) in values.iter_mut().enumerate() {
             let (f1, v1) = frames[idx];
             let (f2, v2) = frames[idx + 1];
             let frame = i as i32 + offset;
-            values[i] = v1 + (v2 - v1) * (frame - f1) as f32 / (f2 - f1) as f32;
+            *value = v1 + (v2 - v1) * (frame - f1) as f32 / (f2 - f1) as f32;
             if frame == f2 {
                 idx += 1;
             }
@@ -131,7 +164,7 @@ impl Fade {
                     while i < 0 {
                         i += len as i32;
                     }
-                    i = i % len as i32;
+                    i %= len as i32;
                     self.values[i as usize]
                 }
             }
@@ -143,72 +176,101 @@ impl FromStr for Fade {
 
     fn from_str(str: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = str.split('/').collect();
-        let mode_str = parts
-            .get(0)
-            .expect(&format!("Unexpected format in {}", str));
-        let mode = mode_str.parse().expect(&format!(
-            "Unexpected fade mode in {}, must be one of (clamp|repeat)",
-            str
-        ));
-
-        let abs_str = parts
-            .get(1)
-            .expect(&format!("Unexpected format in {}", str));
-        let absolute = match *abs_str {
+        if parts.len() < 2 {
+            return Err(ParseOptionError(format!("Unexpected format in {}", str)));
+        }
+        let mode = parts[0].parse().map_err(|_| {
+            ParseOptionError(format!(
+                "Unexpected fade mode in {}, must be one of (clamp|repeat)",
+                str
+            ))
+        })?;
+
+        let absolute = match parts[1] {
             "absolute" | "abs" => true,
             "relative" | "rel" => false,
             _ => return Err(ParseOptionError(format!("Not a frame fade spec: {}", str))),
         };
-        let mut frames = vec![];
+        let error = || {
+            ParseOptionError(format!(
+                "Expected (int,float) per frame for fade. Got: {}",
+                str
+            ))
+        };
+        let mut frames: Vec<(i32, f32)> = vec![];
         for p in parts.iter().skip(2) {
             let parts: Vec<_> = p.split(',').collect();
             if parts.len() != 2 {
-                return Err(ParseOptionError(format!(
-                    "Expected (int,float) per frame for fade. Got: {}",
-                    str
-                )));
+                return Err(error());
             }
             frames.push((
-                parts[0].parse().expect(&format!(
-                    "Expected (int,float) per frame for fade. Got: {}",
-                    str
-                )),
-                parts[1].parse().expect(&format!(
-                    "Expected (int,float) per frame for fade. Got: {}",
-                    str
-                )),
+                parts[0].parse().map_err(|_| error())?,
+                parts[1].parse().map_err(|_| error())?,
             ));
         }
+        if frames.len() < 2 {
+            return Err(ParseOptionError(format!(
+                "Fade requires at least two frames specified. Got: {}",
+                str
+            )));
+        }
+        if frames.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err(ParseOptionError(format!(
+                "Fade frames must be in ascending order. Got: {}",
+                str
+            )));
+        }
 
         Ok(Fade::new(mode, absolute, frames))
     }
 }
 
+/// Robust scale estimator for relative thresholds.
+/// All estimators are normalized to match the inter-quartile range for normally distributed data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScaleEstimator {
+    /// Inter-quartile range.
+    Iqr,
+    /// Median absolute deviation.
+    Mad,
+    /// Rousseeuw and Croux' Sn estimator.
+    Sn,
+    /// Rousseeuw and Croux' Qn estimator.
+    Qn,
+}
+
 /// Outlier algorithm threshold.
 #[derive(Debug, Clone)]
 pub struct Threshold {
     absolute: bool,
+    estimator: ScaleEstimator,
     min: f32,
     max: f32,
     scale: f32,
 }
 impl Threshold {
-    /// Creates a new treshold.
+    /// Creates a new treshold. Relative thresholds use the inter-quartile range.
     pub fn new(absolute: bool, min: f32, max: f32) -> Self {
         if absolute {
             Threshold {
                 absolute,
+                estimator: ScaleEstimator::Iqr,
                 min: min * 255.0,
                 max: max * 255.0,
                 scale: 1.0 / ((max - min) * 255.0),
             }
         } else {
-            Threshold {
-                absolute,
-                min,
-                max,
-                scale: 1.0 / (max - min),
-            }
+            Threshold::robust(ScaleEstimator::Iqr, min, max)
+        }
+    }
+    /// Creates a new relative treshold, using the given robust scale estimator.
+    pub fn robust(estimator: ScaleEstimator, min: f32, max: f32) -> Self {
+        Threshold {
+            absolute: false,
+            estimator,
+            min,
+            max,
+            scale: 1.0 / (max - min),
         }
     }
     /// Creates a new absolute treshold.
@@ -233,6 +295,10 @@ impl Threshold {
     pub fn absolute(&self) -> bool {
         self.absolute
     }
+    /// The robust scale estimator for relative thresholds.
+    pub fn estimator(&self) -> &ScaleEstimator {
+        &self.estimator
+    }
     /// Lower threshold (start of blending).
     pub fn min(&self) -> f32 {
         self.min
@@ -247,35 +313,41 @@ impl FromStr for Threshold {
 
     fn from_str(str: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = str.split('/').collect();
-        let opt_str = parts
-            .get(0)
-            .expect(&format!("Unexpected format in {}", str));
-        let absolute = match *opt_str {
-            "absolute" | "abs" => true,
-            "relative" | "rel" => false,
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(ParseOptionError(format!("Unexpected format in {}", str)));
+        }
+        let estimator = match parts[0] {
+            "absolute" | "abs" => None,
+            "relative" | "rel" | "iqr" => Some(ScaleEstimator::Iqr),
+            "mad" => Some(ScaleEstimator::Mad),
+            "sn" => Some(ScaleEstimator::Sn),
+            "qn" => Some(ScaleEstimator::Qn),
             _ => return Err(ParseOptionError(format!(
-                "Not a pixel outlier detection mode: {}. Must be one of (abs[olute]|rel[ative])/<min>[/<max>]",
+                "Not a pixel outlier detection mode: {}. Must be one of (abs[olute]|rel[ative]|iqr|mad|sn|qn)/<min>[/<max>]",
                 str
             ))),
         };
 
-        let thresh_min_str = parts
-            .get(1)
-            .expect(&format!("Unexpected format in {}", str));
-        let min = thresh_min_str.parse().expect(&format!(
-            "Unable to parse lower threshold for outlier detection: {}",
-            str
-        ));
-        let thresh_max_str = parts.get(2);
-        let max = match thresh_max_str {
-            Some(str) => str.parse().expect(&format!(
-                "Unable to parse upper threshold for outlier detection: {}",
+        let min = parts[1].parse().map_err(|_| {
+            ParseOptionError(format!(
+                "Unable to parse lower threshold for outlier detection: {}",
                 str
-            )),
+            ))
+        })?;
+        let max = match parts.get(2) {
+            Some(max_str) => max_str.parse().map_err(|_| {
+                ParseOptionError(format!(
+                    "Unable to parse upper threshold for outlier detection: {}",
+                    str
+                ))
+            })?,
             None => min,
         };
 
-        Ok(Threshold::new(absolute, min, max))
+        Ok(match estimator {
+            Some(estimator) => Threshold::robust(estimator, min, max),
+            None => Threshold::abs(min, max),
+        })
     }
 }
 
@@ -294,6 +366,9 @@ pub enum OutlierSelectionMode {
     AllForward,
     /// Progressively blend all outliers into background, backward.
     AllBackward,
+    /// Use the outlier from the frame with the largest connected outlier region containing the pixel.
+    /// Selects whole regions (i.e. object instances) rather than individual pixels.
+    Largest,
 }
 impl FromStr for OutlierSelectionMode {
     type Err = ParseEnumError;
@@ -306,14 +381,52 @@ impl FromStr for OutlierSelectionMode {
             "average" => Ok(OutlierSelectionMode::Average),
             "forward" => Ok(OutlierSelectionMode::AllForward),
             "backward" => Ok(OutlierSelectionMode::AllBackward),
+            "largest" => Ok(OutlierSelectionMode::Largest),
             _ => Err(ParseEnumError(format!(
-                "Not an outlier selection mode: {}. Must be one of (first|last|extreme|average|forward|backward)",
+                "Not an outlier selection mode: {}. Must be one of (first|last|extreme|average|forward|backward|largest)",
                 str
             ))),
         }
     }
 }
 
+/// Clean plate, i.e. an image of the empty scene.
+/// Used as background, and as reference for outlier detection instead of the median.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CleanPlate {
+    /// Clean plate image file. Must have the same size and color format as the processed images.
+    Image(PathBuf),
+    /// Range of "clean" frames (start, end exclusive). The clean plate is the per-pixel median of these frames.
+    Frames(usize, usize),
+}
+impl FromStr for CleanPlate {
+    type Err = ParseOptionError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = str.split('/').collect();
+        if parts.len() == 3 && parts[0] == "frames" {
+            let start = parts[1].parse().map_err(|_| {
+                ParseOptionError(format!(
+                    "Unable to parse start frame in clean plate: {}",
+                    str
+                ))
+            })?;
+            let end = parts[2].parse().map_err(|_| {
+                ParseOptionError(format!("Unable to parse end frame in clean plate: {}", str))
+            })?;
+            if end <= start {
+                return Err(ParseOptionError(format!(
+                    "Expected start < end for clean plate frames. Got: {}",
+                    str
+                )));
+            }
+            Ok(CleanPlate::Frames(start, end))
+        } else {
+            Ok(CleanPlate::Image(PathBuf::from(str)))
+        }
+    }
+}
+
 /// Background pixel selection mode, i.e. when no outliers are found.
 #[derive(Debug, Clone)]
 pub enum BackgroundMode {
@@ -343,15 +456,319 @@ impl FromStr for BackgroundMode {
     }
 }
 
+/// Color space for outlier detection (median, inter-quartile range and distance calculation).
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorSpace {
+    /// Plain RGB(A) channels.
+    Rgb,
+    /// CIELAB, for perceptual distances (CIE76 Delta E).
+    Lab,
+    /// YCbCr (ITU-R BT.601), separates brightness (Y) from color (Cb, Cr).
+    YCbCr,
+    /// HSV, with circular hue.
+    Hsv,
+}
+impl ColorSpace {
+    /// Converts a pixel, given in 8 bit color range [0, 255], to this color space.
+    /// Only the first three channels are converted if there are at least three channels,
+    /// all other channels are copied.
+    pub fn convert(&self, pixel: &[f32], out: &mut [f32]) {
+        out[..pixel.len()].copy_from_slice(pixel);
+        if pixel.len() < 3 || *self == ColorSpace::Rgb {
+            return;
+        }
+        let rgb = [pixel[0], pixel[1], pixel[2]];
+        let conv = match self {
+            ColorSpace::Rgb => rgb,
+            ColorSpace::Lab => color::rgb_to_lab(rgb),
+            ColorSpace::YCbCr => color::rgb_to_ycbcr(rgb),
+            ColorSpace::Hsv => color::rgb_to_hsv(rgb),
+        };
+        out[..3].copy_from_slice(&conv);
+    }
+    /// Is the channel circular (i.e. the hue in HSV), with a period of 255?
+    pub fn is_circular(&self, channel: usize, channels: usize) -> bool {
+        *self == ColorSpace::Hsv && channel == 0 && channels >= 3
+    }
+}
+impl FromStr for ColorSpace {
+    type Err = ParseEnumError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "rgb" => Ok(ColorSpace::Rgb),
+            "lab" => Ok(ColorSpace::Lab),
+            "ycbcr" => Ok(ColorSpace::YCbCr),
+            "hsv" => Ok(ColorSpace::Hsv),
+            _ => Err(ParseEnumError(format!(
+                "Not a color space: {}. Must be one of (rgb|lab|ycbcr|hsv)",
+                str
+            ))),
+        }
+    }
+}
+
+/// Values of an option for parameter sweeps.
+/// Numbers between `/` may be given as ranges `<start>..<end>:<step>` (end inclusive),
+/// e.g. `abs/0.02..0.1:0.02` expands to `abs/0.02`, `abs/0.04`, ..., `abs/0.10`.
+/// Values keep their textual representation, for use in output file names.
+#[derive(Debug, Clone)]
+pub struct Sweep<T> {
+    values: Vec<(String, T)>,
+}
+impl<T> Sweep<T> {
+    /// The expanded values, with their textual representation.
+    pub fn values(&self) -> &[(String, T)] {
+        &self.values
+    }
+    /// Consumes the sweep and returns the expanded values.
+    pub fn into_values(self) -> Vec<(String, T)> {
+        self.values
+    }
+}
+impl<T: FromStr> FromStr for Sweep<T>
+where
+    T::Err: fmt::Display,
+{
+    type Err = ParseOptionError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let mut expanded: Vec<String> = vec![];
+        for (i, part) in str.split('/').enumerate() {
+            let values = Self::expand_range(part)?;
+            expanded = if i == 0 {
+                values
+            } else {
+                expanded
+                    .iter()
+                    .flat_map(|prefix| values.iter().map(move |v| format!("{}/{}", prefix, v)))
+                    .collect()
+            };
+        }
+        let values = expanded
+            .into_iter()
+            .map(|s| match s.parse::<T>() {
+                Ok(value) => Ok((s, value)),
+                Err(err) => Err(ParseOptionError(err.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Sweep { values })
+    }
+}
+impl<T> Sweep<T> {
+    /// Expands a range `<start>..<end>:<step>`, or returns the unchanged string if it is no range.
+    fn expand_range(str: &str) -> Result<Vec<String>, ParseOptionError> {
+        let (start, rest) = match str.find("..") {
+            Some(idx) => (&str[..idx], &str[(idx + 2)..]),
+            None => return Ok(vec![str.to_string()]),
+        };
+        let (end, step) = match rest.find(':') {
+            Some(idx) => (&rest[..idx], &rest[(idx + 1)..]),
+            None => {
+                return Err(ParseOptionError(format!(
+                    "Range {} requires a step: <start>..<end>:<step>",
+                    str
+                )))
+            }
+        };
+        let parse = |s: &str| -> Result<f64, ParseOptionError> {
+            s.parse().map_err(|_| {
+                ParseOptionError(format!("Unable to parse number {} in range {}", s, str))
+            })
+        };
+        let (start_value, end_value, step_value) = (parse(start)?, parse(end)?, parse(step)?);
+        if step_value <= 0.0 || end_value < start_value {
+            return Err(ParseOptionError(format!(
+                "Expected start <= end and step > 0 in range {}",
+                str
+            )));
+        }
+        let decimals = [start, end, step]
+            .iter()
+            .map(|s| s.find('.').map_or(0, |idx| s.len() - idx - 1))
+            .max()
+            .unwrap_or(0);
+        let count = ((end_value - start_value) / step_value + 1e-9).floor() as usize + 1;
+        Ok((0..count)
+            .map(|i| format!("{:.*}", decimals, start_value + i as f64 * step_value))
+            .collect())
+    }
+}
+
+/// A single combination of algorithm options in a parameter sweep.
+#[derive(Debug, Clone)]
+pub struct SweepItem {
+    /// Index of the combination.
+    pub index: usize,
+    /// Outlier threshold, with its textual representation.
+    pub threshold: (String, Threshold),
+    /// Outlier selection mode, with its textual representation.
+    pub outlier: (String, OutlierSelectionMode),
+    /// Background selection mode, with its textual representation.
+    pub background: (String, BackgroundMode),
+}
+impl SweepItem {
+    /// Output path for this combination.
+    /// Replaces `{threshold}`, `{outlier}`, `{background}` and `{index}` in the file name.
+    /// Appends the index to the file name if it contains none of these.
+    pub fn output_path(&self, path: &Path) -> PathBuf {
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return path.to_path_buf(),
+        };
+        let label = |str: &str| str.replace('/', "_");
+        let index = format!("{:03}", self.index);
+        let new_name = name
+            .replace("{threshold}", &label(&self.threshold.0))
+            .replace("{outlier}", &label(&self.outlier.0))
+            .replace("{background}", &label(&self.background.0))
+            .replace("{index}", &index);
+        let new_name = if new_name != name {
+            new_name
+        } else {
+            match name.rfind('.') {
+                Some(idx) => format!("{}-{}{}", &name[..idx], index, &name[idx..]),
+                None => format!("{}-{}", name, index),
+            }
+        };
+        path.with_file_name(new_name)
+    }
+}
+
+/// Parameter sweep over algorithm options, rendering each combination from the same time slices.
+#[derive(Debug, Clone)]
+pub struct ParameterSweep {
+    thresholds: Vec<(String, Threshold)>,
+    outliers: Vec<(String, OutlierSelectionMode)>,
+    backgrounds: Vec<(String, BackgroundMode)>,
+}
+impl ParameterSweep {
+    /// Creates a new parameter sweep. Each list must contain at least one value.
+    pub fn new(
+        thresholds: Vec<(String, Threshold)>,
+        outliers: Vec<(String, OutlierSelectionMode)>,
+        backgrounds: Vec<(String, BackgroundMode)>,
+    ) -> Self {
+        ParameterSweep {
+            thresholds,
+            outliers,
+            backgrounds,
+        }
+    }
+    /// The thresholds of the sweep, with their labels.
+    pub fn thresholds(&self) -> &[(String, Threshold)] {
+        &self.thresholds
+    }
+    /// The number of combinations.
+    pub fn len(&self) -> usize {
+        self.thresholds.len() * self.outliers.len() * self.backgrounds.len()
+    }
+    /// Is the sweep empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// All combinations of options.
+    pub fn combinations(&self) -> Vec<SweepItem> {
+        let mut items = Vec::with_capacity(self.len());
+        for threshold in &self.thresholds {
+            for outlier in &self.outliers {
+                for background in &self.backgrounds {
+                    items.push(SweepItem {
+                        index: items.len(),
+                        threshold: threshold.clone(),
+                        outlier: outlier.clone(),
+                        background: background.clone(),
+                    });
+                }
+            }
+        }
+        items
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::options::Fade;
+    use crate::options::{
+        BackgroundMode, CleanPlate, Fade, OutlierSelectionMode, ParameterSweep, ScaleEstimator,
+        Sweep, Threshold,
+    };
+    use std::path::PathBuf;
 
     #[test]
     fn fade_test() {
         let str = "clamp/abs/0,0/10,1";
         let _f: Fade = str.parse().unwrap();
+        assert!("clamp/abs/0,0".parse::<Fade>().is_err());
+        assert!("clamp/abs/0,0/x,1".parse::<Fade>().is_err());
+        assert!("clamp".parse::<Fade>().is_err());
 
         //println!("{:#?}", f);
     }
+
+    #[test]
+    fn threshold_test() {
+        let t: Threshold = "mad/2/4".parse().unwrap();
+        assert!(!t.absolute());
+        assert_eq!(t.estimator(), &ScaleEstimator::Mad);
+        assert_eq!(t.blend_value(3.0), 0.5);
+
+        let t: Threshold = "abs/0.1".parse().unwrap();
+        assert!(t.absolute());
+        assert_eq!(t.min(), 25.5);
+        assert!("abs".parse::<Threshold>().is_err());
+        assert!("mad/x".parse::<Threshold>().is_err());
+    }
+
+    #[test]
+    fn clean_plate_test() {
+        assert_eq!(
+            "frames/0/5".parse::<CleanPlate>(),
+            Ok(CleanPlate::Frames(0, 5))
+        );
+        assert_eq!(
+            "plate.png".parse::<CleanPlate>(),
+            Ok(CleanPlate::Image(PathBuf::from("plate.png")))
+        );
+        assert!("frames/5/5".parse::<CleanPlate>().is_err());
+    }
+
+    #[test]
+    fn sweep_test() {
+        let thresholds: Sweep<Threshold> = "abs/0.02..0.06:0.02/0.1..0.2:0.1".parse().unwrap();
+        let labels: Vec<_> = thresholds
+            .values()
+            .iter()
+            .map(|(s, _)| s.as_str())
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                "abs/0.02/0.1",
+                "abs/0.02/0.2",
+                "abs/0.04/0.1",
+                "abs/0.04/0.2",
+                "abs/0.06/0.1",
+                "abs/0.06/0.2"
+            ]
+        );
+        assert!("abs/0.02..0.06".parse::<Sweep<Threshold>>().is_err());
+
+        let outliers: Sweep<OutlierSelectionMode> = "first".parse().unwrap();
+        let backgrounds: Sweep<BackgroundMode> = "random".parse().unwrap();
+        let sweep = ParameterSweep::new(
+            thresholds.into_values(),
+            outliers.into_values(),
+            backgrounds.into_values(),
+        );
+        let items = sweep.combinations();
+        assert_eq!(items.len(), 6);
+        assert_eq!(
+            items[1].output_path(&PathBuf::from("out/img.png")),
+            PathBuf::from("out/img-001.png")
+        );
+        assert_eq!(
+            items[1].output_path(&PathBuf::from("out/img-{threshold}-{outlier}.png")),
+            PathBuf::from("out/img-abs_0.02_0.2-first.png")
+        );
+    }
 }
@@ -0,0 +1,1288 @@
+//! High-level processing pipeline, from input images to output images or videos.
+use crate::cache::SliceCache;
+use crate::chrono::OutlierProcessor;
+use crate::color::Sample;
+use crate::flist::{FileLister, FrameRange};
+use crate::mask::{Feather, MaskFilter};
+use crate::memory::{MemoryJob, MemoryPlan};
+use crate::options::{
+    parse_default, BackgroundMode, CleanPlate, ColorSpace, Fade, OutlierSelectionMode,
+    ParameterSweep, SelectionMode, Threshold, DEFAULT_BACKGROUND, DEFAULT_COLOR_SPACE,
+    DEFAULT_COMPRESSION, DEFAULT_DELTA, DEFAULT_IN_MEMORY, DEFAULT_MODE, DEFAULT_OUTLIER,
+    DEFAULT_QUALITY, DEFAULT_SLICE, DEFAULT_THRESHOLD, DEFAULT_WEIGHTS,
+};
+use crate::shake::{Crop, ShakeAnalyzer, ShakeReduction};
+use crate::simple::SimpleProcessor;
+use crate::slicer::{MemoryMode, SliceLength, TimeSlicer};
+use crate::streams::{Compression, DeltaEncoding, ImageHeader, ImageStream, TimeSlices};
+use crate::{Error, Result};
+use image::flat::SampleLayout;
+use indicatif::ProgressBar;
+use path_absolutize::*;
+use rayon::prelude::*;
+use std::cmp;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Memory for the output buffers and outlier masks of a batch of video frames, in bytes, if not given by the video batch size.
+const VIDEO_BATCH_MEMORY: usize = 1024 * 1024 * 1024;
+
+/// Processing pipeline from input images to an output image or video.
+/// Runs camera shake reduction, time-slicing, the selected algorithm and parameter sweeps, and saves all outputs.
+///
+/// Created by [`new`](#method.new) with default options, and configured by builder methods.
+/// Alternatively, created from parsed command line arguments.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub(crate) pattern: String,
+    pub(crate) frames: Option<FrameRange>,
+    pub(crate) mode: SelectionMode,
+    pub(crate) threshold: Threshold,
+    pub(crate) outlier: OutlierSelectionMode,
+    pub(crate) background: BackgroundMode,
+    pub(crate) sweep: Option<ParameterSweep>,
+    pub(crate) weights: [f32; 4],
+    pub(crate) color_space: ColorSpace,
+    pub(crate) mask_filters: Vec<MaskFilter>,
+    pub(crate) feather: Option<Feather>,
+    pub(crate) clean_plate: Option<CleanPlate>,
+    pub(crate) fade: Fade,
+    pub(crate) sample: Option<usize>,
+    pub(crate) slice: SliceLength,
+    pub(crate) compression: Compression,
+    pub(crate) delta: DeltaEncoding,
+    pub(crate) in_memory: MemoryMode,
+    pub(crate) cache: bool,
+    pub(crate) temp_dir: Option<PathBuf>,
+    pub(crate) video_in: Option<FrameRange>,
+    pub(crate) video_out: Option<FrameRange>,
+    pub(crate) video_threads: Option<usize>,
+    pub(crate) video_batch: Option<usize>,
+    pub(crate) shake_reduction: Option<ShakeReduction>,
+    pub(crate) shake_threads: Option<usize>,
+    pub(crate) output: PathBuf,
+    pub(crate) output_blend: Option<PathBuf>,
+    pub(crate) output_background: Option<PathBuf>,
+    pub(crate) quality: u8,
+    pub(crate) verbose: bool,
+}
+
+impl Pipeline {
+    /// Creates a pipeline for the images matching a file search pattern, with a single output image.
+    /// All other options have the defaults of the command line application, except that the pipeline is silent.
+    pub fn new(pattern: &str, output: &Path) -> Self {
+        Pipeline {
+            pattern: pattern.to_string(),
+            frames: None,
+            mode: parse_default(DEFAULT_MODE),
+            threshold: parse_default(DEFAULT_THRESHOLD),
+            outlier: parse_default(DEFAULT_OUTLIER),
+            background: parse_default(DEFAULT_BACKGROUND),
+            sweep: None,
+            weights: DEFAULT_WEIGHTS,
+            color_space: parse_default(DEFAULT_COLOR_SPACE),
+            mask_filters: vec![],
+            feather: None,
+            clean_plate: None,
+            fade: Fade::none(),
+            sample: None,
+            slice: parse_default(DEFAULT_SLICE),
+            compression: parse_default(DEFAULT_COMPRESSION),
+            delta: parse_default(DEFAULT_DELTA),
+            in_memory: parse_default(DEFAULT_IN_MEMORY),
+            cache: false,
+            temp_dir: None,
+            video_in: None,
+            video_out: None,
+            video_threads: None,
+            video_batch: None,
+            shake_reduction: None,
+            shake_threads: None,
+            output: output.to_path_buf(),
+            output_blend: None,
+            output_background: None,
+            quality: DEFAULT_QUALITY,
+            verbose: false,
+        }
+    }
+
+    /// Restricts the input images to a range of those matching the pattern.
+    pub fn frames(mut self, frames: FrameRange) -> Self {
+        self.frames = Some(frames);
+        self
+    }
+    /// Sets the pixel selection mode.
+    pub fn mode(mut self, mode: SelectionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    /// Sets the outlier threshold.
+    pub fn threshold(mut self, threshold: Threshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
+    /// Sets the outlier selection mode in case more than one outlier is found.
+    pub fn outlier(mut self, outlier: OutlierSelectionMode) -> Self {
+        self.outlier = outlier;
+        self
+    }
+    /// Sets the background pixel selection mode.
+    pub fn background(mut self, background: BackgroundMode) -> Self {
+        self.background = background;
+        self
+    }
+    /// Sets a parameter sweep, overriding threshold, outlier and background mode.
+    /// Each combination is saved to outputs with the combination's labels appended to the file names.
+    pub fn sweep(mut self, sweep: ParameterSweep) -> Self {
+        self.sweep = Some(sweep);
+        self
+    }
+    /// Sets the color channel weights for distance calculation.
+    pub fn weights(mut self, weights: [f32; 4]) -> Self {
+        self.weights = weights;
+        self
+    }
+    /// Sets the color space for outlier detection.
+    pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+    /// Sets the spatial filters for per-frame outlier masks.
+    pub fn mask_filters(mut self, mask_filters: Vec<MaskFilter>) -> Self {
+        self.mask_filters = mask_filters;
+        self
+    }
+    /// Sets the feathering of the blend mask.
+    pub fn feather(mut self, feather: Feather) -> Self {
+        self.feather = Some(feather);
+        self
+    }
+    /// Sets a clean plate for background and outlier detection.
+    pub fn clean_plate(mut self, clean_plate: CleanPlate) -> Self {
+        self.clean_plate = Some(clean_plate);
+        self
+    }
+    /// Sets frame fading.
+    pub fn fade(mut self, fade: Fade) -> Self {
+        self.fade = fade;
+        self
+    }
+    /// Restricts calculation of median and inter-quartile range to a sub-sample of input images.
+    pub fn sample(mut self, sample: usize) -> Self {
+        self.sample = Some(sample);
+        self
+    }
+    /// Sets slicing, compression and delta encoding of time slices.
+    pub fn slicing(
+        mut self,
+        slice: SliceLength,
+        compression: Compression,
+        delta: DeltaEncoding,
+    ) -> Self {
+        self.slice = slice;
+        self.compression = compression;
+        self.delta = delta;
+        self
+    }
+    /// Sets whether time slices are held in memory instead of temp files.
+    pub fn in_memory(mut self, in_memory: MemoryMode) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+    /// Keeps time slices in a persistent cache in the temp directory, for re-use by later runs.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+    /// Sets the temp directory. Uses a sub-directory of the system temp directory by default.
+    pub fn temp_dir(mut self, temp_dir: &Path) -> Self {
+        self.temp_dir = Some(temp_dir.to_path_buf());
+        self
+    }
+    /// Creates a video instead of a single image, from the frames to be used per video frame (`video_in`),
+    /// and the range of video output frames (`video_out`). A missing range uses all frames.
+    pub fn video(mut self, video_in: Option<FrameRange>, video_out: Option<FrameRange>) -> Self {
+        if video_in.is_some() || video_out.is_some() {
+            self.video_in = Some(video_in.unwrap_or_else(FrameRange::empty));
+            self.video_out = Some(video_out.unwrap_or_else(FrameRange::empty));
+        } else {
+            self.video_in = None;
+            self.video_out = None;
+        }
+        self
+    }
+    /// Sets the number of threads for parallel video frame output.
+    pub fn video_threads(mut self, threads: usize) -> Self {
+        self.video_threads = Some(threads);
+        self
+    }
+    /// Sets the number of video frames rendered in a single pass over the time slices.
+    pub fn video_batch(mut self, batch: usize) -> Self {
+        self.video_batch = Some(batch);
+        self
+    }
+    /// Enables camera shake reduction, optionally with a number of threads for shake analysis.
+    pub fn shake_reduction(mut self, shake: ShakeReduction, threads: Option<usize>) -> Self {
+        self.shake_reduction = Some(shake);
+        self.shake_threads = threads;
+        self
+    }
+    /// Sets the path of an output image showing which pixels are outliers (blend value).
+    pub fn output_blend(mut self, path: &Path) -> Self {
+        self.output_blend = Some(path.to_path_buf());
+        self
+    }
+    /// Sets the path of an output image showing only the background.
+    pub fn output_background(mut self, path: &Path) -> Self {
+        self.output_background = Some(path.to_path_buf());
+        self
+    }
+    /// Sets the output image quality for JPG files, in percent.
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality;
+        self
+    }
+    /// Prints progress and status messages to stdout. Silent by default.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Runs the pipeline, from input images to the output image or video.
+    pub fn run(&self) -> Result<()> {
+        let files = self.list_files()?;
+        let header = ImageHeader::read(&files[0])?;
+        self.validate_image(header.width, header.height, header.channels(), files.len())?;
+
+        let shake = match &self.shake_reduction {
+            Some(red) => Some(ShakeAnalyzer {}.analyze(
+                &files[..],
+                red.anchors(),
+                red.anchor_radius(),
+                red.search_radius(),
+                self.shake_threads,
+                self.verbose,
+            )?),
+            None => None,
+        };
+        let crop: Option<Vec<Crop>> = shake
+            .as_ref()
+            .and_then(|(offset, layout)| Crop::create(&offset[..], layout));
+
+        if self.verbose && self.shake_reduction.is_some() {
+            if crop.is_some() {
+                println!("Camera shake detected. Images will be corrected.");
+            } else {
+                println!("No camera shake detected. Images will not be corrected.");
+            }
+        }
+
+        let is_16bit = header.is_16bit();
+        if self.verbose && is_16bit {
+            println!("Processing 16 bit images.");
+        }
+
+        match (self.mode == SelectionMode::Outlier, is_16bit) {
+            (true, false) => self.run_outliers::<u8>(&files, &crop),
+            (true, true) => self.run_outliers::<u16>(&files, &crop),
+            (false, false) => self.run_simple::<u8>(&files, &crop),
+            (false, true) => self.run_simple::<u16>(&files, &crop),
+        }
+    }
+
+    /// Checks the options that depend on the input images, i.e. shake anchors, channel weights,
+    /// mask filter and feather sizes, and clean plate frames. Reports all problems at once.
+    pub fn validate_image(
+        &self,
+        width: u32,
+        height: u32,
+        channels: usize,
+        images: usize,
+    ) -> Result<()> {
+        let mut problems = vec![];
+        let size = cmp::min(width, height) as usize;
+        let pixels = width as usize * height as usize;
+        for filter in &self.mask_filters {
+            match filter {
+                MaskFilter::Open(radius) | MaskFilter::Close(radius) => {
+                    if *radius == 0 || 2 * *radius as usize + 1 > size {
+                        problems.push(format!(
+                            "`--mask-filter`: expects a radius > 0 that fits into {}x{} pixel images, got {:?}",
+                            width, height, filter
+                        ));
+                    }
+                }
+                MaskFilter::MinArea(area) | MaskFilter::FillHoles(Some(area)) => {
+                    if *area == 0 || *area > pixels {
+                        problems.push(format!(
+                            "`--mask-filter`: expects an area > 0 of at most {} pixels, got {:?}",
+                            pixels, filter
+                        ));
+                    }
+                }
+                MaskFilter::FillHoles(None) => {}
+            }
+        }
+        if let Some(Feather::Guided(radius, _)) = &self.feather {
+            if 2 * radius + 1 > size {
+                problems.push(format!(
+                    "`--feather`: radius {} does not fit into {}x{} pixel images",
+                    radius, width, height
+                ));
+            }
+        }
+        if let Some(CleanPlate::Frames(start, end)) = &self.clean_plate {
+            if *end > images {
+                problems.push(format!(
+                    "`--clean-plate`: frames {} to {} exceed the {} input images",
+                    start, end, images
+                ));
+            }
+        }
+        if let Some(shake) = &self.shake_reduction {
+            let margin = (shake.anchor_radius() + shake.search_radius()) as i32;
+            for (x, y) in shake.anchors() {
+                if *x < margin
+                    || *y < margin
+                    || *x + margin >= width as i32
+                    || *y + margin >= height as i32
+                {
+                    problems.push(format!(
+                        "`--shake-anchors`: anchor {}/{} is closer to the border of {}x{} pixel images than anchor plus search radius ({})",
+                        x, y, width, height, margin
+                    ));
+                }
+            }
+        }
+        let channels = cmp::min(channels, self.weights.len());
+        if self.weights[..channels].iter().all(|w| *w == 0.0) {
+            problems.push(format!(
+                "`--weights`: all weights of the images' {} channels are zero",
+                channels
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(format!(
+                "Invalid options:\n  {}",
+                problems.join("\n  ")
+            )))
+        }
+    }
+
+    /// Chooses slicing, in-memory time slices, threads and video batch size to fit a memory budget in bytes,
+    /// with at most `max_threads` threads. Applies the plan to this pipeline, except for the number of threads
+    /// for time-slicing and processing, which are determined by the current thread pool.
+    /// The plan tells whether the job fits into the budget.
+    pub fn plan_memory(&mut self, budget: usize, max_threads: usize) -> Result<MemoryPlan> {
+        let files = self.list_files()?;
+        let header = ImageHeader::read(&files[0])?;
+        let job = self.memory_job(&header.layout(), header.sample_bytes(), files.len());
+
+        let in_memory = match self.in_memory {
+            _ if self.cache => Some(false),
+            MemoryMode::Always => Some(true),
+            MemoryMode::Never => Some(false),
+            MemoryMode::Auto(_) => None,
+        };
+        let plan = job.plan(
+            budget,
+            max_threads,
+            self.video_batch.unwrap_or(job.frames),
+            in_memory,
+        );
+
+        self.slice = plan.slice.clone();
+        self.in_memory = if plan.in_memory {
+            MemoryMode::Always
+        } else {
+            MemoryMode::Never
+        };
+        self.video_threads = Some(plan.threads);
+        if job.frames > 1 {
+            self.video_batch = Some(plan.video_batch);
+        }
+
+        if !self.verbose {
+            return Ok(plan);
+        }
+        println!(
+            "Memory plan for {} MB: --slice {} ({} time slices), {}, {} threads{}, estimated peak {} MB",
+            budget / (1024 * 1024),
+            plan.slice,
+            plan.slice.count(&header.layout()),
+            if plan.in_memory {
+                "in memory"
+            } else {
+                "temp files"
+            },
+            plan.threads,
+            if job.frames > 1 {
+                format!(", batches of {} frames", plan.video_batch)
+            } else {
+                String::new()
+            },
+            plan.peak.div_ceil(1024 * 1024),
+        );
+        if !plan.fits {
+            println!("Warning: the job does not fit into the memory budget of --max-memory.");
+        }
+        Ok(plan)
+    }
+
+    /// Reports input images, time slices, memory and output files of a run, without decoding images or writing files.
+    /// Returns the report as text, independent of verbosity.
+    pub fn dry_run(&self) -> Result<String> {
+        let files = self.list_files()?;
+        let header = ImageHeader::read(&files[0])?;
+        self.validate_image(header.width, header.height, header.channels(), files.len())?;
+        let mut report = vec![];
+        report.push(format!(
+            "{} images, {}x{} pixels, {:?} ({:?} to {:?})",
+            files.len(),
+            header.width,
+            header.height,
+            header.color,
+            &files[0],
+            &files[files.len() - 1],
+        ));
+
+        // Check that all images have the same layout as the first one
+        let mismatches: Vec<_> = files[1..]
+            .par_iter()
+            .filter_map(|file| match ImageHeader::read(file) {
+                Ok(other) if other == header => None,
+                Ok(other) => Some(format!(
+                    "{:?}: {}x{} pixels, {:?}",
+                    file, other.width, other.height, other.color
+                )),
+                Err(err) => Some(format!("{:?}: {}", file, err)),
+            })
+            .collect();
+        for mismatch in &mismatches {
+            report.push(format!(
+                "Warning: layout differs from first image: {}",
+                mismatch
+            ));
+        }
+        if self.shake_reduction.is_some() {
+            report.push(
+                "Camera shake reduction may crop the images (not analyzed in a dry run)"
+                    .to_string(),
+            );
+        }
+
+        let frames = self
+            .video_ranges()
+            .map(|(video_in, video_out)| video_frames(video_in, video_out, files.len()));
+        if let Some((v_lower, v_upper, windows)) = &frames {
+            let lengths: Vec<_> = windows.iter().map(|(_, indices)| indices.len()).collect();
+            let rendered = lengths.iter().filter(|len| **len > 0).count();
+            report.push(format!(
+                "Video: {} frames ({} skipped), frame range {} to {}, {} to {} images per frame",
+                rendered,
+                windows.len() - rendered,
+                v_lower,
+                v_upper,
+                lengths.iter().filter(|len| **len > 0).min().unwrap_or(&0),
+                lengths.iter().max().unwrap_or(&0),
+            ));
+        }
+
+        if self.mode == SelectionMode::Outlier {
+            let job = self.memory_job(&header.layout(), header.sample_bytes(), files.len());
+            let layout = header.layout();
+            let in_memory = !self.cache && self.in_memory.in_memory(job.slices_bytes());
+            report.push(format!(
+                "Time slices: --slice {} ({} time slices), {} MB before compression, {}",
+                self.slice,
+                self.slice.count(&layout),
+                job.slices_bytes().div_ceil(1024 * 1024),
+                if in_memory {
+                    "in memory".to_string()
+                } else {
+                    format!("temp files in {:?}", temp_dir_path(&self.temp_dir)?)
+                },
+            ));
+            if job.with_masks {
+                report.push(format!(
+                    "Outlier masks: one additional pass over all time slices, {} MB per frame",
+                    job.mask_bytes().div_ceil(1024 * 1024),
+                ));
+            }
+            let threads = rayon::current_num_threads();
+            let batch = match self.video_batch {
+                Some(batch) => cmp::min(batch, job.frames),
+                None => cmp::min(default_video_batch(&job), job.frames),
+            };
+            report.push(format!(
+                "Estimated peak memory: {} MB ({} threads{})",
+                job.peak_bytes(self.slice.samples(&layout), threads, batch, in_memory)
+                    .div_ceil(1024 * 1024),
+                threads,
+                if job.frames > 1 {
+                    format!(", batches of {} frames", batch)
+                } else {
+                    String::new()
+                },
+            ));
+        }
+
+        // Output files, per parameter combination
+        let mut outputs = vec![];
+        match &self.sweep {
+            Some(sweep) if self.mode == SelectionMode::Outlier => {
+                for item in sweep.combinations() {
+                    outputs.push(item.output_path(&self.output));
+                    outputs.extend(self.output_blend.iter().map(|out| item.output_path(out)));
+                    outputs.extend(
+                        self.output_background
+                            .iter()
+                            .map(|out| item.output_path(out)),
+                    );
+                }
+            }
+            _ => {
+                outputs.push(self.output.clone());
+                if self.mode == SelectionMode::Outlier {
+                    outputs.extend(self.output_blend.iter().cloned());
+                    outputs.extend(self.output_background.iter().cloned());
+                }
+            }
+        }
+        report.push("Output files:".to_string());
+        for output in &outputs {
+            match &frames {
+                Some((v_lower, _, windows)) => {
+                    let rendered: Vec<_> = windows
+                        .iter()
+                        .filter(|(_, indices)| !indices.is_empty())
+                        .map(|(frame, _)| frame - v_lower)
+                        .collect();
+                    if let (Some(first), Some(last)) = (rendered.first(), rendered.last()) {
+                        report.push(format!(
+                            "  {:?} to {:?} ({} frames)",
+                            frame_path(output, *first)?,
+                            frame_path(output, *last)?,
+                            rendered.len()
+                        ));
+                    }
+                }
+                None => report.push(format!("  {:?}", output)),
+            }
+        }
+        if !mismatches.is_empty() {
+            report.push(format!(
+                "Warning: {} images differ in layout from the first image, or are unreadable",
+                mismatches.len()
+            ));
+        }
+        Ok(report.join("\n"))
+    }
+
+    /// Lists the input images. Fails if no images match the pattern.
+    fn list_files(&self) -> Result<Vec<PathBuf>> {
+        let files = FileLister::new(&self.pattern, &self.frames).files_vec()?;
+        if files.is_empty() {
+            return Err(Error::Config(format!(
+                "No images found for pattern {:?}",
+                &self.pattern
+            )));
+        }
+        Ok(files)
+    }
+
+    /// The video frame ranges `video_in` and `video_out`, if a video is created.
+    fn video_ranges(&self) -> Option<(&FrameRange, &FrameRange)> {
+        match (&self.video_in, &self.video_out) {
+            (Some(video_in), Some(video_out)) => Some((video_in, video_out)),
+            _ => None,
+        }
+    }
+
+    /// Describes the memory usage of the outlier algorithm, from the layout of the images.
+    fn memory_job(&self, layout: &SampleLayout, sample_bytes: usize, images: usize) -> MemoryJob {
+        let (frames, window, window_shift) = match self.video_ranges() {
+            Some((video_in, video_out)) => {
+                let (_, _, windows) = video_frames(video_in, video_out, images);
+                let windows: Vec<_> = windows
+                    .iter()
+                    .map(|(_, indices)| indices.len())
+                    .filter(|len| *len > 0)
+                    .collect();
+                (
+                    cmp::max(1, windows.len()),
+                    windows.iter().max().copied().unwrap_or(images),
+                    video_out.step() as usize,
+                )
+            }
+            None => (1, images, 1),
+        };
+        MemoryJob {
+            width: layout.width as usize,
+            height: layout.height as usize,
+            channels: layout.channels as usize,
+            sample_bytes,
+            images,
+            frames,
+            window,
+            window_shift,
+            with_background: self.output_background.is_some() || self.feather.is_some(),
+            with_blend: self.feather.is_some(),
+            with_masks: !self.mask_filters.is_empty()
+                || self.outlier == OutlierSelectionMode::Largest,
+            with_plate: self.clean_plate.is_some(),
+        }
+    }
+
+    /// Runs the simple algorithm to image or video
+    fn run_simple<T: Sample>(&self, files: &[PathBuf], crop: &Option<Vec<Crop>>) -> Result<()> {
+        match self.video_ranges() {
+            // Process to video
+            Some((video_in, video_out)) => {
+                self.create_video_simple::<T>(files, crop, video_in, video_out)
+            }
+            // Process to image
+            None => self.create_frame_simple::<T>(files, crop, None, &self.output, self.verbose),
+        }
+    }
+
+    /// Runs the outlier algorithm to image or video
+    fn run_outliers<T: Sample>(&self, files: &[PathBuf], crop: &Option<Vec<Crop>>) -> Result<()> {
+        // Hold time slices in memory if they fit, except for cached time slices
+        let in_memory = !self.cache && self.in_memory.in_memory(estimate_slices_size(files)?);
+
+        let (time_slices, layout, image_count) = if in_memory {
+            if self.verbose {
+                println!("Holding time slices in memory");
+            }
+            self.to_time_slices(crop, T::BYTES == 2, None)?
+        } else {
+            let temp_dir = create_temp_dir(&self.temp_dir, self.verbose)?;
+
+            // Look up time slices in the cache
+            let cache = if self.cache {
+                let description = SliceCache::describe(
+                    files,
+                    crop,
+                    T::BYTES == 2,
+                    &self.compression,
+                    &self.delta,
+                    &self.slice,
+                )?;
+                Some((SliceCache::new(&temp_dir), description))
+            } else {
+                None
+            };
+            let cached = cache
+                .as_ref()
+                .and_then(|(cache, description)| cache.load(description));
+
+            // Convert to time slices and save to temp files
+            match cached {
+                Some(entry) => {
+                    if self.verbose {
+                        println!(
+                            "Using {} cached time slices ({})",
+                            entry.files().len(),
+                            entry.key()
+                        );
+                    }
+                    (
+                        TimeSlices::Files(entry.files().to_vec()),
+                        *entry.layout(),
+                        entry.image_count(),
+                    )
+                }
+                None => {
+                    let slice_dir = match &cache {
+                        Some((cache, description)) => cache.prepare(description)?,
+                        None => temp_dir,
+                    };
+                    let (time_slices, layout, image_count) =
+                        self.to_time_slices(crop, T::BYTES == 2, Some(&slice_dir))?;
+                    if let (Some((cache, description)), Some(temp_files)) =
+                        (&cache, time_slices.files())
+                    {
+                        let entry = cache.store(description, temp_files, &layout, image_count)?;
+                        if self.verbose {
+                            println!("Stored time slices in cache ({})", entry.key());
+                        }
+                    }
+                    (time_slices, layout, image_count)
+                }
+            }
+        };
+
+        // Process each parameter combination from the same time slices
+        match &self.sweep {
+            Some(sweep) => {
+                let mut pipeline = self.clone();
+                for item in sweep.combinations() {
+                    pipeline.threshold = item.threshold.1.clone();
+                    pipeline.outlier = item.outlier.1.clone();
+                    pipeline.background = item.background.1.clone();
+                    pipeline.output = item.output_path(&self.output);
+                    pipeline.output_blend =
+                        self.output_blend.as_ref().map(|out| item.output_path(out));
+                    pipeline.output_background = self
+                        .output_background
+                        .as_ref()
+                        .map(|out| item.output_path(out));
+                    if self.verbose {
+                        println!(
+                            "Sweep {}/{}: --threshold {} --outlier {} --background {} -> {:?}",
+                            item.index + 1,
+                            sweep.len(),
+                            item.threshold.0,
+                            item.outlier.0,
+                            item.background.0,
+                            pipeline.output
+                        );
+                    }
+                    pipeline.create_output::<T>(&time_slices, &layout, image_count)?;
+                }
+            }
+            None => self.create_output::<T>(&time_slices, &layout, image_count)?,
+        }
+
+        // Keep cached time slices
+        if self.cache {
+            return Ok(());
+        }
+
+        // Delete temp file
+        let temp_files = match time_slices.files() {
+            Some(files) => files,
+            None => return Ok(()),
+        };
+        if self.verbose {
+            println!("Deleting {} time slices", temp_files.len());
+        }
+        let bar = ProgressBar::new(temp_files.len() as u64);
+        bar.set_draw_delta((temp_files.len() / 200) as u64);
+        for file in temp_files {
+            if self.verbose {
+                bar.inc(1);
+            }
+            if let Err(err) = std::fs::remove_file(file) {
+                if self.verbose {
+                    println!("Unable to delete file {:?}: {}", file, err);
+                }
+            }
+        }
+        if self.verbose {
+            bar.finish_and_clear();
+        }
+        Ok(())
+    }
+
+    /// Processes time slices to a video or to an image
+    fn create_output<T: Sample>(
+        &self,
+        time_slices: &TimeSlices,
+        layout: &SampleLayout,
+        image_count: usize,
+    ) -> Result<()> {
+        match self.video_ranges() {
+            // Process to video
+            Some((video_in, video_out)) => {
+                self.create_video::<T>(time_slices, layout, image_count, video_in, video_out)
+            }
+            // Process to image
+            None => self.create_frame::<T>(time_slices, layout, image_count),
+        }
+    }
+
+    /// Runs the outlier algorithm to video.
+    /// Frames are rendered in batches, with a single pass over the time slices per batch.
+    fn create_video<T: Sample>(
+        &self,
+        time_slices: &TimeSlices,
+        layout: &SampleLayout,
+        image_count: usize,
+        video_in: &FrameRange,
+        video_out: &FrameRange,
+    ) -> Result<()> {
+        let (v_lower, v_upper, windows) = video_frames(video_in, video_out, image_count);
+        let all_frames: Vec<_> = windows
+            .into_iter()
+            .filter(|(frame, indices)| {
+                if self.verbose && indices.is_empty() {
+                    println!("Skipping frame {}/{}", frame - v_lower, v_upper - v_lower);
+                }
+                !indices.is_empty()
+            })
+            .collect();
+
+        let batch_size = match self.video_batch {
+            Some(batch) => batch,
+            None => default_video_batch(&self.memory_job(layout, T::BYTES, image_count)),
+        };
+
+        thread_pool(self.video_threads)?.install(|| {
+            for batch in all_frames.chunks(batch_size) {
+                if self.verbose {
+                    println!(
+                        "Processing frames {} to {} of {}",
+                        batch[0].0 - v_lower,
+                        batch[batch.len() - 1].0 - v_lower,
+                        v_upper - v_lower
+                    );
+                }
+                let indices: Vec<&[usize]> =
+                    batch.iter().map(|(_, indices)| &indices[..]).collect();
+                let outputs = self.create_processor().process_frames::<T>(
+                    layout,
+                    time_slices,
+                    &self.slice,
+                    Some(image_count),
+                    &indices,
+                    self.output_background.is_some(),
+                    self.verbose,
+                )?;
+
+                if self.verbose {
+                    println!("Saving {} frames", batch.len());
+                }
+                batch.par_iter().zip(outputs.par_iter()).try_for_each(
+                    |((frame, _), (buff, is_outlier, background))| {
+                        self.save_outputs(
+                            layout,
+                            buff,
+                            is_outlier,
+                            background.as_deref(),
+                            &frame_path(&self.output, frame - v_lower)?,
+                            &self
+                                .output_blend
+                                .as_ref()
+                                .map(|out| frame_path(out, frame - v_lower))
+                                .transpose()?,
+                            &self
+                                .output_background
+                                .as_ref()
+                                .map(|out| frame_path(out, frame - v_lower))
+                                .transpose()?,
+                        )
+                    },
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs the simple algorithm to video
+    fn create_video_simple<T: Sample>(
+        &self,
+        files: &[PathBuf],
+        crop: &Option<Vec<Crop>>,
+        video_in: &FrameRange,
+        video_out: &FrameRange,
+    ) -> Result<()> {
+        let (v_lower, v_upper, windows) = video_frames(video_in, video_out, files.len());
+        thread_pool(self.video_threads)?.install(|| {
+            windows.par_iter().try_for_each(|(frame, indices)| {
+                if !indices.is_empty() {
+                    let output = frame_path(&self.output, frame - v_lower)?;
+                    if self.verbose {
+                        println!(
+                            "Processing frame {}/{} -> ",
+                            frame - v_lower,
+                            v_upper - v_lower
+                        );
+                    }
+                    self.create_frame_simple::<T>(files, crop, Some(&indices[..]), &output, false)
+                } else {
+                    if self.verbose {
+                        println!("Skipping frame {}/{}", frame - v_lower, v_upper - v_lower);
+                    }
+                    Ok(())
+                }
+            })
+        })
+    }
+
+    /// Runs the outlier algorithm to image
+    fn create_frame<T: Sample>(
+        &self,
+        time_slices: &TimeSlices,
+        layout: &SampleLayout,
+        image_count: usize,
+    ) -> Result<()> {
+        // Process time slices
+        let (buff, is_outlier, background) = self.create_processor().process::<T>(
+            layout,
+            time_slices,
+            &self.slice,
+            Some(image_count),
+            None,
+            self.output_background.is_some(),
+            self.verbose,
+        )?;
+
+        if self.verbose {
+            println!("Saving output... ");
+        }
+        self.save_outputs(
+            layout,
+            &buff,
+            &is_outlier,
+            background.as_deref(),
+            &self.output,
+            &self.output_blend,
+            &self.output_background,
+        )
+    }
+
+    /// Runs the simple algorithm to image
+    fn create_frame_simple<T: Sample>(
+        &self,
+        files: &[PathBuf],
+        crop: &Option<Vec<Crop>>,
+        image_indices: Option<&[usize]>,
+        output: &PathBuf,
+        show_progress: bool,
+    ) -> Result<()> {
+        // Process time slices
+        let processor = SimpleProcessor::new(
+            self.weights,
+            self.fade.clone(),
+            self.mode == SelectionMode::Darker,
+        );
+        let (buff, layout) = processor.process::<T>(files, crop, image_indices, show_progress)?;
+
+        if show_progress {
+            println!("Saving output... ");
+        }
+        save_image(&buff, &layout, output, self.quality)
+    }
+
+    /// Creates the outlier processor from the options
+    fn create_processor(&self) -> OutlierProcessor {
+        OutlierProcessor::new(
+            self.threshold.clone(),
+            self.background.clone(),
+            self.outlier.clone(),
+            self.weights,
+            self.color_space.clone(),
+            self.mask_filters.clone(),
+            self.feather.clone(),
+            self.clean_plate.clone(),
+            self.fade.clone(),
+            self.sample,
+        )
+    }
+
+    /// Saves the output image, and the blend and background images if requested
+    #[allow(clippy::too_many_arguments)]
+    fn save_outputs<T: Sample>(
+        &self,
+        layout: &SampleLayout,
+        buff: &[T],
+        is_outlier: &[T],
+        background: Option<&[T]>,
+        output: &PathBuf,
+        output_blend: &Option<PathBuf>,
+        output_background: &Option<PathBuf>,
+    ) -> Result<()> {
+        save_image(buff, layout, output, self.quality)?;
+        if let Some(out) = &output_blend {
+            save_image(is_outlier, layout, out, self.quality)?;
+        }
+        if let (Some(out), Some(background)) = (&output_background, background) {
+            save_image(background, layout, out, self.quality)?;
+        }
+        Ok(())
+    }
+
+    /// Time-slices images, into temp files in the given directory, or in memory if no directory is given.
+    fn to_time_slices(
+        &self,
+        crop: &Option<Vec<Crop>>,
+        is_16bit: bool,
+        temp_path: Option<&PathBuf>,
+    ) -> Result<(TimeSlices, SampleLayout, usize)> {
+        let images = ImageStream::from_pattern(&self.pattern, &self.frames)?;
+        let (time_slices, layout, image_count) = match (temp_path, is_16bit) {
+            (Some(temp_path), false) => {
+                let (files, layout, image_count) = TimeSlicer::new_8bit().write_time_slices(
+                    images,
+                    crop,
+                    temp_path.clone(),
+                    &self.compression,
+                    &self.delta,
+                    &self.slice,
+                    self.verbose,
+                )?;
+                (TimeSlices::Files(files), layout, image_count)
+            }
+            (Some(temp_path), true) => {
+                let (files, layout, image_count) = TimeSlicer::new_16bit().write_time_slices(
+                    images,
+                    crop,
+                    temp_path.clone(),
+                    &self.compression,
+                    &self.delta,
+                    &self.slice,
+                    self.verbose,
+                )?;
+                (TimeSlices::Files(files), layout, image_count)
+            }
+            (None, false) => TimeSlicer::new_8bit().memory_time_slices(
+                images,
+                crop,
+                &self.slice,
+                self.verbose,
+            )?,
+            (None, true) => TimeSlicer::new_16bit().memory_time_slices(
+                images,
+                crop,
+                &self.slice,
+                self.verbose,
+            )?,
+        };
+        Ok((time_slices, layout, image_count))
+    }
+}
+
+/// Determines the temp directory, without creating it. Uses a sub-directory of the system temp directory if `None`.
+fn temp_dir_path(temp_dir: &Option<PathBuf>) -> Result<PathBuf> {
+    let temp_dir = match temp_dir {
+        Some(dir) => dir.clone(),
+        None => std::env::temp_dir().join("chrono-photo"),
+    };
+    Ok(temp_dir.absolutize()?.to_path_buf())
+}
+
+/// Determines and creates the temp directory. Uses a sub-directory of the system temp directory if `None`.
+pub fn create_temp_dir(temp_dir: &Option<PathBuf>, show_progress: bool) -> Result<PathBuf> {
+    let temp_dir = temp_dir_path(temp_dir)?;
+    if show_progress {
+        print!("Temp directory: {:?}", temp_dir);
+    }
+
+    // Create temp dir (only 1 level of creation depth)
+    let created = !temp_dir.is_dir();
+    if created {
+        std::fs::create_dir(&temp_dir)?;
+    }
+    if show_progress {
+        println!("{}", if created { " -> created." } else { "" });
+    }
+    Ok(temp_dir)
+}
+
+/// Builds a thread pool with the given number of threads, or the number of processors.
+fn thread_pool(threads: Option<usize>) -> Result<rayon::ThreadPool> {
+    Ok(match threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new().num_threads(threads),
+        None => rayon::ThreadPoolBuilder::new(),
+    }
+    .build()?)
+}
+
+/// The video frames of `video_out`, with the image indices of `video_in` used for each frame (empty for skipped frames).
+/// Returns the lower and upper bound of the frame range, and the frames.
+fn video_frames(
+    video_in: &FrameRange,
+    video_out: &FrameRange,
+    image_count: usize,
+) -> (i32, i32, Vec<(i32, Vec<usize>)>) {
+    let v_lower = match video_out.start() {
+        Some(start) => start,
+        None => {
+            if let Some(r) = video_in.range() {
+                -r + 1
+            } else {
+                0
+            }
+        }
+    };
+
+    let v_upper = match video_out.end() {
+        Some(end) => end,
+        None => image_count as i32,
+    };
+
+    let all_frames = (0..((v_upper - v_lower) / video_out.step() as i32))
+        .map(|i| i * video_out.step() as i32 + v_lower)
+        .map(|frame| (frame, video_window(video_in, frame, image_count)))
+        .collect();
+    (v_lower, v_upper, all_frames)
+}
+
+/// The number of video frames per batch if not given, from the memory of output buffers and outlier masks per frame.
+fn default_video_batch(job: &MemoryJob) -> usize {
+    cmp::max(1, VIDEO_BATCH_MEMORY / job.frame_bytes())
+}
+
+/// The image indices used for the given video frame.
+fn video_window(frames: &FrameRange, frame: i32, image_count: usize) -> Vec<usize> {
+    let start = match frames.start() {
+        Some(s) => {
+            let mut st = frame + s;
+            while st < 0 {
+                st += frames.step() as i32
+            }
+            cmp::max(st % frames.step() as i32, frame + s)
+        }
+        None => 0,
+    };
+    let end = match frames.end() {
+        Some(e) => cmp::min(
+            image_count as i32 + (frame + e) % frames.step() as i32 - frames.step() as i32,
+            frame + e,
+        ),
+        None => image_count as i32,
+    };
+
+    let mut indices = Vec::new();
+    let mut f = start;
+    while f < end {
+        indices.push(f as usize);
+        f += frames.step() as i32;
+    }
+    indices
+}
+
+/// Output path for a video frame, with the frame number appended to the file name.
+fn frame_path(path: &PathBuf, frame: i32) -> Result<PathBuf> {
+    let format_error = || Error::Config(format!("Unexpected format in {:?}", path));
+    let (name, ext) = name_and_extension(path).ok_or_else(format_error)?;
+    let mut output = path.parent().ok_or_else(format_error)?.to_path_buf();
+    output.push(format!("{}-{:05}.{}", name, frame, ext));
+    Ok(output)
+}
+
+fn name_and_extension(path: &Path) -> Option<(String, String)> {
+    let stem = path.file_stem()?.to_str()?;
+    let ext = path.extension()?.to_str()?;
+    Some((stem.to_string(), ext.to_string()))
+}
+
+/// Saves an image buffer to a file. 16 bit images are converted to 8 bit for JPEG output.
+pub fn save_image<T: Sample>(
+    buffer: &[T],
+    layout: &SampleLayout,
+    out_path: &PathBuf,
+    quality: u8,
+) -> Result<()> {
+    let abs_path = out_path.absolutize()?;
+
+    let ext = abs_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "Expects a Unicode extension for output file {:?} to determine image format.",
+                out_path
+            ))
+        })?
+        .to_lowercase();
+
+    let parent = abs_path
+        .parent()
+        .ok_or_else(|| Error::Config(format!("Not a valid output path: {:?}", abs_path)))?;
+
+    if !parent.is_dir() {
+        std::fs::create_dir(parent)?;
+    }
+
+    if ext == "jpg" || ext == "jpeg" {
+        let mut file = File::create(&abs_path)?;
+        let bytes: Vec<u8> = buffer.iter().map(|v| v.as_u8()).collect();
+        let mut enc = image::jpeg::JPEGEncoder::new_with_quality(&mut file, quality);
+        enc.encode(
+            &bytes,
+            layout.width,
+            layout.height,
+            u8::color_type(layout.width_stride),
+        )
+        .map_err(|err| Error::image(&abs_path, err))
+    } else {
+        image::save_buffer(
+            &abs_path,
+            &T::to_native_bytes(buffer),
+            layout.width,
+            layout.height,
+            T::color_type(layout.width_stride),
+        )
+        .map_err(|err| Error::image(&abs_path, err))
+    }
+}
+
+/// Estimates the size of the uncompressed time slices of all images, in bytes, from the first image.
+fn estimate_slices_size(files: &[PathBuf]) -> Result<usize> {
+    let header = ImageHeader::read(&files[0])?;
+    Ok(header.width as usize
+        * header.height as usize
+        * header.color.bytes_per_pixel() as usize
+        * files.len())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::flist::FrameRange;
+    use crate::mask::{Feather, MaskFilter};
+    use crate::options::{CleanPlate, ColorSpace, OutlierSelectionMode, SelectionMode};
+    use crate::pipeline::{video_frames, Pipeline};
+    use crate::shake::ShakeReduction;
+    use crate::slicer::{MemoryMode, SliceLength};
+    use crate::streams::{Compression, DeltaEncoding};
+    use std::path::Path;
+
+    #[test]
+    fn pipeline_test() {
+        let pipeline = Pipeline::new("*.jpg", Path::new("out.png"));
+        assert_eq!(pipeline.mode, SelectionMode::Outlier);
+        assert_eq!(pipeline.outlier, OutlierSelectionMode::Extreme);
+        assert_eq!(pipeline.color_space, ColorSpace::Rgb);
+        assert_eq!(pipeline.slice, SliceLength::Rows(4));
+        assert_eq!(pipeline.compression, Compression::GZip(6));
+        assert_eq!(pipeline.delta, DeltaEncoding::None);
+        assert_eq!(pipeline.in_memory, MemoryMode::Auto(1024));
+
+        let pipeline = Pipeline::new("*.jpg", Path::new("out.png"))
+            .shake_reduction(ShakeReduction::new(vec![(10, 10), (50, 50)], 5, 5), None)
+            .video(Some("0/5/1".parse().unwrap()), None);
+        assert!(pipeline.validate_image(100, 100, 3, 10).is_ok());
+        assert!(pipeline.validate_image(100, 59, 3, 10).is_err());
+
+        let (video_in, video_out) = pipeline.video_ranges().unwrap();
+        let (v_lower, v_upper, frames) = video_frames(video_in, video_out, 10);
+        assert_eq!((v_lower, v_upper, frames.len()), (-4, 10, 14));
+        assert_eq!(frames[0], (-4, vec![0]));
+        assert_eq!(frames[10], (6, vec![6, 7, 8]));
+        assert_eq!(
+            video_frames(&FrameRange::empty(), video_out, 10).2[6],
+            (6, (0..10).collect())
+        );
+
+        let pipeline = Pipeline::new("*.jpg", Path::new("out.png"))
+            .weights([0.0, 0.0, 0.0, 1.0])
+            .shake_reduction(ShakeReduction::new(vec![(10, 10)], 5, 5), None);
+        assert!(pipeline.validate_image(100, 100, 4, 10).is_ok());
+        assert!(pipeline.validate_image(100, 100, 3, 10).is_err());
+
+        let pipeline = Pipeline::new("*.jpg", Path::new("out.png"))
+            .mask_filters(vec![MaskFilter::Open(2), MaskFilter::MinArea(1000)])
+            .feather(Feather::Guided(8, 0.01))
+            .clean_plate(CleanPlate::Frames(0, 10));
+        assert!(pipeline.validate_image(100, 100, 3, 10).is_ok());
+        let message = pipeline
+            .validate_image(10, 10, 3, 5)
+            .unwrap_err()
+            .to_string();
+        for option in &["--mask-filter", "--feather", "--clean-plate"] {
+            assert!(message.contains(&format!("`{}`", option)), "{}", message);
+        }
+        let pipeline = Pipeline::new("*.jpg", Path::new("out.png"))
+            .mask_filters(vec![MaskFilter::Close(0), MaskFilter::FillHoles(Some(0))]);
+        let message = pipeline
+            .validate_image(100, 100, 3, 10)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(message.matches("`--mask-filter`").count(), 2, "{}", message);
+    }
+}
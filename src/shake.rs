@@ -1,6 +1,8 @@
 //! Camera shake correction
 
-use crate::ParseOptionError;
+use crate::color::Sample;
+use crate::streams::open_image;
+use crate::{Error, ParseOptionError, Result};
 use image;
 use image::flat::SampleLayout;
 use image::{DynamicImage, FlatSamples};
@@ -66,16 +68,9 @@ impl FromStr for ShakeParams {
                 str
             )));
         }
-        let rad = parts
-            .get(0)
-            .unwrap()
-            .parse()
-            .expect(&format!("Unexpected format in shake parameter: {}", str));
-        let search_rad = parts
-            .get(1)
-            .unwrap()
-            .parse()
-            .expect(&format!("Unexpected format in shake parameter: {}", str));
+        let error = || ParseOptionError(format!("Unexpected format in shake parameter: {}", str));
+        let rad = parts[0].parse().map_err(|_| error())?;
+        let search_rad = parts[1].parse().map_err(|_| error())?;
 
         Ok(ShakeParams {
             anchor_radius: rad,
@@ -105,14 +100,14 @@ impl FromStr for ShakeAnchor {
                 str
             )));
         }
-        let x = parts.get(0).unwrap().parse().expect(&format!(
-            "Unexpected format in shake anchor, expected x/y: {}",
-            str
-        ));
-        let y = parts.get(1).unwrap().parse().expect(&format!(
-            "Unexpected format in shake anchor, expected x/y: {}",
-            str
-        ));
+        let error = || {
+            ParseOptionError(format!(
+                "Unexpected format in shake anchor, expected x/y: {}",
+                str
+            ))
+        };
+        let x = parts[0].parse().map_err(|_| error())?;
+        let y = parts[1].parse().map_err(|_| error())?;
 
         Ok(ShakeAnchor { anchor: (x, y) })
     }
@@ -195,7 +190,46 @@ impl ShakeAnalyzer {
         search_radius: u32,
         threads: Option<usize>,
         show_progress: bool,
-    ) -> image::ImageResult<(Vec<(i32, i32)>, SampleLayout)> {
+    ) -> Result<(Vec<(i32, i32)>, SampleLayout)> {
+        let first = files
+            .first()
+            .ok_or_else(|| Error::Config("No images to analyze for camera shake".to_string()))?;
+        let first_image = open_image(first)?;
+        if first_image.as_flat_samples_u16().is_some() {
+            self.analyze_samples::<u16>(
+                first_image,
+                files,
+                anchors,
+                anchor_radius,
+                search_radius,
+                threads,
+                show_progress,
+            )
+        } else {
+            self.analyze_samples::<u8>(
+                first_image,
+                files,
+                anchors,
+                anchor_radius,
+                search_radius,
+                threads,
+                show_progress,
+            )
+        }
+    }
+
+    /// Analyze images with samples of type `T`, with the already opened first image.
+    #[allow(clippy::too_many_arguments)]
+    fn analyze_samples<T: Sample>(
+        &self,
+        first_image: DynamicImage,
+        files: &[PathBuf],
+        anchors: &[(i32, i32)],
+        anchor_radius: u32,
+        search_radius: u32,
+        threads: Option<usize>,
+        show_progress: bool,
+    ) -> Result<(Vec<(i32, i32)>, SampleLayout)> {
         let size = (2 * anchor_radius + 1) as i32;
         let search_size = (2 * search_radius + 1) as i32;
         let window_len = (size * size) as usize;
@@ -212,30 +246,21 @@ impl ShakeAnalyzer {
             Some(threads) => rayon::ThreadPoolBuilder::new().num_threads(threads),
             None => rayon::ThreadPoolBuilder::new(),
         }
-        .build()
-        .expect("Unable to build thread pool.");
+        .build()?;
 
-        let first_image = image::open(&files[0])?;
         let (windows, layout) = {
-            let lay = first_image
-                .as_flat_samples_u8()
-                .expect(&format!(
-                    "Problem converting image {:?}: not 8 bits per channel",
-                    &files[0]
+            let first_samples = T::flat_samples(&first_image).ok_or_else(|| {
+                Error::Layout(format!(
+                    "Problem converting image {:?}: not {} bits per channel",
+                    &files[0],
+                    T::BYTES * 8
                 ))
-                .layout;
+            })?;
+            let lay = first_samples.layout;
             let ch = lay.width_stride;
-            let mut wins = vec![0; anchors.len() * window_len * ch];
+            let mut wins = vec![T::zero(); anchors.len() * window_len * ch];
 
-            self.fill_windows(
-                &first_image.as_flat_samples_u8().expect(&format!(
-                    "Problem converting image {:?}: not 8 bits per channel",
-                    &files[0]
-                )),
-                anchors,
-                &mut wins,
-                anchor_radius,
-            );
+            self.fill_windows(&first_samples, anchors, &mut wins, anchor_radius)?;
             (wins, lay)
         };
 
@@ -243,11 +268,11 @@ impl ShakeAnalyzer {
             files
                 .par_iter()
                 .skip(1)
-                .map(|file| -> image::ImageResult<(i32, i32)> {
+                .map(|file| -> Result<(i32, i32)> {
                     if show_progress {
                         bar.inc(1);
                     }
-                    let image = image::open(file)?;
+                    let image = open_image(file)?;
                     /*match &windows {
                         Some(_) => {}
                         None => {
@@ -281,16 +306,19 @@ impl ShakeAnalyzer {
 
                     let mut diffs = vec![0; (search_size * search_size) as usize];
                     self.calc_diffs(
-                        &image.as_flat_samples_u8().expect(&format!(
-                            "Problem converting image {:?}: not 8 bits per channel",
-                            file
-                        )),
+                        &T::flat_samples(&image).ok_or_else(|| {
+                            Error::Layout(format!(
+                                "Problem converting image {:?}: not {} bits per channel",
+                                file,
+                                T::BYTES * 8
+                            ))
+                        })?,
                         anchors,
                         &windows[..],
                         &mut diffs[..],
                         anchor_radius,
                         search_radius,
-                    );
+                    )?;
                     let (min_idx, _min_diff) =
                         diffs.iter().enumerate().min_by_key(|(_i, &d)| d).unwrap();
                     let xmin = (min_idx as i32 % search_size) - search_radius as i32;
@@ -312,13 +340,13 @@ impl ShakeAnalyzer {
         Ok((out, layout))
     }
 
-    fn fill_windows(
+    fn fill_windows<T: Sample>(
         &self,
-        image: &FlatSamples<&[u8]>,
+        image: &FlatSamples<&[T]>,
         anchors: &[(i32, i32)],
-        windows: &mut [u8],
+        windows: &mut [T],
         anchor_radius: u32,
-    ) {
+    ) -> Result<()> {
         let size = (2 * anchor_radius + 1) as i32;
         let channels = image.layout.width_stride;
         let win_len = (size * size * channels as i32) as usize;
@@ -330,51 +358,46 @@ impl ShakeAnalyzer {
                 for dx in 0..size {
                     let xx = *cx + dx - anchor_radius as i32;
                     let idx = (dy * size + dx) * channels as i32;
-                    let idx_image = image
-                        .layout
-                        .index(0, xx as u32, yy as u32)
-                        .expect(&format!("Image coordinate out of range: {:?}", (xx, yy)));
+                    let idx_image = image_index(image, xx, yy)?;
                     for ch in 0..channels {
                         win[idx as usize + ch] = image.samples[idx_image + ch];
                     }
                 }
             }
         }
+        Ok(())
     }
 
-    fn calc_diffs(
+    fn calc_diffs<T: Sample>(
         &self,
-        image: &FlatSamples<&[u8]>,
+        image: &FlatSamples<&[T]>,
         anchors: &[(i32, i32)],
-        windows: &[u8],
-        diff: &mut [i32],
+        windows: &[T],
+        diff: &mut [i64],
         anchor_radius: u32,
         search_radius: u32,
-    ) {
+    ) -> Result<()> {
         let size = (2 * anchor_radius + 1) as i32;
         let search_size = (2 * search_radius + 1) as i32;
         let channels = image.layout.width_stride;
         let win_len = (size * size * channels as i32) as usize;
-        for i in 0..diff.len() {
-            diff[i] = 0;
+        for d in diff.iter_mut() {
+            *d = 0;
         }
         for (i, (cx, cy)) in anchors.iter().enumerate() {
             let win = &windows[(i * win_len)..(i * win_len + win_len)];
             for oy in 0..search_size {
                 for ox in 0..search_size {
-                    let diff_idx = (oy * search_size + ox) as i32;
+                    let diff_idx = oy * search_size + ox;
                     for dy in 0..size {
                         let yy = *cy + (oy - search_radius as i32) + dy - anchor_radius as i32;
                         for dx in 0..size {
                             let xx = *cx + (ox - search_radius as i32) + dx - anchor_radius as i32;
                             let idx = (dy * size + dx) * channels as i32;
-                            let idx_image = image
-                                .layout
-                                .index(0, xx as u32, yy as u32)
-                                .expect(&format!("Image coordinate out of range: {:?}", (xx, yy)));
+                            let idx_image = image_index(image, xx, yy)?;
                             for ch in 0..channels {
-                                diff[diff_idx as usize] += (win[idx as usize + ch] as i32
-                                    - image.samples[idx_image + ch] as i32)
+                                diff[diff_idx as usize] += (win[idx as usize + ch].as_u16() as i64
+                                    - image.samples[idx_image + ch].as_u16() as i64)
                                     .pow(2);
                             }
                         }
@@ -382,5 +405,25 @@ impl ShakeAnalyzer {
                 }
             }
         }
+        Ok(())
+    }
+}
+
+/// Index of the first sample of pixel `(x, y)`, or a configuration error if the coordinate is out of the image.
+/// Occurs if shake anchors are too close to the image border, with respect to the anchor and search radius.
+fn image_index<T>(image: &FlatSamples<&[T]>, x: i32, y: i32) -> Result<usize> {
+    if x < 0 || y < 0 {
+        return Err(coordinate_error(x, y));
     }
+    image
+        .layout
+        .index(0, x as u32, y as u32)
+        .ok_or_else(|| coordinate_error(x, y))
+}
+
+fn coordinate_error(x: i32, y: i32) -> Error {
+    Error::Config(format!(
+        "Image coordinate out of range: {:?}. Shake anchors too close to the image border?",
+        (x, y)
+    ))
 }
@@ -1,7 +1,10 @@
 //! Outlier chrono-photo algorithm.
 use crate::color;
+use crate::color::Sample;
 use crate::options::Fade;
 use crate::shake::Crop;
+use crate::streams::open_image;
+use crate::{Error, Result};
 use image::flat::SampleLayout;
 use indicatif::ProgressBar;
 use rayon::prelude::*;
@@ -23,13 +26,14 @@ impl SimpleProcessor {
         }
     }
 
-    pub fn process(
+    /// Processes the given images. Images must have samples of type `T`.
+    pub fn process<T: Sample>(
         self,
         files: &[PathBuf],
         crop: &Option<Vec<Crop>>,
         image_indices: Option<&[usize]>,
         show_progress: bool,
-    ) -> image::ImageResult<(Vec<u8>, SampleLayout)> {
+    ) -> Result<(Vec<T>, SampleLayout)> {
         let samples = match image_indices {
             Some(indices) => indices.len(),
             None => files.len(),
@@ -40,100 +44,98 @@ impl SimpleProcessor {
 
         let mut layout: Option<SampleLayout> = None;
 
-        let mut fun =
-            |sample_idx: usize, image_idx: usize, path: &PathBuf| -> image::ImageResult<()> {
-                let mut image = image::open(path)?;
-                if let Some(crop) = crop {
-                    //println!("{:?}", crop[image_idx]);
-                    image = crop[image_idx].crop(&mut image);
-                }
-                let buff = image
-                    .as_flat_samples_u8()
-                    .expect("Unexpected format. Not an 8 bit image.");
+        let mut fun = |sample_idx: usize, image_idx: usize, path: &PathBuf| -> Result<()> {
+            let mut image = open_image(path)?;
+            if let Some(crop) = crop {
+                //println!("{:?}", crop[image_idx]);
+                image = crop[image_idx].crop(&mut image);
+            }
+            let buff = T::flat_samples(&image).ok_or_else(|| {
+                Error::Layout(format!(
+                    "Unexpected format in {:?}. Not a {} bit image.",
+                    path,
+                    T::BYTES * 8
+                ))
+            })?;
 
-                let frame_offset = match image_indices {
-                    Some(indices) => indices[0] as i32,
-                    None => 0,
-                };
+            let frame_offset = match image_indices {
+                Some(indices) => indices[0] as i32,
+                None => 0,
+            };
 
-                // Prepare data
-                match layout {
-                    Some(lay) => {
-                        if buff.layout != lay {
-                            //println!("{:?} vs. {:?}", buff.layout, lay);
-                            // TODO better error handling
-                            panic!("Image layout does not fit!".to_string());
-                        }
+            // Prepare data
+            match layout {
+                Some(lay) => {
+                    if buff.layout != lay {
+                        return Err(Error::Layout(format!(
+                            "Image {:?} has a different size or color format than the first image",
+                            path
+                        )));
                     }
-                    None => {
-                        layout = Some(buff.layout);
-                        channels = Some(buff.layout.width_stride);
-                        buffer = Some(vec![
-                            0;
-                            buff.layout.height as usize * buff.layout.height_stride
-                        ]);
-                        extreme_value = Some(vec![
-                            if self.darker {
-                                std::f32::MAX
-                            } else {
-                                std::f32::MIN
-                            };
-                            buff.layout.height as usize
-                                * buff.layout.width as usize
-                        ]);
-                    }
-                };
-                let channels = channels.unwrap();
-                let extremes = extreme_value.as_mut().unwrap();
+                }
+                None => {
+                    layout = Some(buff.layout);
+                    channels = Some(buff.layout.width_stride);
+                    buffer = Some(vec![
+                        T::zero();
+                        buff.layout.height as usize * buff.layout.height_stride
+                    ]);
+                    extreme_value = Some(vec![
+                        if self.darker { f32::MAX } else { f32::MIN };
+                        buff.layout.height as usize
+                            * buff.layout.width as usize
+                    ]);
+                }
+            };
+            let channels = channels.unwrap();
+            let extremes = extreme_value.as_mut().unwrap();
 
-                /*for (idx, (out_pix, in_pix)) in buffer
-                    .as_mut()
-                    .unwrap()
-                    .par_chunks_mut(channels)
-                    .zip(buff.samples.par_chunks(channels))
-                    .enumerate()
-                {*/
-                buffer
-                    .as_mut()
-                    .unwrap()
-                    .par_chunks_mut(channels)
-                    .zip(buff.samples.par_chunks(channels))
-                    .zip(extremes.par_iter_mut())
-                    .for_each(|((out_pix, in_pix), extreme)| {
-                        let mut value = 0.0;
-                        for ch in 0..channels {
-                            value += in_pix[ch] as f32 * self.weights[ch];
+            /*for (idx, (out_pix, in_pix)) in buffer
+                .as_mut()
+                .unwrap()
+                .par_chunks_mut(channels)
+                .zip(buff.samples.par_chunks(channels))
+                .enumerate()
+            {*/
+            buffer
+                .as_mut()
+                .unwrap()
+                .par_chunks_mut(channels)
+                .zip(buff.samples.par_chunks(channels))
+                .zip(extremes.par_iter_mut())
+                .for_each(|((out_pix, in_pix), extreme)| {
+                    let mut value = 0.0;
+                    for (sample, weight) in in_pix.iter().zip(&self.weights) {
+                        value += sample.as_f32() * weight;
+                    }
+                    let mut is_extreme = false;
+                    if self.darker {
+                        //if value < extremes[idx] {
+                        if value < *extreme {
+                            is_extreme = true;
                         }
-                        let mut is_extreme = false;
-                        if self.darker {
-                            //if value < extremes[idx] {
-                            if value < *extreme {
-                                is_extreme = true;
-                            }
-                        } else {
-                            //if value > extremes[idx] {
-                            if value > *extreme {
-                                is_extreme = true;
-                            }
+                    } else {
+                        //if value > extremes[idx] {
+                        if value > *extreme {
+                            is_extreme = true;
                         }
-                        if is_extreme {
-                            //extremes[idx] = value;
-                            *extreme = value;
-                            let fade = self.fade(sample_idx as i32, samples as i32, frame_offset);
-                            if fade > 0.0 {
-                                if fade >= 1.0 {
-                                    for ch in 0..channels {
-                                        out_pix[ch] = in_pix[ch];
-                                    }
-                                } else {
-                                    color::blend_into_u8(out_pix, &in_pix, fade);
-                                }
+                    }
+                    if is_extreme {
+                        //extremes[idx] = value;
+                        *extreme = value;
+                        let fade = self.fade(sample_idx as i32, samples as i32, frame_offset);
+                        if fade > 0.0 {
+                            if fade >= 1.0 {
+                                out_pix.copy_from_slice(in_pix);
+                            } else {
+                                color::blend_into(out_pix, in_pix, fade);
                             }
                         }
-                    });
+                    }
+                });
 
-                Ok(())
-            };
+            Ok(())
+        };
 
         match image_indices {
             Some(indices) => {
@@ -164,7 +166,10 @@ impl SimpleProcessor {
             }
         }
 
-        Ok((buffer.unwrap(), layout.unwrap()))
+        match (buffer, layout) {
+            (Some(buffer), Some(layout)) => Ok((buffer, layout)),
+            _ => Err(Error::Config("No images to process".to_string())),
+        }
     }
 
     fn fade(&self, frame: i32, total: i32, offset: i32) -> f32 {
@@ -1,12 +1,14 @@
 //! Converts a series of images by time to images by row. I.e. transposes (x,y) in the cube in (x,y,t) to (x,t).
 
+use crate::color::Sample;
 use crate::shake::Crop;
 use crate::slicer::SliceLength::{Count, Pixels, Rows};
-use crate::streams::{Compression, ImageStream, PixelOutputStream};
-use crate::ParseEnumError;
+use crate::streams::{
+    Compression, DeltaCoder, DeltaEncoding, ImageStream, PixelOutputStream, SliceHeader, TimeSlices,
+};
+use crate::{Error, ParseEnumError, Result};
 use image::flat::SampleLayout;
 use indicatif::ProgressBar;
-use num_traits::PrimInt;
 use rand::Rng;
 use rayon::prelude::*;
 use std::fmt;
@@ -14,15 +16,18 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 const HEX_CHARS: &str = "0123456789abcdef";
+/// Maximum number of time slice files kept open during time-slicing. Above, files are re-opened for each image.
+const MAX_OPEN_FILES: usize = 512;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SliceLength {
     Rows(usize),
     Pixels(usize),
     Count(usize),
 }
 impl SliceLength {
-    pub fn bytes(&self, layout: &SampleLayout) -> usize {
+    /// The number of samples per image in each time slice (i.e. bytes for 8 bit images).
+    pub fn samples(&self, layout: &SampleLayout) -> usize {
         match self {
             Pixels(n) => *n * layout.width_stride,
             Count(n) => {
@@ -31,6 +36,7 @@ impl SliceLength {
             Rows(n) => *n * layout.height_stride,
         }
     }
+    /// The total number of time slices.
     pub fn count(&self, layout: &SampleLayout) -> usize {
         match self {
             Pixels(n) => ((layout.height * layout.width) as f32 / *n as f32).ceil() as usize,
@@ -44,21 +50,23 @@ impl FromStr for SliceLength {
 
     fn from_str(str: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = str.split('/').collect();
-        let opt_str = parts
-            .get(0)
-            .expect(&format!("Unexpected format in {}.", str));
-
-        let number = parts
-            .get(1)
-            .expect(&format!("Unexpected format in {}", str));
-        let value = number
-            .parse()
-            .expect(&format!("Unable to parse slicing numeric part: {}", str));
+        let (opt_str, number) = match parts[..] {
+            [opt_str, number] => (opt_str, number),
+            _ => {
+                return Err(ParseEnumError(format!(
+                    "Unexpected format in {}. Must be (rows|pixels|count)/<number>",
+                    str
+                )))
+            }
+        };
+        let value = number.parse().map_err(|_| {
+            ParseEnumError(format!("Unable to parse slicing numeric part: {}", str))
+        })?;
 
         let s = match opt_str {
-            &"rows" => Rows(value),
-            &"pixels" => Pixels(value),
-            &"count" => Count(value),
+            "rows" => Rows(value),
+            "pixels" => Pixels(value),
+            "count" => Count(value),
             _ => {
                 return Err(ParseEnumError(format!(
                     "Not a valid slicing mode: {}. Must be one of (rows|pixels|count)/<number>",
@@ -69,12 +77,72 @@ impl FromStr for SliceLength {
         Ok(s)
     }
 }
+impl fmt::Display for SliceLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rows(n) => write!(f, "rows/{}", n),
+            Pixels(n) => write!(f, "pixels/{}", n),
+            Count(n) => write!(f, "count/{}", n),
+        }
+    }
+}
+
+/// Decides whether time slices are held in memory instead of temporary files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryMode {
+    /// Time slices are held in memory if their estimated size is at most the given number of megabytes.
+    Auto(usize),
+    /// Time slices are always held in memory.
+    Always,
+    /// Time slices are always written to temporary files.
+    Never,
+}
+impl MemoryMode {
+    /// Checks if time slices with the given (estimated) size in bytes are to be held in memory.
+    pub fn in_memory(&self, bytes: usize) -> bool {
+        match self {
+            MemoryMode::Auto(mb) => bytes <= *mb * 1024 * 1024,
+            MemoryMode::Always => true,
+            MemoryMode::Never => false,
+        }
+    }
+}
+impl FromStr for MemoryMode {
+    type Err = ParseEnumError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = str.split('/').collect();
+        match (parts[0], parts.get(1)) {
+            ("auto", None) => Ok(MemoryMode::Auto(1024)),
+            ("auto", Some(mb)) => mb
+                .parse()
+                .map(MemoryMode::Auto)
+                .map_err(|_| ParseEnumError(format!("Unable to parse memory budget in {}", str))),
+            ("always", None) => Ok(MemoryMode::Always),
+            ("never", None) => Ok(MemoryMode::Never),
+            _ => Err(ParseEnumError(format!(
+                "Not a memory mode: {}. Must be one of (auto[/<mb>]|always|never)",
+                str
+            ))),
+        }
+    }
+}
+
+/// A time slice file during time-slicing.
+struct SliceFile {
+    path: PathBuf,
+    /// The output stream, kept open across images if the number of files permits.
+    stream: Option<PixelOutputStream>,
+    /// The file header. Chunk offsets are collected while writing, for finishing the file.
+    header: SliceHeader,
+    coder: DeltaCoder,
+}
 
 /// Converts a series of images by time to images by row. I.e. transposes (x,y) in the cube in (x,y,t) to (x,t).
 #[allow(dead_code)]
 pub struct TimeSlicer<T>
 where
-    T: PrimInt,
+    T: Sample,
 {
     is_16: bool,
     dummy: T,
@@ -99,20 +167,29 @@ impl TimeSlicer<u16> {
 
 impl<T> TimeSlicer<T>
 where
-    T: PrimInt,
+    T: Sample,
 {
     /// Writes time slices for all images in the given stream, into the given temporary directory.
-    /// Files are named `temp-xxxxx.bin`.
+    /// Files are named `temp-xxxxx.bin`. Each file starts with a header describing its content, followed by
+    /// the delta-encoded (optional) and compressed chunks, and an index of the chunks.
+    /// Files are kept open until all images are written, unless there are more than 512 files.
+    #[allow(clippy::too_many_arguments)]
     pub fn write_time_slices(
         &self,
         images: ImageStream,
         crop: &Option<Vec<Crop>>,
         temp_dir: PathBuf,
         compression: &Compression,
+        delta: &DeltaEncoding,
         slices: &SliceLength,
-    ) -> Result<(Vec<PathBuf>, SampleLayout, usize), TimeSliceError> {
-        assert!(temp_dir.is_dir());
-        let size_hint = images.len();
+        show_progress: bool,
+    ) -> Result<(Vec<PathBuf>, SampleLayout, usize)> {
+        if !temp_dir.is_dir() {
+            return Err(Error::Config(format!(
+                "Temp directory {:?} does not exist",
+                temp_dir
+            )));
+        }
 
         let mut rng = rand::thread_rng();
         let chars: Vec<char> = HEX_CHARS.chars().collect();
@@ -120,33 +197,191 @@ where
             .map(|_| chars[rng.gen_range(0, chars.len())])
             .collect();
 
+        let mut files: Option<Vec<SliceFile>> = None;
+
+        let (layout, size_hint, total_bytes) = self.slice_images(
+            images,
+            crop,
+            slices,
+            show_progress,
+            |img_index, samples, layout, stride, slice_count| {
+                let num_sample = samples.len();
+                let files = files.get_or_insert_with(|| {
+                    (0..slice_count)
+                        .map(|i| {
+                            let mut path = PathBuf::from(&temp_dir);
+                            path.push(format!("temp-{}-{:05}.bin", id, i));
+                            let start = i * stride;
+                            let end = std::cmp::min((i + 1) * stride, num_sample);
+                            let header = SliceHeader {
+                                bits: (T::BYTES * 8) as u8,
+                                compression: compression.clone(),
+                                delta: delta.clone(),
+                                layout: *layout,
+                                slice_index: i,
+                                slice_count,
+                                sample_offset: start,
+                                samples: end - start,
+                                offsets: vec![],
+                            };
+                            SliceFile {
+                                path,
+                                stream: None,
+                                header,
+                                coder: DeltaCoder::new(delta.clone()),
+                            }
+                        })
+                        .collect()
+                });
+                files
+                    .par_iter_mut()
+                    .map(|file| {
+                        let start = file.header.sample_offset;
+                        let end = start + file.header.samples;
+                        let bytes = T::to_bytes(&samples[start..end]);
+
+                        let mut stream = match file.stream.take() {
+                            Some(stream) => stream,
+                            None => PixelOutputStream::new(
+                                &file.path,
+                                compression.clone(),
+                                img_index > 0,
+                            )?,
+                        };
+                        if img_index == 0 {
+                            stream.write_header(&file.header)?;
+                        }
+                        file.header.offsets.push(stream.offset());
+
+                        let len = stream.write_chunk(&file.coder.encode(&bytes))?;
+                        if slice_count <= MAX_OPEN_FILES {
+                            file.stream = Some(stream);
+                        } else {
+                            stream.close()?;
+                        }
+                        Ok(len)
+                    })
+                    .sum::<Result<usize>>()
+            },
+        )?;
+
+        let files: Vec<PathBuf> = files
+            .unwrap_or_default()
+            .into_par_iter()
+            .map(|file| {
+                let stream = match file.stream {
+                    Some(stream) => stream,
+                    None => PixelOutputStream::new(&file.path, compression.clone(), true)?,
+                };
+                stream.finish(&file.header)?;
+                Ok(file.path)
+            })
+            .collect::<Result<_>>()?;
+        if show_progress {
+            println!("Total: {} kb in {} files", total_bytes / 1024, files.len());
+        }
+
+        Ok((files, layout, size_hint))
+    }
+
+    /// Creates uncompressed time slices in memory, for all images in the given stream.
+    pub fn memory_time_slices(
+        &self,
+        images: ImageStream,
+        crop: &Option<Vec<Crop>>,
+        slices: &SliceLength,
+        show_progress: bool,
+    ) -> Result<(TimeSlices, SampleLayout, usize)> {
+        let mut chunks: Vec<Vec<Vec<u8>>> = vec![];
+
+        let (layout, size_hint, total_bytes) = self.slice_images(
+            images,
+            crop,
+            slices,
+            show_progress,
+            |_, samples, _, stride, slice_count| {
+                if chunks.is_empty() {
+                    chunks = (0..slice_count).map(|_| Vec::new()).collect();
+                }
+                let num_sample = samples.len();
+                let total = chunks
+                    .par_iter_mut()
+                    .enumerate()
+                    .map(|(row, slice)| {
+                        let start = row * stride;
+                        let end = std::cmp::min((row + 1) * stride, num_sample);
+                        let bytes = T::to_bytes(&samples[start..end]).into_owned();
+                        let len = bytes.len();
+                        slice.push(bytes);
+                        len
+                    })
+                    .sum::<usize>();
+                Ok(total)
+            },
+        )?;
+        if show_progress {
+            println!(
+                "Total: {} kb in {} time slices in memory",
+                total_bytes / 1024,
+                chunks.len()
+            );
+        }
+
+        Ok((TimeSlices::Memory(chunks), layout, size_hint))
+    }
+
+    /// Iterates the images in the given stream, checks their layout,
+    /// and passes each image's samples to `write`, together with the image layout, the samples per slice and the number of slices.
+    /// Images are decoded in parallel, ahead of writing, with one image per thread.
+    /// `write` returns the number of bytes written.
+    /// Returns the image layout, the number of images and the total number of bytes written.
+    fn slice_images<F>(
+        &self,
+        images: ImageStream,
+        crop: &Option<Vec<Crop>>,
+        slices: &SliceLength,
+        show_progress: bool,
+        mut write: F,
+    ) -> Result<(SampleLayout, usize, usize)>
+    where
+        F: FnMut(usize, &[T], &SampleLayout, usize, usize) -> Result<usize>,
+    {
+        let size_hint = images.len();
+
         let mut layout: Option<SampleLayout> = None;
         let mut slicing: Option<(usize, usize)> = None;
         let mut count = 0;
 
-        let mut files: Option<Vec<(usize, PathBuf)>> = None;
-
-        let mut total_bytes: u32 = 0;
-        let mut total_files = 0;
-        println!("Time-slicing {} images", size_hint);
+        let mut total_bytes = 0;
+        if show_progress {
+            println!("Time-slicing {} images", size_hint);
+        }
         let bar = ProgressBar::new(size_hint as u64);
         bar.set_draw_delta((size_hint / 200) as u64);
-        for (img_index, img) in images.enumerate() {
-            bar.inc(1);
+        for (img_index, img) in images.prefetch(rayon::current_num_threads()).enumerate() {
+            if show_progress {
+                bar.inc(1);
+            }
 
-            let mut dyn_img = img.unwrap();
+            let mut dyn_img = img?;
             if let Some(crop) = crop {
                 dyn_img = crop[img_index].crop(&mut dyn_img);
             }
-            let pix = dyn_img
-                .as_flat_samples_u8()
-                .expect("Unexpected format. Not an 8 bit image.");
+            let pix = T::flat_samples(&dyn_img).ok_or_else(|| {
+                Error::Layout(format!(
+                    "Unexpected format. Not a {} bit image.",
+                    T::BYTES * 8
+                ))
+            })?;
 
             let lay = match layout {
                 Some(lay) => {
                     //println!("{:?} vs. {:?}", pix.layout, lay);
                     if pix.layout != lay {
-                        return Err(TimeSliceError("Image layout does not fit!".to_string()));
+                        return Err(Error::Layout(format!(
+                            "Image {} has a different size or color format than the first image",
+                            img_index
+                        )));
                     }
                     lay
                 }
@@ -156,87 +391,46 @@ where
                 }
             };
 
-            let sli = match slicing {
+            let (slice_samples, slice_count) = match slicing {
                 Some(sl) => sl,
                 None => {
-                    let sl = (slices.bytes(&lay), slices.count(&lay));
+                    let sl = (slices.samples(&lay), slices.count(&lay));
                     slicing = Some(sl);
                     sl
                 }
             };
-            let slice_bytes = sli.0;
-            let slice_count = sli.1;
-            total_files = slice_count;
 
-            if files.is_none() {
-                files = Some(
-                    (0..slice_count)
-                        .map(|i| {
-                            let mut path = PathBuf::from(&temp_dir);
-                            path.push(format!("temp-{}-{:05}.bin", id, i));
-                            (i, path)
-                        })
-                        .collect(),
-                );
-            }
-
-            let stride = slice_bytes;
-            let num_sample = pix.samples.len();
-            total_bytes += files
-                .as_ref()
-                .unwrap()
-                .par_iter()
-                .map(|(row, path)| {
-                    let start = row * stride;
-                    let end = std::cmp::min((row + 1) * stride, num_sample);
-
-                    let mut stream =
-                        PixelOutputStream::new(&path, compression.clone(), img_index > 0)
-                            .expect(&format!("Unable to create file {:?}", path));
-
-                    stream
-                        .write_chunk(&pix.samples[start..end])
-                        .expect(&format!(
-                            "Unable to write chunk to file {:?}",
-                            stream.path()
-                        ))
-                })
-                .sum::<usize>() as u32;
+            total_bytes += write(img_index, pix.samples, &lay, slice_samples, slice_count)?;
             count += 1;
         }
-        bar.finish_and_clear();
-        println!("Total: {} kb in {} files", total_bytes / 1024, total_files);
-
-        /*for stream in out_streams.as_mut().unwrap().iter_mut() {
-            stream.close().unwrap();
-        }*/
+        if show_progress {
+            bar.finish_and_clear();
+        }
 
         if count == 0 {
-            Err(TimeSliceError(
+            Err(Error::Config(
                 "No images found for given pattern".to_string(),
             ))
         } else {
-            Ok((
-                (0..total_files)
-                    .map(|i| {
-                        let mut path = PathBuf::from(&temp_dir);
-                        path.push(format!("temp-{}-{:05}.bin", id, i));
-                        path
-                    })
-                    .collect(),
-                layout.unwrap(),
-                size_hint,
-            ))
+            Ok((layout.unwrap(), size_hint, total_bytes))
         }
     }
 }
 
-/// Error type for failed time-slicing due to wrong data layout.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct TimeSliceError(String);
+#[cfg(test)]
+mod test {
+    use crate::slicer::MemoryMode;
 
-impl fmt::Display for TimeSliceError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+    #[test]
+    fn parse_memory_mode() {
+        assert_eq!("auto".parse(), Ok(MemoryMode::Auto(1024)));
+        assert_eq!("auto/100".parse(), Ok(MemoryMode::Auto(100)));
+        assert_eq!("always".parse(), Ok(MemoryMode::Always));
+        assert_eq!("never".parse(), Ok(MemoryMode::Never));
+        assert!("auto/x".parse::<MemoryMode>().is_err());
+        assert!("sometimes".parse::<MemoryMode>().is_err());
+
+        assert!(MemoryMode::Auto(1).in_memory(1024 * 1024));
+        assert!(!MemoryMode::Auto(1).in_memory(1024 * 1024 + 1));
     }
 }
@@ -1,71 +1,336 @@
 //! Provides an image stream from a list of files, or a (TODO) video file.
+use crate::color::Sample;
 use crate::flist::{FileLister, FrameRange};
-use crate::ParseEnumError;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::{Error, ParseEnumError, Result};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
 use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
-use glob::PatternError;
 use image;
+use image::flat::SampleLayout;
+use rayon::prelude::*;
+use std::borrow::Cow;
 use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::JoinHandle;
 
-#[derive(Clone, Debug)]
+/// Compression of time slice files. Each chunk (i.e. the data of one image in a time slice) is compressed separately.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Compression {
+    /// No compression. Fastest for fast disks.
+    None,
+    /// LZ4 compression. Very fast, with moderate compression.
+    Lz4,
+    /// GZip compression, with level 0 to 9.
     GZip(u32),
+    /// ZLib compression, with level 0 to 9.
     ZLib(u32),
+    /// Deflate compression, with level 0 to 9.
     Deflate(u32),
+    /// Zstandard compression, with level 1 to 22.
+    Zstd(u32),
 }
 impl FromStr for Compression {
     type Err = ParseEnumError;
 
     fn from_str(str: &str) -> Result<Self, Self::Err> {
         let parts: Vec<_> = str.split('/').collect();
-        let str = parts
-            .get(0)
-            .expect(&format!("Unexpected format in compression {}", str));
-        let level = if let Some(num) = parts.get(1) {
-            num.parse()
-                .expect(&format!("Unable to parse compression level in {}", num))
-        } else {
-            6
+        let level = match parts.get(1) {
+            Some(num) => Some(num.parse().map_err(|_| {
+                ParseEnumError(format!("Unable to parse compression level in {}", str))
+            })?),
+            None => None,
         };
 
-        match str {
-            &"gzip" => Ok(Compression::GZip(level)),
-            &"zlib" => Ok(Compression::ZLib(level)),
-            &"deflate" => Ok(Compression::Deflate(level)),
+        match (parts[0], level) {
+            ("none", None) => Ok(Compression::None),
+            ("lz4", None) => Ok(Compression::Lz4),
+            ("gzip", _) => Ok(Compression::GZip(level.unwrap_or(6))),
+            ("zlib", _) => Ok(Compression::ZLib(level.unwrap_or(6))),
+            ("deflate", _) => Ok(Compression::Deflate(level.unwrap_or(6))),
+            ("zstd", _) => Ok(Compression::Zstd(level.unwrap_or(3))),
+            _ => Err(ParseEnumError(format!(
+                "Not a compression: {}. Must be one of (none|lz4|gzip|zlib|deflate|zstd)[/<level>], with a level only for gzip, zlib, deflate and zstd",
+                str
+            ))),
+        }
+    }
+}
+
+impl Compression {
+    /// Code and level, for time slice file headers.
+    fn code(&self) -> (u8, u32) {
+        match self {
+            Compression::None => (0, 0),
+            Compression::Lz4 => (1, 0),
+            Compression::GZip(level) => (2, *level),
+            Compression::ZLib(level) => (3, *level),
+            Compression::Deflate(level) => (4, *level),
+            Compression::Zstd(level) => (5, *level),
+        }
+    }
+    /// Compression from code and level, see [`code`](#method.code).
+    fn from_code(code: u8, level: u32) -> Option<Self> {
+        match code {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::GZip(level)),
+            3 => Some(Compression::ZLib(level)),
+            4 => Some(Compression::Deflate(level)),
+            5 => Some(Compression::Zstd(level)),
+            _ => None,
+        }
+    }
+}
+
+/// Temporal delta encoding of time slice chunks, applied before compression.
+/// Greatly improves compression for static scenes, e.g. from a tripod.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeltaEncoding {
+    /// No delta encoding.
+    None,
+    /// Each chunk is stored as difference to the previous chunk.
+    /// Reading a chunk requires decoding all previous chunks.
+    Previous,
+    /// Each chunk is stored as difference to the last keyframe, with a keyframe every n chunks.
+    /// Reading a chunk requires decoding only the last keyframe.
+    Keyframe(usize),
+}
+impl FromStr for DeltaEncoding {
+    type Err = ParseEnumError;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = str.split('/').collect();
+        match (parts[0], parts.get(1)) {
+            ("none", None) => Ok(DeltaEncoding::None),
+            ("previous", None) => Ok(DeltaEncoding::Previous),
+            ("keyframe", Some(interval)) => match interval.parse() {
+                Ok(interval) if interval > 0 => Ok(DeltaEncoding::Keyframe(interval)),
+                _ => Err(ParseEnumError(format!(
+                    "Unable to parse keyframe interval in {}. Must be a number > 0",
+                    str
+                ))),
+            },
             _ => Err(ParseEnumError(format!(
-                "Not a compression: {}. Must be one of (gzip|zlib|deflate)",
+                "Not a delta encoding: {}. Must be one of (none|previous|keyframe/<interval>)",
                 str
             ))),
         }
     }
 }
 
+impl DeltaEncoding {
+    /// Code and keyframe interval, for time slice file headers.
+    fn code(&self) -> (u8, u32) {
+        match self {
+            DeltaEncoding::None => (0, 0),
+            DeltaEncoding::Previous => (1, 0),
+            DeltaEncoding::Keyframe(interval) => (2, *interval as u32),
+        }
+    }
+    /// Delta encoding from code and keyframe interval, see [`code`](#method.code).
+    fn from_code(code: u8, interval: u32) -> Option<Self> {
+        match (code, interval) {
+            (0, _) => Some(DeltaEncoding::None),
+            (1, _) => Some(DeltaEncoding::Previous),
+            (2, interval) if interval > 0 => Some(DeltaEncoding::Keyframe(interval as usize)),
+            _ => None,
+        }
+    }
+}
+
+/// Delta encoder and decoder for the sequence of chunks of a single time slice.
+/// Differences are calculated byte-wise, with wrapping arithmetic.
+#[derive(Clone, Debug)]
+pub struct DeltaCoder {
+    encoding: DeltaEncoding,
+    index: usize,
+    reference: Vec<u8>,
+}
+impl DeltaCoder {
+    /// Creates a coder, starting at the first chunk.
+    pub fn new(encoding: DeltaEncoding) -> Self {
+        DeltaCoder {
+            encoding,
+            index: 0,
+            reference: vec![],
+        }
+    }
+    /// Checks if any delta encoding is applied.
+    pub fn is_active(&self) -> bool {
+        self.encoding != DeltaEncoding::None
+    }
+    /// Checks if the next chunk is stored as is.
+    fn is_keyframe(&self, len: usize) -> bool {
+        let keyframe = match self.encoding {
+            DeltaEncoding::None => true,
+            DeltaEncoding::Previous => self.index == 0,
+            DeltaEncoding::Keyframe(interval) => self.index % interval == 0,
+        };
+        keyframe || self.reference.len() != len
+    }
+    /// Checks if the next chunk is required for decoding later chunks.
+    pub fn is_reference(&self) -> bool {
+        match self.encoding {
+            DeltaEncoding::None => false,
+            DeltaEncoding::Previous => true,
+            DeltaEncoding::Keyframe(interval) => self.index % interval == 0,
+        }
+    }
+    /// Encodes the next chunk.
+    pub fn encode<'a>(&mut self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        let encoded = if self.is_keyframe(bytes.len()) {
+            Cow::Borrowed(bytes)
+        } else {
+            Cow::Owned(
+                bytes
+                    .iter()
+                    .zip(&self.reference)
+                    .map(|(b, r)| b.wrapping_sub(*r))
+                    .collect(),
+            )
+        };
+        if self.is_reference() {
+            self.reference.clear();
+            self.reference.extend_from_slice(bytes);
+        }
+        self.index += 1;
+        encoded
+    }
+    /// Decodes the next chunk in place.
+    pub fn decode(&mut self, bytes: &mut [u8]) {
+        if !self.is_keyframe(bytes.len()) {
+            for (b, r) in bytes.iter_mut().zip(&self.reference) {
+                *b = b.wrapping_add(*r);
+            }
+        }
+        if self.is_reference() {
+            self.reference.clear();
+            self.reference.extend_from_slice(bytes);
+        }
+        self.index += 1;
+    }
+    /// Skips the next chunk. Must only be used for chunks that are not required as reference.
+    pub fn skip(&mut self) {
+        self.index += 1;
+    }
+    /// The index of the chunk where reading must start to decode chunk `target`,
+    /// when the chunk to be read next is `current`.
+    pub fn seek_start(&self, current: usize, target: usize) -> usize {
+        match self.encoding {
+            DeltaEncoding::None => target,
+            DeltaEncoding::Previous => {
+                if current <= target {
+                    current
+                } else {
+                    0
+                }
+            }
+            DeltaEncoding::Keyframe(interval) => {
+                let keyframe = target - target % interval;
+                if keyframe < current && current <= target {
+                    current
+                } else {
+                    keyframe
+                }
+            }
+        }
+    }
+    /// Moves the coder to the given chunk, as returned by [`seek_start`](#method.seek_start).
+    pub fn seek(&mut self, index: usize) {
+        self.index = index;
+    }
+}
+
+/// Size and color type of an image file, read from its header without decoding the pixel data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageHeader {
+    /// Image width, in pixels.
+    pub width: u32,
+    /// Image height, in pixels.
+    pub height: u32,
+    /// Color type of the decoded image.
+    pub color: image::ColorType,
+}
+impl ImageHeader {
+    /// Reads the header of an image file. Formats other than JPEG, PNG, TIFF and BMP are decoded entirely.
+    pub fn read(path: &PathBuf) -> Result<Self> {
+        Self::read_image(path).map_err(|err| Error::image(path, err))
+    }
+    fn read_image(path: &PathBuf) -> image::ImageResult<Self> {
+        use image::ImageDecoder;
+        let reader =
+            || -> image::ImageResult<BufReader<File>> { Ok(BufReader::new(File::open(path)?)) };
+        let (dimensions, color) = match image::ImageFormat::from_path(path)? {
+            image::ImageFormat::Jpeg => {
+                let decoder = image::jpeg::JpegDecoder::new(reader()?)?;
+                (decoder.dimensions(), decoder.color_type())
+            }
+            image::ImageFormat::Png => {
+                let decoder = image::png::PngDecoder::new(reader()?)?;
+                (decoder.dimensions(), decoder.color_type())
+            }
+            image::ImageFormat::Tiff => {
+                let decoder = image::tiff::TiffDecoder::new(reader()?)?;
+                (decoder.dimensions(), decoder.color_type())
+            }
+            image::ImageFormat::Bmp => {
+                let decoder = image::bmp::BmpDecoder::new(reader()?)?;
+                (decoder.dimensions(), decoder.color_type())
+            }
+            _ => {
+                let image = image::open(path)?;
+                (image::GenericImageView::dimensions(&image), image.color())
+            }
+        };
+        Ok(ImageHeader {
+            width: dimensions.0,
+            height: dimensions.1,
+            color,
+        })
+    }
+    /// The number of channels per pixel.
+    pub fn channels(&self) -> usize {
+        self.color.channel_count() as usize
+    }
+    /// Bytes per sample, i.e. per color channel of a pixel.
+    pub fn sample_bytes(&self) -> usize {
+        (self.color.bytes_per_pixel() / self.color.channel_count()) as usize
+    }
+    /// Checks if the image has 16 bits per color channel.
+    pub fn is_16bit(&self) -> bool {
+        self.sample_bytes() == 2
+    }
+    /// The sample layout of the decoded image.
+    pub fn layout(&self) -> SampleLayout {
+        SampleLayout::row_major_packed(self.color.channel_count(), self.width, self.height)
+    }
+}
+
 /// Provides a stream of images from a file search pattern.
 pub struct ImageStream {
     files: VecDeque<PathBuf>,
 }
 impl ImageStream {
     /// Creates an ImageStream from a file search pattern.
-    pub fn from_pattern(pattern: &str, frames: &Option<FrameRange>) -> Result<Self, PatternError> {
-        let lister = FileLister::new(&pattern, frames);
+    pub fn from_pattern(pattern: &str, frames: &Option<FrameRange>) -> Result<Self> {
+        let lister = FileLister::new(pattern, frames);
         let files = lister.files_vecdeque()?;
         Ok(ImageStream { files })
     }
 }
 impl Iterator for ImageStream {
-    type Item = image::ImageResult<image::DynamicImage>;
+    type Item = Result<image::DynamicImage>;
 
-    fn next(&mut self) -> Option<image::ImageResult<image::DynamicImage>> {
+    fn next(&mut self) -> Option<Result<image::DynamicImage>> {
         if self.files.is_empty() {
             None
         } else {
             let path = self.files.pop_front().unwrap();
-            Some(image::open(&path))
+            Some(open_image(&path))
         }
     }
 }
@@ -74,6 +339,10 @@ impl ImageStream {
     pub fn len(&self) -> usize {
         self.files.len()
     }
+    /// Checks if there are no images in this stream
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
     pub fn skip(&mut self) -> Option<()> {
         if self.files.is_empty() {
             None
@@ -82,17 +351,247 @@ impl ImageStream {
             Some(())
         }
     }
+    /// Decodes the images in a background thread, up to `ahead` images in parallel and ahead of the consumer.
+    /// Images are still returned in order.
+    pub fn prefetch(self, ahead: usize) -> PrefetchStream {
+        let ahead = std::cmp::max(ahead, 1);
+        let len = self.files.len();
+        let (sender, receiver) = sync_channel(ahead);
+        let files: Vec<PathBuf> = self.files.into_iter().collect();
+        let decoder = std::thread::spawn(move || {
+            for batch in files.chunks(ahead) {
+                let images: Vec<_> = batch.par_iter().map(open_image).collect();
+                for image in images {
+                    // The consumer stopped early
+                    if sender.send(image).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        PrefetchStream {
+            receiver,
+            decoder: Some(decoder),
+            len,
+            received: 0,
+        }
+    }
+}
+
+/// A stream of images decoded in a background thread, see [`ImageStream::prefetch`](struct.ImageStream.html#method.prefetch).
+///
+/// Ends with an error if the decoder thread panics or stops before all images are decoded.
+pub struct PrefetchStream {
+    receiver: Receiver<Result<image::DynamicImage>>,
+    decoder: Option<JoinHandle<()>>,
+    len: usize,
+    received: usize,
+}
+impl PrefetchStream {
+    /// The number of images in this stream
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// Checks if there are no images in this stream
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+impl Iterator for PrefetchStream {
+    type Item = Result<image::DynamicImage>;
+
+    fn next(&mut self) -> Option<Result<image::DynamicImage>> {
+        if let Ok(image) = self.receiver.recv() {
+            self.received += 1;
+            return Some(image);
+        }
+        // The decoder thread has finished, regularly or by a panic
+        let message = match self.decoder.take()?.join() {
+            Ok(()) if self.received == self.len => return None,
+            Ok(()) => format!(
+                "Image decoding stopped after {} of {} images",
+                self.received, self.len
+            ),
+            Err(panic) => format!(
+                "Image decoding failed after {} of {} images: {}",
+                self.received,
+                self.len,
+                panic
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default()
+            ),
+        };
+        Some(Err(Error::Io(std::io::Error::other(message))))
+    }
+}
+
+/// Opens and decodes an image file.
+pub fn open_image(path: &PathBuf) -> Result<image::DynamicImage> {
+    image::open(path).map_err(|err| Error::image(path, err))
+}
+
+/// Magic number at the start of each time slice file.
+const SLICE_MAGIC: &[u8; 8] = b"CHRONOTS";
+/// Version of the time slice file format.
+const SLICE_VERSION: u16 = 1;
+/// Size of the time slice file header, in bytes.
+const HEADER_SIZE: usize = 78;
+
+/// Header of a time slice file, describing its content.
+///
+/// A time slice file consists of the header (magic number, format version, the fields of this struct,
+/// number of chunks, offset of the chunk index and a header checksum), followed by one chunk per image
+/// (length, CRC32 checksum and compressed data), followed by the chunk index (the file offset of each chunk,
+/// and a checksum). All numbers are big endian.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceHeader {
+    /// Bits per sample (8 or 16).
+    pub bits: u8,
+    /// Compression of the chunks.
+    pub compression: Compression,
+    /// Delta encoding of the chunks.
+    pub delta: DeltaEncoding,
+    /// Layout of the (cropped) images.
+    pub layout: SampleLayout,
+    /// Index of this time slice.
+    pub slice_index: usize,
+    /// Total number of time slices.
+    pub slice_count: usize,
+    /// Index of the first sample of this time slice in each image.
+    pub sample_offset: usize,
+    /// Number of samples per image (i.e. per chunk).
+    pub samples: usize,
+    /// File offsets of the chunks, one per image.
+    pub offsets: Vec<u64>,
+}
+impl SliceHeader {
+    /// The number of images (chunks) in the time slice file.
+    pub fn frame_count(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Writes the header, with the given offset of the chunk index (0 for unfinished files).
+    fn write<W: Write>(&self, writer: &mut W, index_offset: u64) -> std::io::Result<()> {
+        let (compression, level) = self.compression.code();
+        let (delta, interval) = self.delta.code();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.write_all(SLICE_MAGIC)?;
+        bytes.write_u16::<BigEndian>(SLICE_VERSION)?;
+        bytes.write_u8(self.bits)?;
+        bytes.write_u8(compression)?;
+        bytes.write_u32::<BigEndian>(level)?;
+        bytes.write_u8(delta)?;
+        bytes.write_u32::<BigEndian>(interval)?;
+        bytes.write_u8(self.layout.channels)?;
+        bytes.write_u32::<BigEndian>(self.layout.channel_stride as u32)?;
+        bytes.write_u32::<BigEndian>(self.layout.width)?;
+        bytes.write_u32::<BigEndian>(self.layout.width_stride as u32)?;
+        bytes.write_u32::<BigEndian>(self.layout.height)?;
+        bytes.write_u32::<BigEndian>(self.layout.height_stride as u32)?;
+        bytes.write_u32::<BigEndian>(self.slice_index as u32)?;
+        bytes.write_u32::<BigEndian>(self.slice_count as u32)?;
+        bytes.write_u64::<BigEndian>(self.sample_offset as u64)?;
+        bytes.write_u32::<BigEndian>(self.samples as u32)?;
+        bytes.write_u32::<BigEndian>(self.offsets.len() as u32)?;
+        bytes.write_u64::<BigEndian>(index_offset)?;
+        let crc = crc32(&bytes);
+        bytes.write_u32::<BigEndian>(crc)?;
+        writer.write_all(&bytes)
+    }
+
+    /// Reads the header and the chunk index of a time slice file.
+    /// Leaves the reader at the first chunk.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        reader
+            .read_exact(&mut bytes)
+            .map_err(|_| invalid_data("File too short for a time slice header".to_string()))?;
+        if &bytes[..SLICE_MAGIC.len()] != SLICE_MAGIC {
+            return Err(invalid_data(
+                "Not a time slice file (wrong magic number)".to_string(),
+            ));
+        }
+        let mut r = &bytes[SLICE_MAGIC.len()..];
+        let version = r.read_u16::<BigEndian>()?;
+        if version != SLICE_VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported time slice format version {}, expected {}",
+                version, SLICE_VERSION
+            )));
+        }
+        let crc = BigEndian::read_u32(&bytes[(HEADER_SIZE - 4)..]);
+        if crc32(&bytes[..(HEADER_SIZE - 4)]) != crc {
+            return Err(invalid_data("Header checksum mismatch".to_string()));
+        }
+
+        let bits = r.read_u8()?;
+        let (code, level) = (r.read_u8()?, r.read_u32::<BigEndian>()?);
+        let compression = Compression::from_code(code, level)
+            .ok_or_else(|| invalid_data(format!("Unknown compression code {}", code)))?;
+        let (code, interval) = (r.read_u8()?, r.read_u32::<BigEndian>()?);
+        let delta = DeltaEncoding::from_code(code, interval)
+            .ok_or_else(|| invalid_data(format!("Unknown delta encoding code {}", code)))?;
+        let layout = SampleLayout {
+            channels: r.read_u8()?,
+            channel_stride: r.read_u32::<BigEndian>()? as usize,
+            width: r.read_u32::<BigEndian>()?,
+            width_stride: r.read_u32::<BigEndian>()? as usize,
+            height: r.read_u32::<BigEndian>()?,
+            height_stride: r.read_u32::<BigEndian>()? as usize,
+        };
+        let slice_index = r.read_u32::<BigEndian>()? as usize;
+        let slice_count = r.read_u32::<BigEndian>()? as usize;
+        let sample_offset = r.read_u64::<BigEndian>()? as usize;
+        let samples = r.read_u32::<BigEndian>()? as usize;
+        let frame_count = r.read_u32::<BigEndian>()? as usize;
+        let index_offset = r.read_u64::<BigEndian>()?;
+        if index_offset == 0 {
+            return Err(invalid_data(
+                "Incomplete time slice file, time-slicing was not finished".to_string(),
+            ));
+        }
+
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let mut index = vec![0; frame_count * 8];
+        reader
+            .read_exact(&mut index)
+            .map_err(|_| invalid_data("Chunk index is truncated".to_string()))?;
+        let crc = reader
+            .read_u32::<BigEndian>()
+            .map_err(|_| invalid_data("Chunk index is truncated".to_string()))?;
+        if crc32(&index) != crc {
+            return Err(invalid_data("Chunk index checksum mismatch".to_string()));
+        }
+        let offsets = index.chunks(8).map(BigEndian::read_u64).collect();
+        reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+
+        Ok(SliceHeader {
+            bits,
+            compression,
+            delta,
+            layout,
+            slice_index,
+            slice_count,
+            sample_offset,
+            samples,
+            offsets,
+        })
+    }
 }
 
 pub struct PixelOutputStream {
     path: PathBuf,
     stream: BufWriter<std::fs::File>,
     compression: Compression,
+    /// The current end of the file.
+    position: u64,
 }
 impl PixelOutputStream {
-    pub fn new(path: &PathBuf, compression: Compression, append: bool) -> std::io::Result<Self> {
-        let file = if append {
-            OpenOptions::new().write(true).append(true).open(path)?
+    pub fn new(path: &PathBuf, compression: Compression, append: bool) -> Result<Self> {
+        let mut file = if append {
+            OpenOptions::new().write(true).open(path)?
         } else {
             OpenOptions::new()
                 .write(true)
@@ -100,11 +599,13 @@ impl PixelOutputStream {
                 .create(true)
                 .open(path)?
         };
+        let position = file.seek(SeekFrom::End(0))?;
         let stream = BufWriter::new(file);
         let stream = PixelOutputStream {
             path: path.clone(),
             stream,
             compression,
+            position,
         };
         Ok(stream)
     }
@@ -112,124 +613,540 @@ impl PixelOutputStream {
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
-    pub fn write_chunk(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
-        // TODO: make generic
-        match self.compression {
+    /// Writes the header of a new time slice file. The header is completed by [`finish`](#method.finish).
+    pub fn write_header(&mut self, header: &SliceHeader) -> Result<()> {
+        header.write(&mut self.stream, 0)?;
+        self.position += HEADER_SIZE as u64;
+        Ok(())
+    }
+    /// The current end of the file, i.e. the offset of the next chunk.
+    pub fn offset(&self) -> u64 {
+        self.position
+    }
+    /// Compresses and writes a chunk. Returns the compressed size.
+    pub fn write_chunk(&mut self, bytes: &[u8]) -> Result<usize> {
+        let compressed: Cow<[u8]> = match self.compression {
+            Compression::None => Cow::Borrowed(bytes),
+            Compression::Lz4 => Cow::Owned(lz4_flex::compress_prepend_size(bytes)),
             Compression::GZip(level) => {
                 let mut e = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
                 e.write_all(bytes)?;
-                let compressed = &e.finish()?;
-                self.stream
-                    .write_u32::<BigEndian>(compressed.len() as u32)?;
-                self.stream.write_all(compressed)?;
-                self.stream.flush()?;
-                Ok(compressed.len())
+                Cow::Owned(e.finish()?)
             }
             Compression::ZLib(level) => {
                 let mut e = ZlibEncoder::new(Vec::new(), flate2::Compression::new(level));
                 e.write_all(bytes)?;
-                let compressed = &e.finish()?;
-                self.stream
-                    .write_u32::<BigEndian>(compressed.len() as u32)?;
-                self.stream.write_all(compressed)?;
-                self.stream.flush()?;
-                Ok(compressed.len())
+                Cow::Owned(e.finish()?)
             }
             Compression::Deflate(level) => {
                 let mut e = DeflateEncoder::new(Vec::new(), flate2::Compression::new(level));
                 e.write_all(bytes)?;
-                let compressed = &e.finish()?;
-                self.stream
-                    .write_u32::<BigEndian>(compressed.len() as u32)?;
-                self.stream.write_all(compressed)?;
-                self.stream.flush()?;
-                Ok(compressed.len())
+                Cow::Owned(e.finish()?)
             }
-        }
+            Compression::Zstd(level) => Cow::Owned(zstd::bulk::compress(bytes, level as i32)?),
+        };
+        self.stream
+            .write_u32::<BigEndian>(compressed.len() as u32)?;
+        self.stream.write_u32::<BigEndian>(crc32(&compressed))?;
+        self.stream.write_all(&compressed)?;
+        self.position += 8 + compressed.len() as u64;
+        Ok(compressed.len())
     }
-    pub fn close(&mut self) -> std::io::Result<()> {
-        self.stream.flush()
+    pub fn close(&mut self) -> Result<()> {
+        Ok(self.stream.flush()?)
+    }
+    /// Finishes a time slice file by appending the chunk index and completing the header.
+    pub fn finish(self, header: &SliceHeader) -> Result<()> {
+        let index_offset = self.position;
+        let mut file = self.stream.into_inner().map_err(|err| err.into_error())?;
+        let mut index = Vec::with_capacity(header.offsets.len() * 8 + 4);
+        for offset in &header.offsets {
+            index.write_u64::<BigEndian>(*offset)?;
+        }
+        let crc = crc32(&index);
+        index.write_u32::<BigEndian>(crc)?;
+        file.write_all(&index)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(header.write(&mut file, index_offset)?)
     }
 }
 
 pub struct PixelInputStream {
+    path: PathBuf,
     stream: BufReader<File>,
-    compression: Compression,
+    header: SliceHeader,
+    delta: DeltaCoder,
+    /// Index of the next chunk.
+    next: usize,
+    /// Buffer for compressed chunks, re-used to avoid allocations.
+    buffer: Vec<u8>,
+    /// Buffer for delta-decoded chunks, re-used to avoid allocations.
+    decoded: Vec<u8>,
+    /// Buffer for decompressed chunks of 16 bit samples, re-used to avoid allocations.
+    bytes: Vec<u8>,
 }
 impl PixelInputStream {
-    pub fn new(file: &PathBuf, compression: Compression) -> std::io::Result<Self> {
-        let f = File::open(file)?;
-        //let d = GzDecoder::new(f);
+    /// Opens a time slice file and reads its header.
+    pub fn new(file: &PathBuf) -> Result<Self> {
+        let mut stream = BufReader::new(File::open(file)?);
+        let header = SliceHeader::read(&mut stream)
+            .map_err(|err| invalid_data(format!("Invalid time slice file {:?}: {}", file, err)))?;
         let stream = PixelInputStream {
-            stream: BufReader::new(f),
-            compression,
+            path: file.clone(),
+            stream,
+            delta: DeltaCoder::new(header.delta.clone()),
+            header,
+            next: 0,
+            buffer: Vec::new(),
+            decoded: Vec::new(),
+            bytes: Vec::new(),
         };
         Ok(stream)
     }
-    pub fn read_chunk(&mut self, out: &mut Vec<u8>) -> Option<usize> {
-        let len = match self.stream.read_u32::<BigEndian>() {
-            Ok(l) => l,
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::UnexpectedEof => return None,
-                _ => panic!(err),
-            },
-        };
-        let mut compressed = vec![0_u8; len as usize];
-        if let Err(err) = self.stream.read_exact(&mut compressed) {
-            match err.kind() {
-                std::io::ErrorKind::UnexpectedEof => return None,
-                _ => {}
-            }
+    /// The header of the time slice file.
+    pub fn header(&self) -> &SliceHeader {
+        &self.header
+    }
+    /// Reads the next chunk and appends its samples to `out`.
+    /// Returns the number of samples read, or `None` at the end of the stream.
+    pub fn read_chunk<T: Sample>(&mut self, out: &mut Vec<T>) -> Result<Option<usize>> {
+        if T::BYTES * 8 != self.header.bits as usize {
+            return Err(invalid_data(format!(
+                "Time slice file {:?} contains {} bit samples, expected {} bit",
+                self.path,
+                self.header.bits,
+                T::BYTES * 8
+            )));
         }
-        // TODO: Make generic
-        match self.compression {
-            Compression::GZip(_) => {
-                let mut d = GzDecoder::new(&compressed[..]);
-                let size = d.read_to_end(out).unwrap();
-                Some(size)
+        if self.delta.is_active() {
+            if self.read_decoded()?.is_none() {
+                return Ok(None);
             }
-            Compression::ZLib(_) => {
-                let mut d = ZlibDecoder::new(&compressed[..]);
-                let size = d.read_to_end(out).unwrap();
-                Some(size)
+            Ok(Some(T::from_bytes(&self.decoded, out)))
+        } else {
+            self.read_raw_chunk(out)
+        }
+    }
+    /// Reads all remaining chunks, to verify their checksums and decompression.
+    /// Returns the number of chunks read.
+    pub fn verify(&mut self) -> Result<usize> {
+        let mut count = 0;
+        let mut data: Vec<u8> = vec![];
+        loop {
+            data.clear();
+            let result = if self.delta.is_active() {
+                self.read_decoded()?
+            } else {
+                self.read_raw_chunk(&mut data)?.map(|_| ())
+            };
+            if result.is_none() {
+                return Ok(count);
             }
+            count += 1;
+        }
+    }
+
+    /// Reads and delta-decodes the next chunk into the `decoded` buffer.
+    fn read_decoded(&mut self) -> Result<Option<()>> {
+        let mut decoded = std::mem::take(&mut self.decoded);
+        decoded.clear();
+        let result = self.read_raw_chunk::<u8>(&mut decoded);
+        if let Ok(Some(_)) = result {
+            self.delta.decode(&mut decoded);
+        }
+        self.decoded = decoded;
+        result.map(|size| size.map(|_| ()))
+    }
+
+    /// Reads and decompresses the next chunk, without delta decoding.
+    fn read_raw_chunk<T: Sample>(&mut self, out: &mut Vec<T>) -> Result<Option<usize>> {
+        if self.next >= self.header.frame_count() {
+            return Ok(None);
+        }
+        let chunk = self.next;
+        let path = &self.path;
+        let error = |msg: String| {
+            invalid_data(format!(
+                "Error in chunk {} of time slice file {:?}: {}",
+                chunk, path, msg
+            ))
+        };
+
+        let len = self
+            .stream
+            .read_u32::<BigEndian>()
+            .map_err(|err| error(format!("Unable to read chunk length: {}", err)))?;
+        let crc = self
+            .stream
+            .read_u32::<BigEndian>()
+            .map_err(|err| error(format!("Unable to read chunk checksum: {}", err)))?;
+        self.buffer.resize(len as usize, 0);
+        self.stream
+            .read_exact(&mut self.buffer)
+            .map_err(|err| error(format!("Unable to read {} bytes: {}", len, err)))?;
+        if crc32(&self.buffer) != crc {
+            return Err(error("Checksum mismatch".to_string()));
+        }
+        self.next += 1;
+
+        let compressed = &self.buffer[..];
+        let bytes = &mut self.bytes;
+        let size = match self.header.compression {
+            Compression::None => Ok(T::from_bytes(compressed, out)),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(compressed)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+                .map(|decompressed| T::from_bytes(&decompressed, out)),
+            Compression::GZip(_) => T::read_samples(&mut GzDecoder::new(compressed), out, bytes),
+            Compression::ZLib(_) => T::read_samples(&mut ZlibDecoder::new(compressed), out, bytes),
             Compression::Deflate(_) => {
-                let mut d = DeflateDecoder::new(&compressed[..]);
-                let size = d.read_to_end(out).unwrap();
-                Some(size)
+                T::read_samples(&mut DeflateDecoder::new(compressed), out, bytes)
             }
+            Compression::Zstd(_) => zstd::Decoder::with_buffer(compressed)
+                .and_then(|mut decoder| T::read_samples(&mut decoder, out, bytes)),
+        }
+        .map_err(|err| error(format!("Unable to decompress: {}", err)))?;
+
+        let bytes = self.header.samples * self.header.bits as usize / 8;
+        if size * T::BYTES != bytes {
+            return Err(error(format!(
+                "Unexpected chunk size. Expected {} bytes, got {}",
+                bytes,
+                size * T::BYTES
+            )));
+        }
+        Ok(Some(size))
+    }
+
+    /// Skips the next chunk. Returns `None` at the end of the stream.
+    pub fn skip_chunk(&mut self) -> Result<Option<usize>> {
+        if self.next >= self.header.frame_count() {
+            return Ok(None);
+        }
+        // Chunks required for delta decoding of later chunks can't be skipped
+        if self.delta.is_reference() {
+            return Ok(self.read_decoded()?.map(|_| 0));
+        }
+        self.delta.skip();
+        let len = self.stream.read_u32::<BigEndian>().map_err(|err| {
+            invalid_data(format!(
+                "Error in chunk {} of time slice file {:?}: Unable to read chunk length: {}",
+                self.next, self.path, err
+            ))
+        })?;
+        self.stream.seek(SeekFrom::Current(len as i64 + 4))?;
+        self.next += 1;
+        Ok(Some(0))
+    }
+
+    /// Moves to the chunk with the given index, i.e. the chunk read by the next call to `read_chunk`.
+    /// Uses the chunk index to seek directly to the chunk, or to the chunk where delta decoding must start.
+    pub fn seek_chunk(&mut self, index: usize) -> Result<()> {
+        let index = std::cmp::min(index, self.header.frame_count());
+        let start = self.delta.seek_start(self.next, index);
+        if start != self.next {
+            if let Some(offset) = self.header.offsets.get(start) {
+                self.stream.seek(SeekFrom::Start(*offset))?;
+            }
+            self.next = start;
+            self.delta.seek(start);
+        }
+        while self.next < index {
+            self.skip_chunk()?;
+        }
+        Ok(())
+    }
+}
+
+/// CRC32 checksum of the given bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+/// Creates an IO error for invalid data.
+fn invalid_data(msg: String) -> Error {
+    Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+/// Time-sliced image data, either in temporary files or in memory.
+/// See [`TimeSlicer`](../slicer/struct.TimeSlicer.html).
+#[derive(Debug)]
+pub enum TimeSlices {
+    /// Time slice files. Compression and delta encoding are read from the file headers.
+    Files(Vec<PathBuf>),
+    /// Uncompressed time slices in memory, with one chunk (big endian bytes) per image in each slice.
+    Memory(Vec<Vec<Vec<u8>>>),
+}
+impl TimeSlices {
+    /// The number of time slices.
+    pub fn len(&self) -> usize {
+        match self {
+            TimeSlices::Files(files) => files.len(),
+            TimeSlices::Memory(slices) => slices.len(),
+        }
+    }
+    /// Checks if there are no time slices.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The time slice files, or `None` for in-memory time slices.
+    pub fn files(&self) -> Option<&[PathBuf]> {
+        match self {
+            TimeSlices::Files(files) => Some(files),
+            TimeSlices::Memory(_) => None,
         }
     }
+    /// Opens a stream of chunks (one per image) for the time slice with the given index.
+    pub fn open(&self, index: usize) -> Result<TimeSliceStream<'_>> {
+        match self {
+            TimeSlices::Files(files) => Ok(TimeSliceStream::File(Box::new(PixelInputStream::new(
+                &files[index],
+            )?))),
+            TimeSlices::Memory(slices) => Ok(TimeSliceStream::Memory(&slices[index], 0)),
+        }
+    }
+}
 
-    pub fn skip_chunk(&mut self) -> Option<usize> {
-        let len = match self.stream.read_u32::<BigEndian>() {
-            Ok(l) => l,
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::UnexpectedEof => return None,
-                _ => panic!(err),
+/// Stream of chunks from a time slice, see [`TimeSlices::open`](enum.TimeSlices.html#method.open).
+pub enum TimeSliceStream<'a> {
+    File(Box<PixelInputStream>),
+    /// In-memory chunks, and the index of the next chunk.
+    Memory(&'a [Vec<u8>], usize),
+}
+impl<'a> TimeSliceStream<'a> {
+    /// Reads the next chunk and appends its samples to `out`.
+    /// Returns the number of samples read, or `None` at the end of the stream.
+    pub fn read_chunk<T: Sample>(&mut self, out: &mut Vec<T>) -> Result<Option<usize>> {
+        match self {
+            TimeSliceStream::File(stream) => stream.read_chunk(out),
+            TimeSliceStream::Memory(chunks, next) => match chunks.get(*next) {
+                Some(chunk) => {
+                    *next += 1;
+                    Ok(Some(T::from_bytes(chunk, out)))
+                }
+                None => Ok(None),
             },
-        };
-        if let Err(err) = self.stream.seek(SeekFrom::Current(len as i64)) {
-            match err.kind() {
-                std::io::ErrorKind::UnexpectedEof => return None,
-                _ => {}
+        }
+    }
+    /// Moves to the chunk with the given index, i.e. the chunk read by the next call to `read_chunk`.
+    pub fn seek_chunk(&mut self, index: usize) -> Result<()> {
+        match self {
+            TimeSliceStream::File(stream) => stream.seek_chunk(index),
+            TimeSliceStream::Memory(_, next) => {
+                *next = index;
+                Ok(())
             }
         }
-        Some(0)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::streams::ImageStream;
+    use crate::streams::{
+        Compression, DeltaEncoding, ImageHeader, ImageStream, PixelInputStream, PrefetchStream,
+        TimeSlices,
+    };
+    use crate::test_util::{write_slice, TempDir};
+    use std::sync::mpsc::sync_channel;
 
     #[test]
     fn iterate() {
         let pattern = "test_data/*.png";
-        let _stream = ImageStream::from_pattern(&pattern, &None).expect("Error processing pattern");
+        let _stream = ImageStream::from_pattern(pattern, &None).expect("Error processing pattern");
         /*
         for img in stream {
             println!("{:?}", img.unwrap().color());
         }*/
     }
+
+    #[test]
+    fn prefetch_errors() {
+        let image = || Ok(image::DynamicImage::new_rgb8(2, 2));
+        let stream = |decode: fn(usize) -> bool| {
+            let (sender, receiver) = sync_channel(1);
+            let decoder = std::thread::spawn(move || {
+                for i in 0..3 {
+                    if decode(i) {
+                        sender.send(image()).unwrap();
+                    }
+                }
+            });
+            PrefetchStream {
+                receiver,
+                decoder: Some(decoder),
+                len: 3,
+                received: 0,
+            }
+        };
+
+        let images: Vec<_> = stream(|_| true).collect();
+        assert_eq!(images.len(), 3);
+        assert!(images.iter().all(|img| img.is_ok()));
+
+        // Missing images
+        let images: Vec<_> = stream(|i| i != 1).collect();
+        assert_eq!(images.len(), 3);
+        assert!(images[2].is_err());
+
+        // Panic in the decoder thread
+        let images: Vec<_> = stream(|i| i == 0 || panic!("Corrupt image")).collect();
+        assert_eq!(images.len(), 2);
+        match &images[1] {
+            Err(err) => assert!(err.to_string().contains("1 of 3 images: Corrupt image")),
+            Ok(_) => panic!("Expected a decoding error"),
+        }
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let data: Vec<u16> = (0..1000).map(|v| (v % 100) * 500).collect();
+        let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect();
+        let dir = TempDir::new("stream");
+        let path = dir.join("slice.bin");
+        for comp in &["none", "lz4", "gzip", "zlib/1", "deflate", "zstd/5"] {
+            let compression: Compression = comp.parse().unwrap();
+            let chunks = vec![bytes.clone(), bytes.iter().rev().cloned().collect()];
+            write_slice(&path, 16, compression.clone(), DeltaEncoding::None, &chunks);
+
+            let mut stream = PixelInputStream::new(&path).unwrap();
+            assert_eq!(stream.header().compression, compression);
+            assert_eq!(stream.header().frame_count(), 2);
+            let mut out: Vec<u16> = vec![];
+            assert_eq!(stream.read_chunk(&mut out).unwrap(), Some(1000));
+            assert_eq!(stream.read_chunk(&mut out).unwrap(), Some(1000));
+            assert_eq!(stream.read_chunk(&mut out).unwrap(), None);
+            assert_eq!(&out[..1000], &data[..]);
+            assert_eq!(out[1000], data[999].swap_bytes());
+        }
+
+        assert!("none/5".parse::<Compression>().is_err());
+        assert!("gzip/x".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let chunks: Vec<Vec<u8>> = (0..7_u8)
+            .map(|i| vec![i, 255 - i, 100, i.wrapping_mul(50)])
+            .collect();
+        let dir = TempDir::new("delta");
+        let path = dir.join("slice.bin");
+        for delta in &["previous", "keyframe/3"] {
+            let delta: DeltaEncoding = delta.parse().unwrap();
+            write_slice(&path, 8, Compression::Lz4, delta.clone(), &chunks);
+
+            // Read with skipped chunks
+            let mut stream = PixelInputStream::new(&path).unwrap();
+            assert_eq!(stream.header().delta, delta);
+            let mut out: Vec<u8> = vec![];
+            for (i, chunk) in chunks.iter().enumerate() {
+                if i % 2 == 0 {
+                    assert_eq!(stream.skip_chunk().unwrap(), Some(0));
+                } else {
+                    out.clear();
+                    assert_eq!(stream.read_chunk(&mut out).unwrap(), Some(4));
+                    assert_eq!(&out, chunk);
+                }
+            }
+            assert_eq!(stream.read_chunk(&mut out).unwrap(), None);
+        }
+
+        assert_eq!("none".parse(), Ok(DeltaEncoding::None));
+        assert!("keyframe/0".parse::<DeltaEncoding>().is_err());
+        assert!("keyframe".parse::<DeltaEncoding>().is_err());
+    }
+
+    #[test]
+    fn seek_chunks() {
+        let chunks: Vec<Vec<u8>> = (0..10_u8).map(|i| vec![i, i * 3, 7, 255 - i]).collect();
+        let dir = TempDir::new("seek");
+        let path = dir.join("slice.bin");
+        for delta in &["none", "previous", "keyframe/4"] {
+            write_slice(&path, 8, Compression::Lz4, delta.parse().unwrap(), &chunks);
+
+            let mut stream = PixelInputStream::new(&path).unwrap();
+            let mut out: Vec<u8> = vec![];
+            for index in &[6, 7, 2, 9, 5, 0, 4] {
+                stream.seek_chunk(*index).unwrap();
+                out.clear();
+                assert_eq!(stream.read_chunk(&mut out).unwrap(), Some(4));
+                assert_eq!(&out, &chunks[*index]);
+            }
+            stream.seek_chunk(10).unwrap();
+            assert_eq!(stream.read_chunk(&mut out).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn corrupt_slices() {
+        let chunks: Vec<Vec<u8>> = (0..3_u8).map(|i| vec![i; 16]).collect();
+        let dir = TempDir::new("corrupt");
+        let path = dir.join("slice.bin");
+        write_slice(&path, 8, Compression::None, DeltaEncoding::None, &chunks);
+        let valid = std::fs::read(&path).unwrap();
+        assert_eq!(PixelInputStream::new(&path).unwrap().verify().unwrap(), 3);
+
+        // Wrong sample type
+        let mut stream = PixelInputStream::new(&path).unwrap();
+        assert!(stream.read_chunk::<u16>(&mut vec![]).is_err());
+
+        // Corrupt chunk data
+        let mut bytes = valid.clone();
+        let offset = PixelInputStream::new(&path).unwrap().header().offsets[1] as usize;
+        bytes[offset + 10] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let mut stream = PixelInputStream::new(&path).unwrap();
+        let mut out: Vec<u8> = vec![];
+        assert_eq!(stream.read_chunk(&mut out).unwrap(), Some(16));
+        let err = stream.read_chunk(&mut out).unwrap_err();
+        assert!(err.to_string().contains("chunk 1"));
+
+        // Corrupt header
+        let mut bytes = valid.clone();
+        bytes[20] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(PixelInputStream::new(&path).is_err());
+
+        // Truncated file
+        std::fs::write(&path, &valid[..(valid.len() - 8)]).unwrap();
+        assert!(PixelInputStream::new(&path).is_err());
+
+        // Not a time slice file
+        std::fs::write(&path, &[0; 100][..]).unwrap();
+        assert!(PixelInputStream::new(&path).is_err());
+    }
+
+    #[test]
+    fn image_headers() {
+        let dir = TempDir::new("header");
+        for (ext, color) in &[
+            ("png", image::ColorType::Rgb16),
+            ("jpg", image::ColorType::Rgb8),
+            ("bmp", image::ColorType::Rgb8),
+        ] {
+            let path = dir.join(&format!("header.{}", ext));
+            let bytes = vec![100; 7 * 5 * color.bytes_per_pixel() as usize];
+            image::save_buffer(&path, &bytes, 7, 5, *color).unwrap();
+            let header = ImageHeader::read(&path).unwrap();
+
+            assert_eq!((header.width, header.height), (7, 5));
+            assert_eq!(header.color, *color);
+            assert_eq!(header.is_16bit(), *color == image::ColorType::Rgb16);
+            assert_eq!(header.layout().height_stride, 7 * 3);
+        }
+    }
+
+    #[test]
+    fn memory_slices() {
+        let slices = TimeSlices::Memory(vec![vec![vec![0, 1, 0, 2], vec![0, 3, 0, 4]]]);
+        assert_eq!(slices.len(), 1);
+        assert!(slices.files().is_none());
+
+        let mut stream = slices.open(0).unwrap();
+        let mut out: Vec<u16> = vec![];
+        stream.seek_chunk(1).unwrap();
+        assert_eq!(stream.read_chunk(&mut out).unwrap(), Some(2));
+        assert_eq!(stream.read_chunk(&mut out).unwrap(), None);
+        assert_eq!(out, vec![3, 4]);
+    }
 }
@@ -0,0 +1,76 @@
+//! Fixtures shared by the unit tests of several modules.
+use crate::streams::{Compression, DeltaCoder, DeltaEncoding, PixelOutputStream, SliceHeader};
+use image::flat::SampleLayout;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counter for unique directory names of tests running in parallel in the same process.
+static TEMP_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A unique, empty temporary directory for a single test. Deleted when dropped, also if the test fails.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates the directory. The name identifies the test in the directory name.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "chrono-{}-test-{}-{}",
+            name,
+            std::process::id(),
+            TEMP_DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+    /// The path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// The path of a file in the directory.
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Writes a time slice file with the given chunks.
+/// Re-opens the file after the first chunk, like the slicer does for many files.
+pub fn write_slice(
+    path: &PathBuf,
+    bits: u8,
+    compression: Compression,
+    delta: DeltaEncoding,
+    chunks: &[Vec<u8>],
+) {
+    let mut header = SliceHeader {
+        bits,
+        compression: compression.clone(),
+        delta: delta.clone(),
+        layout: SampleLayout::row_major_packed(1, chunks[0].len() as u32 * 8 / bits as u32, 1),
+        slice_index: 0,
+        slice_count: 1,
+        sample_offset: 0,
+        samples: chunks[0].len() * 8 / bits as usize,
+        offsets: vec![],
+    };
+    let mut coder = DeltaCoder::new(delta);
+    let mut stream = PixelOutputStream::new(path, compression.clone(), false).unwrap();
+    stream.write_header(&header).unwrap();
+    for (i, chunk) in chunks.iter().enumerate() {
+        // Re-opened files continue at their end
+        if i == 1 {
+            stream.close().unwrap();
+            stream = PixelOutputStream::new(path, compression.clone(), true).unwrap();
+        }
+        header.offsets.push(stream.offset());
+        stream.write_chunk(&coder.encode(chunk)).unwrap();
+    }
+    stream.finish(&header).unwrap();
+}
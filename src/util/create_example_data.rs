@@ -15,15 +15,16 @@ fn main() {
     let dir = PathBuf::from(path);
     print!("Directory: {:?}", dir);
     if !dir.is_dir() {
-        std::fs::create_dir_all(&dir).expect(&format!("Unable to create directory {:?}", &dir));
+        std::fs::create_dir_all(&dir)
+            .unwrap_or_else(|_| panic!("Unable to create directory {:?}", &dir));
         println!("  ... created.");
     }
 
     let buff_len = size.0 * size.1 * channels;
     let mut buffer = vec![0_u8; buff_len];
     for img in 0..num_images {
-        for i in 0..buff_len {
-            buffer[i] = if i % channels == 2 {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = if i % channels == 2 {
                 rng.gen_range(140, 150)
             } else {
                 rng.gen_range(240, 250)
@@ -33,18 +34,14 @@ fn main() {
         for xx in (cx - radius)..=(cx + radius) {
             for yy in (cy - radius)..=(cy + radius) {
                 let idx = xy_to_index(size, channels, xx, yy);
-                for ch in idx..(idx + 1) {
-                    buffer[ch] = 0;
-                }
+                buffer[idx] = 0;
             }
         }
         let (cx, cy) = (1000, 700);
         for xx in (cx - radius)..=(cx + radius) {
             for yy in (cy - radius)..=(cy + radius) {
                 let idx = xy_to_index(size, channels, xx, yy);
-                for ch in idx..(idx + 1) {
-                    buffer[ch] = 0;
-                }
+                buffer[idx] = 0;
             }
         }
 
@@ -52,7 +49,7 @@ fn main() {
         out_path.push_str(&format!("/image-{:05}.jpg", img));
 
         let mut file = File::create(&out_path)
-            .expect(&format!("Unable to create output file {:?}.", &out_path));
+            .unwrap_or_else(|_| panic!("Unable to create output file {:?}.", &out_path));
         let mut enc = image::jpeg::JPEGEncoder::new_with_quality(&mut file, 95);
         enc.encode(
             &buffer,
@@ -64,7 +61,7 @@ fn main() {
                 image::ColorType::Rgb8
             },
         )
-        .expect(&format!("Unable to write output file {:?}.", &out_path));
+        .unwrap_or_else(|_| panic!("Unable to write output file {:?}.", &out_path));
     }
 }
 